@@ -0,0 +1,201 @@
+//! Cache of the files which have already been sent, so that popular links aren't re-processed.
+//! Telegram keeps the sent files around, so they can be sent again by their file id.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+use crate::utils::{self, DownloadOptions, Media, VideoInfo};
+
+/// Identifies a request: the normalised URL, along with what has been requested.
+pub type Key = (String, Media, Option<u32>);
+
+/// A file which has already been sent.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub file_id: String,
+    pub info: Option<VideoInfo>,
+    inserted: Instant,
+}
+
+static CACHE: OnceLock<Mutex<HashMap<Key, Entry>>> = OnceLock::new();
+static TTL: OnceLock<Duration> = OnceLock::new();
+static MAX_ENTRIES: OnceLock<usize> = OnceLock::new();
+static IN_FLIGHT: OnceLock<Mutex<HashMap<Key, watch::Receiver<Option<bool>>>>> = OnceLock::new();
+
+/// Initialises the cache and its limits.
+pub fn init_statics() {
+    CACHE
+        .set(Mutex::new(HashMap::new()))
+        .expect("CACHE was already initialised");
+
+    IN_FLIGHT
+        .set(Mutex::new(HashMap::new()))
+        .expect("IN_FLIGHT was already initialised");
+
+    TTL.set(Duration::from_secs(
+        std::env::var("CACHE_TTL_SECS")
+            .unwrap_or_else(|_| "86400".to_string())
+            .parse()
+            .unwrap_or_else(|_| {
+                warn!("failed to parse CACHE_TTL_SECS, using default value");
+                86400
+            }),
+    ))
+    .expect("TTL was already initialised");
+
+    MAX_ENTRIES
+        .set(
+            std::env::var("CACHE_MAX_ENTRIES")
+                .unwrap_or_else(|_| "1000".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse CACHE_MAX_ENTRIES, using default value");
+                    1000
+                }),
+        )
+        .expect("MAX_ENTRIES was already initialised");
+}
+
+/// Returns the cache key of a request, or `None` if it shouldn't be cached
+/// (playlists are sent as several files, sections are rarely requested twice,
+/// and the cache might be disabled).
+pub fn key(url: &str, options: DownloadOptions) -> Option<Key> {
+    let max_entries = *MAX_ENTRIES.get().expect("MAX_ENTRIES is not initialised");
+
+    if options.playlist_end.is_some() || options.section.is_some() || max_entries == 0 {
+        return None;
+    }
+
+    Some((utils::normalize_url(url), options.media, options.max_height))
+}
+
+/// Looks up a file, unless it has expired.
+pub fn get(key: &Key) -> Option<Entry> {
+    let ttl = *TTL.get().expect("TTL is not initialised");
+    let mut cache = CACHE
+        .get()
+        .expect("CACHE is not initialised")
+        .lock()
+        .unwrap();
+
+    // expired entries are removed when they're looked up (or when a new entry is inserted)
+    if cache
+        .get(key)
+        .is_some_and(|entry| entry.inserted.elapsed() > ttl)
+    {
+        cache.remove(key);
+    }
+
+    cache.get(key).cloned()
+}
+
+/// Stores the file id of a sent file, evicting the oldest entry if the cache is full.
+pub fn insert(key: Key, file_id: String, info: Option<VideoInfo>) {
+    let ttl = *TTL.get().expect("TTL is not initialised");
+    let max_entries = *MAX_ENTRIES.get().expect("MAX_ENTRIES is not initialised");
+
+    let mut cache = CACHE
+        .get()
+        .expect("CACHE is not initialised")
+        .lock()
+        .unwrap();
+
+    cache.retain(|_, entry| entry.inserted.elapsed() <= ttl);
+
+    if cache.len() >= max_entries && !cache.contains_key(&key) {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, entry)| entry.inserted)
+            .map(|(key, _)| key.clone());
+
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+
+    // the formats are only needed by /formats, which doesn't use the cache
+    let info = info.map(|info| VideoInfo {
+        formats: Vec::new(),
+        ..info
+    });
+
+    cache.insert(
+        key,
+        Entry {
+            file_id,
+            info,
+            inserted: Instant::now(),
+        },
+    );
+}
+
+/// Removes the cached files of an URL (in every resolution, as a video or as audio),
+/// or the whole cache if there's no URL, returning how many entries have been removed.
+/// Files which have been deleted from Telegram (or should be sent again in a better version)
+/// are downloaded again the next time they're requested.
+pub fn flush(url: Option<&str>) -> usize {
+    let mut cache = CACHE
+        .get()
+        .expect("CACHE is not initialised")
+        .lock()
+        .unwrap();
+
+    let before = cache.len();
+
+    match url.map(utils::normalize_url) {
+        Some(url) => cache.retain(|(key_url, _, _), _| *key_url != url),
+        None => cache.clear(),
+    }
+
+    before - cache.len()
+}
+
+/// A file being downloaded, which other requests for it wait for instead of downloading it again.
+/// Once it's dropped, the waiting requests are let go.
+pub struct InFlight {
+    key: Key,
+    finished: watch::Sender<Option<bool>>,
+}
+
+impl InFlight {
+    /// Lets the waiting requests know whether the file has been sent (and cached).
+    pub fn finish(self, sent: bool) {
+        self.finished.send_replace(Some(sent));
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT
+            .get()
+            .expect("IN_FLIGHT is not initialised")
+            .lock()
+            .unwrap()
+            .remove(&self.key);
+    }
+}
+
+/// Marks a file as being downloaded, unless it already is; in that case, returns a receiver
+/// which tells whether the download in progress succeeded (`None` if it didn't finish).
+pub fn begin(key: Key) -> Result<InFlight, watch::Receiver<Option<bool>>> {
+    let mut in_flight = IN_FLIGHT
+        .get()
+        .expect("IN_FLIGHT is not initialised")
+        .lock()
+        .unwrap();
+
+    if let Some(finished) = in_flight.get(&key) {
+        return Err(finished.clone());
+    }
+
+    let (finished, receiver) = watch::channel(None);
+    in_flight.insert(key.clone(), receiver);
+    drop(in_flight);
+
+    Ok(InFlight { key, finished })
+}
@@ -0,0 +1,387 @@
+//! Commands supported by the bot.
+
+use teloxide::utils::command::BotCommands;
+
+use crate::{
+    i18n::{self, Lang, Msg},
+    markup::{bold, code_block, code_inline, escape},
+    stats::Stats,
+    utils::{self, Estimate, Format, Section, SystemInfo},
+};
+
+/// Resolutions (heights in pixels) which can be requested with `/yeet`.
+pub const RESOLUTIONS: [u32; 8] = [144, 240, 360, 480, 720, 1080, 1440, 2160];
+
+/// Commands which only the maintainer can use, left out of `/help` for everyone else.
+const MAINTAINER_COMMANDS: [&str; 6] = [
+    "update",
+    "queue",
+    "allow",
+    "disallow",
+    "sysinfo",
+    "flush_cache",
+];
+
+/// Maximum number of formats listed by `/formats`.
+const MAX_FORMATS: usize = 30;
+
+/// Commands which change the way a link is processed.
+/// Messages which aren't commands are processed as plain video requests.
+#[derive(BotCommands, Clone, Debug, PartialEq, Eq)]
+#[command(
+    rename_rule = "lowercase",
+    description = "These commands are supported:"
+)]
+pub enum Command {
+    #[command(description = "show this message.")]
+    Help,
+    #[command(
+        description = "download a video, optionally in at most the given resolution (e.g. /yeet 720 <link>)."
+    )]
+    Yeet(String),
+    #[command(
+        rename = "yeet_spoiler",
+        description = "download a video and send it covered with a spoiler."
+    )]
+    YeetSpoiler(String),
+    #[command(description = "download the audio of a video (reply to a message to use its link).")]
+    Yoink(String),
+    #[command(description = "cancel your latest download.")]
+    Cancel,
+    #[command(description = "retry your latest failed download, with the larger size limit.")]
+    Retry,
+    #[command(description = "list the formats a video is available in.")]
+    Formats(String),
+    #[command(
+        description = "show what would be downloaded, without downloading it (e.g. /info 720 <link>)."
+    )]
+    Info(String),
+    #[command(description = "send the thumbnail of a video, without downloading the video.")]
+    Thumb(String),
+    #[command(description = "show the usage statistics.")]
+    Stats,
+    #[command(description = "check the latency to Telegram and the length of the queue.")]
+    Ping,
+    #[command(description = "show the version of the bot, yt-dlp and ffmpeg.")]
+    Version,
+    #[command(description = "show the netlocs allowed in this chat.")]
+    Whitelist,
+    #[command(
+        rename = "whitelist_add",
+        description = "allow netlocs in this chat (admins only)."
+    )]
+    WhitelistAdd(String),
+    #[command(
+        rename = "whitelist_remove",
+        description = "disallow netlocs in this chat (admins only)."
+    )]
+    WhitelistRemove(String),
+    #[command(
+        rename = "whitelist_reset",
+        description = "use the global whitelist in this chat (admins only)."
+    )]
+    WhitelistReset,
+    #[command(description = "update yt-dlp (maintainer only).")]
+    Update,
+    #[command(
+        description = "list the running and queued downloads (maintainer only, in private)."
+    )]
+    Queue,
+    #[command(description = "allow netlocs globally (maintainer only).")]
+    Allow(String),
+    #[command(description = "disallow netlocs globally (maintainer only).")]
+    Disallow(String),
+    #[command(description = "show the resource usage (maintainer only).")]
+    Sysinfo,
+    #[command(
+        rename = "flush_cache",
+        description = "forget the sent files of a link, or all of them (maintainer only)."
+    )]
+    FlushCache(String),
+}
+
+/// Splits an optional leading resolution (e.g. `720` or `720p`) off the command arguments.
+/// If the first word isn't a supported resolution, the arguments are returned unchanged.
+pub fn parse_resolution(args: &str) -> (Option<u32>, String) {
+    let args = args.trim();
+    let (first, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+
+    first
+        .trim_end_matches('p')
+        .parse()
+        .ok()
+        .filter(|height| RESOLUTIONS.contains(height))
+        .map_or_else(
+            || (None, args.to_string()),
+            |height| (Some(height), rest.to_string()),
+        )
+}
+
+/// Lists the commands, including the maintainer-only ones if `maintainer` is set.
+pub fn help(lang: Lang, maintainer: bool) -> String {
+    let commands = Command::bot_commands()
+        .into_iter()
+        .filter(|c| maintainer || !MAINTAINER_COMMANDS.contains(&c.command.as_str()))
+        .map(|c| format!("/{} — {}", c.command, c.description))
+        .collect::<Vec<_>>();
+
+    format!(
+        "{}\n\n{}",
+        i18n::tr(lang, Msg::Commands, &[]),
+        commands.join("\n")
+    )
+}
+
+/// Splits a time range (e.g. `0:30-1:15`, or `0:30-` to download until the end) off the command arguments.
+/// The range can be anywhere among the arguments; if there's none, the arguments are returned unchanged.
+pub fn parse_section(args: &str) -> (Option<Section>, String) {
+    let section = |word: &str| {
+        let (start, end) = word.split_once('-')?;
+        let start = utils::parse_timestamp(start)?;
+        let end = if end.is_empty() {
+            None
+        } else {
+            Some(utils::parse_timestamp(end)?)
+        };
+
+        Some(Section { start, end })
+    };
+
+    let words = args.split_whitespace().collect::<Vec<_>>();
+
+    words
+        .iter()
+        .enumerate()
+        .find_map(|(i, word)| section(word).map(|section| (i, section)))
+        .map_or_else(
+            || (None, args.to_string()),
+            |(i, section)| {
+                let mut rest = words.clone();
+                rest.remove(i);
+                (Some(section), rest.join(" "))
+            },
+        )
+}
+
+/// Lists the formats as a formatted message, truncated to `MAX_FORMATS` entries.
+pub fn format_list(lang: Lang, formats: &[Format]) -> String {
+    if formats.is_empty() {
+        return i18n::tr_markup(lang, Msg::NoFormats, &[]);
+    }
+
+    let mut lines = formats
+        .iter()
+        .take(MAX_FORMATS)
+        .map(|f| {
+            // codecs are reported as "none" for video-only and audio-only formats
+            let codecs = [&f.vcodec, &f.acodec]
+                .into_iter()
+                .filter(|c| !c.is_empty() && *c != "none")
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            #[allow(clippy::cast_precision_loss)]
+            let size = f
+                .filesize
+                .map(|s| format!(", ~{:.1} MB", s as f64 / 1000.0 / 1000.0))
+                .unwrap_or_default();
+
+            format!(
+                "• {} {}",
+                code_inline(&f.id),
+                escape(&format!("{} {} ({codecs}){size}", f.resolution, f.ext))
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if formats.len() > MAX_FORMATS {
+        lines.push(i18n::tr_markup(
+            lang,
+            Msg::More,
+            &[&(formats.len() - MAX_FORMATS)],
+        ));
+    }
+
+    format!(
+        "{}\n{}",
+        bold(&i18n::tr(lang, Msg::AvailableFormats, &[])),
+        lines.join("\n")
+    )
+}
+
+/// Formats a number of bytes in a human-readable way (e.g. `1.5 GB`).
+#[allow(clippy::cast_precision_loss)]
+fn format_bytes(bytes: u64) -> String {
+    let megabytes = bytes as f64 / 1000.0 / 1000.0;
+
+    if megabytes >= 1000.0 {
+        format!("{:.2} GB", megabytes / 1000.0)
+    } else {
+        format!("{megabytes:.1} MB")
+    }
+}
+
+/// Formats a duration in a human-readable way (e.g. `2d 3h 4m`).
+fn format_uptime(duration: std::time::Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / 60 / 24, minutes / 60 % 24, minutes % 60);
+
+    if days > 0 {
+        format!("{days}d {hours}h {minutes}m")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+/// Describes the usage statistics, one per line.
+pub fn format_stats(lang: Lang, stats: &Stats) -> String {
+    let average_processing_time = stats.average_processing_time.map_or_else(
+        || i18n::tr(lang, Msg::NotAvailable, &[]),
+        |t| format!("{:.1} s", t.as_secs_f64()),
+    );
+
+    let queue = match stats.queue_capacity {
+        Some(capacity) if stats.queue_length >= capacity => {
+            i18n::tr(lang, Msg::QueueFull, &[&stats.queue_length, &capacity])
+        }
+        Some(capacity) => format!("{}/{capacity}", stats.queue_length),
+        None => stats.queue_length.to_string(),
+    };
+
+    i18n::tr(
+        lang,
+        Msg::Stats,
+        &[
+            &stats.downloads_completed,
+            &format_bytes(stats.bytes_sent),
+            &average_processing_time,
+            &queue,
+            &format_uptime(stats.uptime),
+        ],
+    )
+}
+
+/// Describes the resource usage, as a code block.
+pub fn format_system_info(lang: Lang, info: &SystemInfo) -> String {
+    let unknown = || i18n::tr(lang, Msg::Unknown, &[]);
+
+    code_block(&i18n::tr(
+        lang,
+        Msg::SystemInfo,
+        &[
+            &info.memory.map_or_else(unknown, format_bytes),
+            &info.cpu.map_or_else(unknown, |cpu| format!("{cpu:.1}%")),
+            &info.free_disk.map_or_else(unknown, format_bytes),
+            &info.ytdlp_version.clone().unwrap_or_else(unknown),
+            &info.ffmpeg_version.clone().unwrap_or_else(unknown),
+        ],
+    ))
+}
+
+/// Describes the build of the bot, along with the versions of yt-dlp and ffmpeg it uses.
+pub fn format_version(lang: Lang) -> String {
+    let unknown = || i18n::tr(lang, Msg::Unknown, &[]);
+
+    // the commit is only known if the bot was built from the repository
+    let build = option_env!("GIT_HASH").map_or_else(
+        || env!("CARGO_PKG_VERSION").to_string(),
+        |hash| format!("{} ({hash})", env!("CARGO_PKG_VERSION")),
+    );
+
+    i18n::tr(
+        lang,
+        Msg::Version,
+        &[
+            &build,
+            &env!("BUILD_TIMESTAMP"),
+            &utils::version_string("yt-dlp").unwrap_or_else(unknown),
+            &utils::version_string("ffmpeg").unwrap_or_else(unknown),
+        ],
+    )
+}
+
+/// Formats a duration in seconds as `h:mm:ss` (or `m:ss`).
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+/// Describes what would be downloaded, one detail per line.
+pub fn format_estimate(lang: Lang, estimate: &Estimate) -> String {
+    let unknown = || i18n::tr(lang, Msg::Unknown, &[]);
+
+    i18n::tr(
+        lang,
+        Msg::Estimate,
+        &[
+            &estimate.resolution.clone().unwrap_or_else(unknown),
+            &estimate.duration.map_or_else(unknown, format_duration),
+            &estimate.ext.clone().unwrap_or_else(unknown),
+            &estimate
+                .filesize
+                .map_or_else(unknown, |s| format!("~{}", format_bytes(s))),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections() {
+        assert_eq!(
+            parse_section("https://youtu.be/abc 00:30-01:15"),
+            (
+                Some(Section {
+                    start: 30,
+                    end: Some(75)
+                }),
+                "https://youtu.be/abc".to_string()
+            )
+        );
+        assert_eq!(
+            parse_section("0:01:30-0:02:00 https://youtu.be/abc"),
+            (
+                Some(Section {
+                    start: 90,
+                    end: Some(120)
+                }),
+                "https://youtu.be/abc".to_string()
+            )
+        );
+        assert_eq!(
+            parse_section("https://youtu.be/abc 1:30-"),
+            (
+                Some(Section {
+                    start: 90,
+                    end: None
+                }),
+                "https://youtu.be/abc".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_arguments_without_sections_unchanged() {
+        assert_eq!(
+            parse_section("https://youtu.be/a-b"),
+            (None, "https://youtu.be/a-b".to_string())
+        );
+        assert_eq!(
+            parse_section("https://youtu.be/abc 1:30"),
+            (None, "https://youtu.be/abc 1:30".to_string())
+        );
+        assert_eq!(parse_section(""), (None, String::new()));
+    }
+}
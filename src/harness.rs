@@ -0,0 +1,314 @@
+//! A mock Telegram Bot API for the tests, so that the handlers can run without a network or a real token.
+//! Like the healthcheck, it's a bare HTTP server: every request is recorded and answered
+//! with just enough for teloxide to carry on (a message for the methods which send or edit one).
+
+use std::sync::{Arc, Mutex, Once};
+
+use serde_json::{json, Value};
+use teloxide::{
+    adaptors::throttle::Limits,
+    requests::RequesterExt,
+    types::{Me, Message},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::Bot;
+
+/// The username of the maintainer, as set in `MAINTAINER`.
+pub const MAINTAINER: &str = "maintainer";
+
+/// A request made to the mock Bot API.
+#[derive(Clone, Debug)]
+pub struct Request {
+    /// The name of the method, in lowercase (e.g. `sendmessage`).
+    pub method: String,
+    /// The raw body, which is JSON (or multipart, for the methods uploading a file).
+    pub body: String,
+}
+
+impl Request {
+    /// Returns the text of a message sent or edited with the request.
+    pub fn text(&self) -> Option<String> {
+        let body = serde_json::from_str::<Value>(&self.body).ok()?;
+        body.get("text")?.as_str().map(str::to_string)
+    }
+}
+
+/// The mock Bot API, serving on a random local port until the test's runtime stops.
+pub struct Backend {
+    url: url::Url,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Backend {
+    /// Starts the server.
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind the mock Bot API");
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(respond(stream, Arc::clone(&recorded)));
+            }
+        });
+
+        Self {
+            url: format!("http://{addr}/").parse().unwrap(),
+            requests,
+        }
+    }
+
+    /// Returns a bot sending its requests to the server, throttled like the real one.
+    pub fn bot(&self) -> Bot {
+        teloxide::Bot::new("0:test")
+            .set_api_url(self.url.clone())
+            .throttle(Limits::default())
+    }
+
+    /// Returns the requests made so far, in order.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Returns the texts of the messages sent (or edited) so far, in order.
+    pub fn texts(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .filter(|request| {
+                request.method == "sendmessage" || request.method == "editmessagetext"
+            })
+            .filter_map(Request::text)
+            .collect()
+    }
+}
+
+/// Reads a request, records it and answers it (closing the connection, as in the healthcheck).
+async fn respond(stream: TcpStream, requests: Arc<Mutex<Vec<Request>>>) {
+    let mut stream = BufReader::new(stream);
+    let Some(request) = read_request(&mut stream).await else {
+        return;
+    };
+
+    let result = result(&request);
+    requests.lock().unwrap().push(request);
+
+    let body = json!({ "ok": true, "result": result }).to_string();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    let stream = stream.get_mut();
+    stream.write_all(response.as_bytes()).await.ok();
+    stream.shutdown().await.ok();
+}
+
+/// Reads the method and the body of a request (the uploads are sent in chunks).
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+
+    // e.g. `POST /bot0:test/SendMessage HTTP/1.1`
+    let method = line
+        .split_whitespace()
+        .nth(1)?
+        .rsplit('/')
+        .next()?
+        .to_lowercase();
+
+    let mut content_length = 0;
+    let mut chunked = false;
+
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+
+        let header = line.trim();
+
+        if header.is_empty() {
+            break;
+        }
+
+        let (name, value) = header.split_once(':')?;
+        let value = value.trim();
+
+        match name.to_lowercase().as_str() {
+            "content-length" => content_length = value.parse().ok()?,
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            _ => {}
+        }
+    }
+
+    let mut body = Vec::new();
+
+    if chunked {
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await.ok()?;
+
+            let size = usize::from_str_radix(line.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2]; // the chunk is followed by a line break
+            stream.read_exact(&mut chunk).await.ok()?;
+
+            if size == 0 {
+                break;
+            }
+
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else {
+        body.resize(content_length, 0);
+        stream.read_exact(&mut body).await.ok()?;
+    }
+
+    Some(Request {
+        method,
+        body: String::from_utf8_lossy(&body).to_string(),
+    })
+}
+
+/// Returns the result of a request: the sent (or edited) message, or just `true`.
+fn result(request: &Request) -> Value {
+    if request.method == "getme" {
+        return serde_json::to_value(me()).unwrap();
+    }
+
+    if !request.method.starts_with("send") && !request.method.starts_with("edit") {
+        return json!(true);
+    }
+
+    let body = serde_json::from_str::<Value>(&request.body).unwrap_or_default();
+    let chat_id = body.get("chat_id").and_then(Value::as_i64).unwrap_or(1);
+
+    json!({
+        "message_id": 1000,
+        "date": 0,
+        "chat": { "id": chat_id, "type": "private", "first_name": "Test" },
+        "text": body.get("text").and_then(Value::as_str).unwrap_or_default(),
+    })
+}
+
+/// Initialises the static variables (see `init_statics`) the way the tests expect them, only once,
+/// as the tests share them: `example.com` is whitelisted, and the videos are converted quickly.
+pub fn init() {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        for (name, value) in [
+            ("WHITELIST", "example.com"),
+            ("DENYLIST", "denied.example.com"),
+            ("MAINTAINER", MAINTAINER),
+            ("DEFAULT_LANG", "en"),
+            ("TELEGRAM_UPLOAD_LIMIT_MB", "50"),
+            ("FFMPEG_VIDEO_CODEC", "libx264"),
+            ("FFMPEG_PRESET", "ultrafast"),
+            ("FFMPEG_AUDIO_BITRATE", "128"),
+            ("COPY_AUDIO", "false"),
+        ] {
+            std::env::set_var(name, value);
+        }
+
+        // nothing is read from (or written to) the disk
+        for name in [
+            "MAX_OUTPUT_HEIGHT",
+            "WORK_DIR",
+            "QUEUE_STATE_PATH",
+            "CHAT_WHITELISTS_PATH",
+            "WHITELIST_PATH",
+        ] {
+            std::env::remove_var(name);
+        }
+
+        crate::init_statics();
+    });
+}
+
+/// Returns the bot's own user.
+pub fn me() -> Me {
+    serde_json::from_value(json!({
+        "id": 1,
+        "is_bot": true,
+        "first_name": "link_yeeter",
+        "username": "yeet_bot",
+        "can_join_groups": true,
+        "can_read_all_group_messages": false,
+        "supports_inline_queries": true,
+    }))
+    .unwrap()
+}
+
+/// Returns a message sent to the bot in private, by a user speaking the language.
+pub fn private_message(user_id: u64, username: &str, lang: &str, text: &str) -> Message {
+    message(
+        &json!({ "id": user_id, "type": "private", "first_name": "Test" }),
+        user_id,
+        username,
+        lang,
+        text,
+    )
+}
+
+/// Returns a message sent to a group the bot is in.
+pub fn group_message(chat_id: i64, user_id: u64, text: &str) -> Message {
+    message(
+        &json!({ "id": chat_id, "type": "group", "title": "Test" }),
+        user_id,
+        "someone",
+        "en",
+        text,
+    )
+}
+
+/// Returns a message sent to the chat.
+fn message(chat: &Value, user_id: u64, username: &str, lang: &str, text: &str) -> Message {
+    serde_json::from_value(json!({
+        "message_id": 1,
+        "date": 0,
+        "chat": chat,
+        "from": {
+            "id": user_id,
+            "is_bot": false,
+            "first_name": "Test",
+            "username": username,
+            "language_code": lang,
+        },
+        "text": text,
+    }))
+    .unwrap()
+}
+
+/// Writes a short test video (MPEG-4 with a sine wave, so that it has to be re-encoded) with ffmpeg.
+pub fn sample_video(dir: &std::path::Path) -> String {
+    let path = dir.join("sample.mkv").to_string_lossy().to_string();
+
+    let status = std::process::Command::new("ffmpeg")
+        .args([
+            "-y",
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=duration=2:size=320x240:rate=25",
+            "-f",
+            "lavfi",
+            "-i",
+            "sine=duration=2",
+            "-c:v",
+            "mpeg4",
+            "-c:a",
+            "aac",
+            &path,
+        ])
+        .output()
+        .expect("failed to run ffmpeg")
+        .status;
+
+    assert!(status.success());
+    path
+}
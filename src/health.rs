@@ -0,0 +1,64 @@
+//! A minimal HTTP healthcheck endpoint, meant for liveness and readiness probes.
+//! It answers every request the same way, so it isn't worth a web framework.
+
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::tasks;
+
+/// Serves the healthcheck on the address, until the bot stops.
+pub async fn serve(addr: SocketAddr) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("failed to start the healthcheck server: {}", e);
+            return;
+        }
+    };
+
+    info!("serving the healthcheck on {}", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(respond(stream));
+            }
+            Err(e) => warn!("failed to accept a healthcheck connection: {}", e),
+        }
+    }
+}
+
+/// Answers a request with 200 while the bot is running, or 503 once it's shutting down.
+async fn respond(mut stream: TcpStream) {
+    // the request itself doesn't matter, but it has to be read before answering
+    let mut request = [0; 1024];
+    if stream.read(&mut request).await.is_err() {
+        return;
+    }
+
+    let (status, state) = if tasks::is_shutting_down() {
+        ("503 Service Unavailable", "shutting down")
+    } else {
+        ("200 OK", "running")
+    };
+
+    // tasks which aren't waiting for a worker are being processed
+    let queued = tasks::queue_length();
+    let in_progress = tasks::count().saturating_sub(queued);
+    let body = format!("status: {state}\nqueued: {queued}\nin progress: {in_progress}\n");
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!("failed to answer the healthcheck: {}", e);
+    }
+
+    stream.shutdown().await.ok();
+}
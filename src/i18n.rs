@@ -0,0 +1,373 @@
+//! Translations of the messages sent to the users.
+//! Adding a language only takes a new `Lang` variant, along with a table of its messages.
+
+use std::{
+    fmt::{Display, Write},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use teloxide::types::User;
+
+use crate::markup;
+
+static DEFAULT_LANG: OnceLock<Lang> = OnceLock::new();
+
+/// Languages the messages are available in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lang {
+    English,
+    Polish,
+}
+
+impl FromStr for Lang {
+    type Err = ();
+
+    /// Parses a language code, like "en" or "pl-PL".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = s.split(['-', '_']).next().unwrap_or_default();
+
+        match code.trim().to_lowercase().as_str() {
+            "en" => Ok(Self::English),
+            "pl" => Ok(Self::Polish),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Lang {
+    /// Returns the message in this language; `{}` marks the places of the arguments.
+    const fn template(self, msg: Msg) -> &'static str {
+        match self {
+            Self::English => english(msg),
+            Self::Polish => polish(msg),
+        }
+    }
+}
+
+/// Messages sent to the users.
+#[derive(Clone, Copy, Debug)]
+pub enum Msg {
+    NoUrls,
+    NetlocDenied,
+    NoWhitelistedUrls,
+    MultipleUrls,
+    TooManyUrls,
+    ContactMaintainer,
+    FormatsFailed,
+    InfoFailed,
+    NoThumbnail,
+    Cancelling,
+    NothingToCancel,
+    Cancelled,
+    Delivered,
+    NothingToRetry,
+    Pong,
+    PongLatency,
+    InvalidSection,
+    TooManyTasks,
+    Overloaded,
+    QueuePosition,
+    QueueEmpty,
+    BatchAccepted,
+    DownloadProgress,
+    TimedOut,
+    Restarting,
+    ShuttingDown,
+    DownloadFailed,
+    Livestream,
+    TooLong,
+    AuthRequired,
+    PrivateVideo,
+    VideoUnavailable,
+    FormatUnavailable,
+    ProxyFailed,
+    NoFilesFound,
+    FilesFound,
+    ExtractorFailed,
+    FileTooBig,
+    ExceedsUploadLimit,
+    EstimatedTooBig,
+    LargeVideo,
+    ExtendedLimit,
+    SkippedItems,
+    ConvertFailed,
+    UnsupportedMedia,
+    UploadFailed,
+    BitrateReduced,
+    SentAsDocument,
+    Part,
+    OriginalPoster,
+    AdminsOnly,
+    NotGloballyAllowed,
+    AllowedByChat,
+    AllowedByGlobal,
+    InlineDisabled,
+    UnknownCommand,
+    NoNetlocsGiven,
+    InvalidNetlocs,
+    CacheFlushed,
+    QueueListEmpty,
+    DownloadsInProgress,
+    QueuedDownloads,
+    RunningEntry,
+    WaitingEntry,
+    NoNetlocs,
+    UpdateFailed,
+    AlreadyUpToDate,
+    Updated,
+    More,
+    Commands,
+    NoFormats,
+    AvailableFormats,
+    Stats,
+    QueueFull,
+    Refresh,
+    SystemInfo,
+    Version,
+    Estimate,
+    Unknown,
+    NotAvailable,
+    UntitledVideo,
+}
+
+const fn english(msg: Msg) -> &'static str {
+    match msg {
+        Msg::NoUrls => "No URLs found.",
+        Msg::NetlocDenied => "Downloading from this netloc is not allowed.",
+        Msg::NoWhitelistedUrls => "No whitelisted URLs found.\n\nSupported netlocs: {}.",
+        Msg::MultipleUrls => "Downloading more than one video at a time is unsupported.",
+        Msg::TooManyUrls => "Downloading more than {} videos at a time is unsupported.",
+        Msg::ContactMaintainer => "{}\n\nFor more information, please contact {}.",
+        Msg::FormatsFailed => "Failed to get the available formats.",
+        Msg::InfoFailed => "Failed to get the video info.",
+        Msg::NoThumbnail => "This video doesn't have a thumbnail.",
+        Msg::Cancelling => "Cancelling your latest download.",
+        Msg::NothingToCancel => "There is nothing to cancel.",
+        Msg::Cancelled => "Download cancelled.",
+        Msg::Delivered => "Done! The file has been sent to the delivery chat.",
+        Msg::NothingToRetry => "There is no failed download to retry (downloads can be retried for an hour).",
+        Msg::Pong => "Pong!",
+        Msg::PongLatency => "Pong! Latency to Telegram: {} ms.\nDownloads in the queue: {}.",
+        Msg::InvalidSection => "The requested time range doesn't fit within the video, downloading the whole video instead.",
+        Msg::TooManyTasks => "You already have {} downloads in progress.",
+        Msg::Overloaded => "The bot is overloaded, try again later.",
+        Msg::QueuePosition => "Request accepted.\nYour position in the queue: {}.",
+        Msg::QueueEmpty => "Request accepted.\nThe queue is empty, downloading now.",
+        Msg::DownloadProgress => "Request accepted.\nDownloading: {}%.",
+        Msg::BatchAccepted => "Request accepted.\nDownloading {} videos, they will be sent as soon as they're ready.",
+        Msg::TimedOut => "Download timed out after {} seconds.",
+        Msg::Restarting => "The bot is restarting, your download will be resumed shortly.",
+        Msg::ShuttingDown => "The bot is shutting down, please try again later.",
+        Msg::DownloadFailed => "Failed to download video.",
+        Msg::Livestream => "Failed to download video (livestreams are not supported).",
+        Msg::TooLong => "The video is too long ({} min, the limit is {} min).",
+        Msg::AuthRequired => "Failed to download video (the video requires signing in, e.g. because it's age-restricted).",
+        Msg::PrivateVideo => "Failed to download video (the video is private).",
+        Msg::VideoUnavailable => "Failed to download video (the video has been removed or doesn't exist).",
+        Msg::FormatUnavailable => "Failed to download video (it isn't available in the requested format, try another resolution).",
+        Msg::ProxyFailed => "Failed to download video (couldn't connect to the proxy). Please contact {}.",
+        Msg::NoFilesFound => "Failed to download video (no media files found).",
+        Msg::FilesFound => "Failed to download video ({} files found).",
+        Msg::ExtractorFailed => "Failed to download video (extractor exited with non-zero code).",
+        Msg::FileTooBig => "Failed to convert video (base file size exceeds {} MB).",
+        Msg::ExceedsUploadLimit => "Failed to convert video (it exceeds Telegram's file size limit of {} MB, and reducing its quality is disabled).",
+        Msg::EstimatedTooBig => "The video is too big to download (about {} MB, while the limit is {} MB).",
+        Msg::LargeVideo => "This is a large video and may take several minutes.",
+        Msg::ExtendedLimit => "Using the extended size limit of {} MB for this site.",
+        Msg::SkippedItems => "Skipped {} playlist item(s) (combined file size exceeds {} MB): {}.",
+        Msg::ConvertFailed => "Failed to convert the video.",
+        Msg::UnsupportedMedia => "Unsupported media type (the downloaded file isn't a video or an audio file).",
+        Msg::UploadFailed => "Failed to upload the video.",
+        Msg::BitrateReduced => "Warning: the bitrate of the video has been reduced from {} kbps to {} kbps ({}% reduction) to meet Telegram's file size limit.",
+        Msg::SentAsDocument => "The video couldn't be converted into a format Telegram can play, so it has been sent as a file.",
+        Msg::Part => "Part {}/{}",
+        Msg::OriginalPoster => "[original poster: {}]",
+        Msg::AdminsOnly => "Only the admins of this chat can change its whitelist.",
+        Msg::NotGloballyAllowed => "These netlocs aren't allowed by the global whitelist:",
+        Msg::AllowedByChat => "Netlocs allowed by this chat's whitelist:",
+        Msg::AllowedByGlobal => "Netlocs allowed by the global whitelist:",
+        Msg::InlineDisabled => "Inline mode is not enabled.",
+        Msg::UnknownCommand => "Unknown command.",
+        Msg::NoNetlocsGiven => "No netlocs given.",
+        Msg::InvalidNetlocs => "These aren't valid netlocs:",
+        Msg::CacheFlushed => "Removed {} cache entries.",
+        Msg::QueueListEmpty => "The queue is empty.",
+        Msg::DownloadsInProgress => "Downloads in progress ({}):",
+        Msg::QueuedDownloads => "Queued downloads ({}):",
+        Msg::RunningEntry => "{}. {} (chat {}, running for {}s)",
+        Msg::WaitingEntry => "{}. {} (chat {}, waiting for {}s)",
+        Msg::NoNetlocs => "none",
+        Msg::UpdateFailed => "Failed to update yt-dlp {}: {}",
+        Msg::AlreadyUpToDate => "yt-dlp is already up to date ({}).",
+        Msg::Updated => "yt-dlp has been updated from {} to {}.",
+        Msg::More => "...and {} more (truncated).",
+        Msg::Commands => "These commands are supported:",
+        Msg::NoFormats => "No formats found.",
+        Msg::AvailableFormats => "Available formats:",
+        Msg::Stats => "Downloads completed: {}\nData sent: {}\nAverage processing time: {}\nQueue: {}\nUptime: {}",
+        Msg::QueueFull => "{}/{} (full)",
+        Msg::Refresh => "Refresh",
+        Msg::SystemInfo => "Memory:    {}\nCPU:       {}\nFree disk: {}\nyt-dlp:    {}\nffmpeg:    {}",
+        Msg::Version => "link_yeeter {}\nBuilt: {}\nyt-dlp: {}\nffmpeg: {}",
+        Msg::Estimate => "Resolution: {}\nDuration: {}\nFormat: {}\nExpected size: {}",
+        Msg::Unknown => "unknown",
+        Msg::NotAvailable => "n/a",
+        Msg::UntitledVideo => "Video",
+    }
+}
+
+const fn polish(msg: Msg) -> &'static str {
+    match msg {
+        Msg::NoUrls => "Nie znaleziono żadnych linków.",
+        Msg::NetlocDenied => "Pobieranie z tej strony jest niedozwolone.",
+        Msg::NoWhitelistedUrls => "Nie znaleziono linków z dozwolonych stron.\n\nObsługiwane strony: {}.",
+        Msg::MultipleUrls => "Pobieranie więcej niż jednego filmu naraz nie jest obsługiwane.",
+        Msg::TooManyUrls => "Pobieranie więcej niż {} filmów naraz nie jest obsługiwane.",
+        Msg::ContactMaintainer => "{}\n\nW razie pytań skontaktuj się z {}.",
+        Msg::FormatsFailed => "Nie udało się pobrać listy dostępnych formatów.",
+        Msg::InfoFailed => "Nie udało się pobrać informacji o filmie.",
+        Msg::NoThumbnail => "Ten film nie ma miniatury.",
+        Msg::Cancelling => "Anulowanie ostatniego pobierania.",
+        Msg::NothingToCancel => "Nie ma nic do anulowania.",
+        Msg::Cancelled => "Anulowano pobieranie.",
+        Msg::Delivered => "Gotowe! Plik został wysłany na czat docelowy.",
+        Msg::NothingToRetry => "Nie ma nieudanego pobierania do ponowienia (pobieranie można ponowić w ciągu godziny).",
+        Msg::Pong => "Pong!",
+        Msg::PongLatency => "Pong! Opóźnienie do Telegrama: {} ms.\nPobrania w kolejce: {}.",
+        Msg::InvalidSection => "Podany przedział czasu wykracza poza film, pobieranie całego filmu.",
+        Msg::TooManyTasks => "Liczba Twoich pobrań w toku: {}. Poczekaj, aż się zakończą.",
+        Msg::Overloaded => "Bot jest przeciążony, spróbuj ponownie później.",
+        Msg::QueuePosition => "Przyjęto zgłoszenie.\nTwoje miejsce w kolejce: {}.",
+        Msg::QueueEmpty => "Przyjęto zgłoszenie.\nKolejka jest pusta, pobieranie się rozpoczęło.",
+        Msg::DownloadProgress => "Przyjęto zgłoszenie.\nPobieranie: {}%.",
+        Msg::BatchAccepted => "Przyjęto zgłoszenie.\nLiczba pobieranych filmów: {}. Zostaną wysłane, gdy tylko będą gotowe.",
+        Msg::TimedOut => "Pobieranie przekroczyło limit czasu ({} s).",
+        Msg::Restarting => "Bot jest uruchamiany ponownie, pobieranie wkrótce zostanie wznowione.",
+        Msg::ShuttingDown => "Bot jest wyłączany, spróbuj ponownie później.",
+        Msg::DownloadFailed => "Nie udało się pobrać filmu.",
+        Msg::Livestream => "Nie udało się pobrać filmu (transmisje na żywo nie są obsługiwane).",
+        Msg::TooLong => "Film jest zbyt długi ({} min, limit wynosi {} min).",
+        Msg::AuthRequired => "Nie udało się pobrać filmu (film wymaga zalogowania, np. z powodu ograniczeń wiekowych).",
+        Msg::PrivateVideo => "Nie udało się pobrać filmu (film jest prywatny).",
+        Msg::VideoUnavailable => "Nie udało się pobrać filmu (film został usunięty lub nie istnieje).",
+        Msg::FormatUnavailable => "Nie udało się pobrać filmu (nie jest dostępny w wybranym formacie, spróbuj innej rozdzielczości).",
+        Msg::ProxyFailed => "Nie udało się pobrać filmu (brak połączenia z serwerem proxy). Skontaktuj się z {}.",
+        Msg::NoFilesFound => "Nie udało się pobrać filmu (nie znaleziono plików multimedialnych).",
+        Msg::FilesFound => "Nie udało się pobrać filmu (liczba znalezionych plików: {}).",
+        Msg::ExtractorFailed => "Nie udało się pobrać filmu (ekstraktor zakończył działanie z błędem).",
+        Msg::FileTooBig => "Nie udało się przekonwertować filmu (rozmiar pliku przekracza {} MB).",
+        Msg::ExceedsUploadLimit => "Nie udało się przekonwertować filmu (przekracza limit rozmiaru plików Telegrama wynoszący {} MB, a obniżanie jakości jest wyłączone).",
+        Msg::EstimatedTooBig => "Film jest zbyt duży, aby go pobrać (około {} MB, a limit wynosi {} MB).",
+        Msg::LargeVideo => "To duży film, jego pobranie może potrwać kilka minut.",
+        Msg::ExtendedLimit => "Dla tej strony obowiązuje zwiększony limit rozmiaru wynoszący {} MB.",
+        Msg::SkippedItems => "Pominięto elementy playlisty w liczbie {} (ich łączny rozmiar przekracza {} MB): {}.",
+        Msg::ConvertFailed => "Nie udało się przekonwertować filmu.",
+        Msg::UnsupportedMedia => "Nieobsługiwany typ pliku (pobrany plik nie jest filmem ani plikiem audio).",
+        Msg::UploadFailed => "Nie udało się przesłać filmu.",
+        Msg::BitrateReduced => "Uwaga: bitrate filmu został zmniejszony z {} kbps do {} kbps (o {}%), aby zmieścił się w limicie rozmiaru plików Telegrama.",
+        Msg::SentAsDocument => "Nie udało się przekonwertować filmu do formatu odtwarzanego przez Telegrama, więc został wysłany jako plik.",
+        Msg::Part => "Część {}/{}",
+        Msg::OriginalPoster => "[autor: {}]",
+        Msg::AdminsOnly => "Tylko administratorzy tego czatu mogą zmieniać jego białą listę.",
+        Msg::NotGloballyAllowed => "Te strony nie są dozwolone przez globalną białą listę:",
+        Msg::AllowedByChat => "Strony dozwolone przez białą listę tego czatu:",
+        Msg::AllowedByGlobal => "Strony dozwolone przez globalną białą listę:",
+        Msg::InlineDisabled => "Tryb inline nie jest włączony.",
+        Msg::UnknownCommand => "Nieznane polecenie.",
+        Msg::NoNetlocsGiven => "Nie podano żadnych stron.",
+        Msg::InvalidNetlocs => "Te strony są nieprawidłowe:",
+        Msg::CacheFlushed => "Usunięte wpisy z pamięci podręcznej: {}.",
+        Msg::QueueListEmpty => "Kolejka jest pusta.",
+        Msg::DownloadsInProgress => "Pobrania w toku ({}):",
+        Msg::QueuedDownloads => "Pobrania w kolejce ({}):",
+        Msg::RunningEntry => "{}. {} (czat {}, w toku od {} s)",
+        Msg::WaitingEntry => "{}. {} (czat {}, czeka od {} s)",
+        Msg::NoNetlocs => "brak",
+        Msg::UpdateFailed => "Nie udało się zaktualizować yt-dlp {}: {}",
+        Msg::AlreadyUpToDate => "yt-dlp jest już aktualny ({}).",
+        Msg::Updated => "yt-dlp został zaktualizowany z wersji {} do {}.",
+        Msg::More => "...i {} więcej (lista skrócona).",
+        Msg::Commands => "Dostępne polecenia:",
+        Msg::NoFormats => "Nie znaleziono żadnych formatów.",
+        Msg::AvailableFormats => "Dostępne formaty:",
+        Msg::Stats => "Ukończone pobrania: {}\nWysłane dane: {}\nŚredni czas przetwarzania: {}\nKolejka: {}\nCzas działania: {}",
+        Msg::QueueFull => "{}/{} (pełna)",
+        Msg::Refresh => "Odśwież",
+        Msg::SystemInfo => "Pamięć:        {}\nProcesor:      {}\nWolne miejsce: {}\nyt-dlp:        {}\nffmpeg:        {}",
+        Msg::Version => "link_yeeter {}\nZbudowano: {}\nyt-dlp: {}\nffmpeg: {}",
+        Msg::Estimate => "Rozdzielczość: {}\nCzas trwania: {}\nFormat: {}\nPrzewidywany rozmiar: {}",
+        Msg::Unknown => "nieznany",
+        Msg::NotAvailable => "b.d.",
+        Msg::UntitledVideo => "Film",
+    }
+}
+
+/// Initialises the language used when the user's language isn't supported.
+pub fn init_statics() {
+    DEFAULT_LANG
+        .set(
+            std::env::var("DEFAULT_LANG")
+                .unwrap_or_else(|_| "en".to_string())
+                .parse()
+                .unwrap_or_else(|()| {
+                    warn!("failed to parse DEFAULT_LANG, using default value");
+                    Lang::English
+                }),
+        )
+        .expect("DEFAULT_LANG was already initialised");
+}
+
+/// Returns the language of a user, falling back to `DEFAULT_LANG`.
+pub fn lang(user: Option<&User>) -> Lang {
+    user.and_then(|user| user.language_code.as_deref())
+        .and_then(|code| code.parse().ok())
+        .unwrap_or_else(|| *DEFAULT_LANG.get().expect("DEFAULT_LANG is not initialised"))
+}
+
+/// Translates a message, filling in its arguments in order.
+pub fn tr(lang: Lang, msg: Msg, args: &[&(dyn Display + Sync)]) -> String {
+    fill(lang.template(msg), args, str::to_string)
+}
+
+/// Translates a message for a formatted reply (see `markup`), escaping the message itself.
+/// The arguments are inserted as they are, so they have to be escaped (or formatted) already.
+pub fn tr_markup(lang: Lang, msg: Msg, args: &[&(dyn Display + Sync)]) -> String {
+    fill(lang.template(msg), args, markup::escape)
+}
+
+/// Fills in the arguments of a template, transforming the text around them.
+fn fill(
+    template: &str,
+    args: &[&(dyn Display + Sync)],
+    transform: impl Fn(&str) -> String,
+) -> String {
+    let mut parts = template.split("{}");
+    let mut args = args.iter();
+    let mut text = transform(parts.next().unwrap_or_default());
+
+    for part in parts {
+        if let Some(arg) = args.next() {
+            write!(text, "{arg}").ok();
+        }
+
+        text.push_str(&transform(part));
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::utils::markdown;
+
+    use super::*;
+
+    #[test]
+    fn escapes_the_text_around_the_arguments() {
+        assert_eq!(
+            fill("{} (1.5x) {}", &[&"*a*", &1.5], markdown::escape),
+            r"*a* \(1\.5x\) 1.5"
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! Inline mode, which lets the bot be used in chats it isn't a member of.
+//! Inline results can only reference files which are already on Telegram's servers,
+//! so the videos are uploaded to a cache chat first.
+
+use std::sync::OnceLock;
+
+use teloxide::{
+    prelude::*,
+    types::{
+        InlineQueryResult, InlineQueryResultArticle, InlineQueryResultCachedVideo, InputFile,
+        InputMessageContent, InputMessageContentText, User,
+    },
+};
+
+use crate::{
+    cache,
+    i18n::{self, Lang, Msg},
+    tasks, utils, whitelists, Bot, HandlerResult,
+};
+
+static CACHE_CHAT_ID: OnceLock<Option<ChatId>> = OnceLock::new();
+
+/// Initialises the cache chat; inline mode is disabled without one.
+pub fn init_statics() {
+    CACHE_CHAT_ID
+        .set(utils::optional_var("CACHE_CHAT_ID").and_then(|x| {
+            let chat_id = x.parse().ok().map(ChatId);
+
+            if chat_id.is_none() {
+                warn!("failed to parse CACHE_CHAT_ID, inline mode is disabled");
+            }
+
+            chat_id
+        }))
+        .expect("CACHE_CHAT_ID was already initialised");
+}
+
+/// A video uploaded to the cache chat.
+struct Uploaded {
+    file_id: String,
+    info: Option<utils::VideoInfo>,
+}
+
+/// Handles inline queries, answering with the video linked in the query.
+pub async fn handler(query: InlineQuery, bot: Bot) -> HandlerResult {
+    // inline queries aren't sent from a specific chat, so the global whitelist applies
+    let whitelist = whitelists::global();
+
+    // queries are sent as the user types, so wait until there's a link
+    if utils::get_url_info(&query.query, &whitelist).total_urls == 0 {
+        return Ok(());
+    }
+
+    let url_info = utils::get_url_info(
+        &utils::resolve_shortened_urls(&query.query).await,
+        &whitelist,
+    );
+
+    info!("received inline query: {}", query.query);
+
+    let lang = i18n::lang(Some(&query.from));
+
+    let cache_chat_id = *CACHE_CHAT_ID
+        .get()
+        .expect("CACHE_CHAT_ID is not initialised");
+
+    let result = match (cache_chat_id, url_info.maybe_url) {
+        (None, _) => Err(i18n::tr(lang, Msg::InlineDisabled, &[])),
+        (Some(chat_id), Some(url)) => {
+            let timeout = *crate::TASK_TIMEOUT
+                .get()
+                .expect("TASK_TIMEOUT is not initialised");
+
+            tokio::time::timeout(timeout, upload(&bot, chat_id, &url, &query.from, lang))
+                .await
+                .unwrap_or_else(|_| Err(i18n::tr(lang, Msg::TimedOut, &[&timeout.as_secs()])))
+        }
+        (Some(_), None) if url_info.whitelisted_urls == 0 && url_info.denied_urls > 0 => {
+            Err(i18n::tr(lang, Msg::NetlocDenied, &[]))
+        }
+        (Some(_), None) if url_info.whitelisted_urls == 0 => Err(i18n::tr(
+            lang,
+            Msg::NoWhitelistedUrls,
+            &[&whitelist.join(", ")],
+        )),
+        (Some(_), None) => Err(i18n::tr(lang, Msg::MultipleUrls, &[])),
+    };
+
+    let result = match result {
+        Ok(uploaded) => {
+            let title = uploaded
+                .info
+                .as_ref()
+                .and_then(|info| info.title.clone())
+                .unwrap_or_else(|| i18n::tr(lang, Msg::UntitledVideo, &[]));
+
+            let mut video = InlineQueryResultCachedVideo::new("video", uploaded.file_id, title);
+
+            let caption = crate::fit_caption(
+                &uploaded
+                    .info
+                    .as_ref()
+                    .map(utils::VideoInfo::describe)
+                    .unwrap_or_default(),
+            );
+
+            if !caption.is_empty() {
+                video = video.caption(caption);
+            }
+
+            InlineQueryResult::CachedVideo(video)
+        }
+        Err(text) => {
+            debug!("inline query failed: {}", text);
+
+            InlineQueryResult::Article(
+                InlineQueryResultArticle::new(
+                    "error",
+                    "Error",
+                    InputMessageContent::Text(InputMessageContentText::new(&text)),
+                )
+                .description(text),
+            )
+        }
+    };
+
+    // the query might have expired in the meantime, if the download took long
+    bot.answer_inline_query(query.id, [result])
+        .await
+        .log_on_error()
+        .await;
+
+    Ok(())
+}
+
+/// Uploads the video to the cache chat, unless it's already there.
+/// The upload is registered as a task of the user, so it's limited and can be cancelled like the others.
+async fn upload(
+    bot: &Bot,
+    chat_id: ChatId,
+    url: &str,
+    user: &User,
+    lang: Lang,
+) -> Result<Uploaded, String> {
+    let options = utils::DownloadOptions {
+        media: utils::Media::Video,
+        playlist_end: None,
+        max_height: None,
+        section: None,
+        fallback: false,
+    };
+
+    let cache_key = cache::key(url, options);
+
+    // queries are sent as the user types, so the same video is usually requested again while it's
+    // downloading; the repeated queries wait for that download, like the repeated requests in chats
+    let in_flight = match cache_key.clone().map(cache::begin) {
+        Some(Ok(in_flight)) => Some(in_flight),
+        Some(Err(mut finished)) => {
+            info!("waiting for the download in progress: {}", url);
+
+            // the channel is closed once the other download finishes
+            while finished.changed().await.is_ok() {}
+
+            if *finished.borrow() == Some(false) {
+                return Err(i18n::tr(lang, Msg::DownloadFailed, &[]));
+            }
+
+            None
+        }
+        None => None,
+    };
+
+    // videos which have already been sent can be referenced right away
+    if let Some(entry) = cache_key.as_ref().and_then(cache::get) {
+        info!("using a cached file for {}", url);
+        metrics::counter!("cache_hits_total").increment(1);
+
+        return Ok(Uploaded {
+            file_id: entry.file_id,
+            info: entry.info,
+        });
+    }
+
+    // don't let a single user flood the queue (the maintainer is exempt)
+    let max_tasks = *crate::MAX_TASKS_PER_USER
+        .get()
+        .expect("MAX_TASKS_PER_USER is not initialised");
+
+    let user_tasks = tasks::count_for_user(user.id);
+
+    if user_tasks >= max_tasks && !crate::is_maintainer_user(user) {
+        debug!("user {} has too many tasks in progress", user.id);
+        return Err(i18n::tr(lang, Msg::TooManyTasks, &[&user_tasks]));
+    }
+
+    // register the task, so that it can be cancelled (unless the queue is full)
+    let Some(task) = tasks::Task::register_inline(user.id, url) else {
+        warn!("the queue is full, rejecting {}", url);
+        metrics::counter!("downloads_rejected_total").increment(1);
+
+        return Err(i18n::tr(lang, Msg::Overloaded, &[]));
+    };
+
+    // dropping the download kills yt-dlp and ffmpeg, as they're spawned with kill_on_drop
+    let result = tokio::select! {
+        result = download_and_upload(bot, chat_id, url, options, &task, lang) => result,
+        () = task.token().cancelled() => {
+            info!("download cancelled: {}", url);
+            metrics::counter!("downloads_cancelled_total").increment(1);
+
+            // tasks cancelled at the end of the grace period weren't cancelled by the user
+            let msg = if tasks::is_shutting_down() {
+                Msg::ShuttingDown
+            } else {
+                Msg::Cancelled
+            };
+
+            // the waiting queries download the video themselves then
+            return Err(i18n::tr(lang, msg, &[]));
+        }
+    };
+
+    drop(task);
+
+    if let (Ok(uploaded), Some(key)) = (&result, cache_key) {
+        cache::insert(key, uploaded.file_id.clone(), uploaded.info.clone());
+    }
+
+    // the waiting queries look the video up in the cache
+    if let Some(in_flight) = in_flight {
+        in_flight.finish(result.is_ok());
+    }
+
+    result
+}
+
+#[allow(clippy::too_many_lines)] // sorry
+/// Downloads a video, converts it and uploads it to the cache chat.
+async fn download_and_upload(
+    bot: &Bot,
+    chat_id: ChatId,
+    url: &str,
+    options: utils::DownloadOptions,
+    task: &tasks::Task,
+    lang: Lang,
+) -> Result<Uploaded, String> {
+    // tasks which haven't started yet aren't started during the shutdown
+    let worker = tokio::select! {
+        worker = crate::WORKERS.get().expect("WORKERS is not initialised").acquire() => worker,
+        () = tasks::shutdown_token().cancelled() => {
+            return Err(i18n::tr(lang, Msg::ShuttingDown, &[]));
+        }
+    };
+
+    task.start();
+
+    let preflight = utils::preflight(url).await;
+
+    if preflight.as_ref().is_some_and(utils::Preflight::is_ongoing) {
+        return Err(i18n::tr(lang, Msg::Livestream, &[]));
+    }
+
+    if let Some((duration, limit)) = preflight
+        .and_then(|preflight| preflight.duration)
+        .and_then(crate::exceeds_max_duration)
+    {
+        return Err(i18n::tr(
+            lang,
+            Msg::TooLong,
+            &[&crate::minutes(duration), &crate::minutes(limit)],
+        ));
+    }
+
+    let temp_dir = utils::temp_dir().unwrap();
+    let dir_path = temp_dir.path().to_str().unwrap();
+
+    let download = async {
+        let _domain = tasks::acquire_domain(url).await;
+        utils::download(url, dir_path, options, |_| {}).await
+    };
+
+    let (result, info) = tokio::join!(download, utils::get_info(url));
+
+    match result.map_err(|failure| failure.error) {
+        Ok(()) => {}
+        Err(utils::DownloadError::Proxy) => {
+            return Err(i18n::tr(
+                lang,
+                Msg::ProxyFailed,
+                &[crate::MAINTAINER
+                    .get()
+                    .expect("MAINTAINER is not initialised")],
+            ));
+        }
+        Err(utils::DownloadError::Private) => return Err(i18n::tr(lang, Msg::PrivateVideo, &[])),
+        Err(utils::DownloadError::Unavailable) => {
+            return Err(i18n::tr(lang, Msg::VideoUnavailable, &[]))
+        }
+        Err(_) => return Err(i18n::tr(lang, Msg::DownloadFailed, &[])),
+    }
+
+    // find the video (subtitles are ignored, as they can't be sent along, and so are thumbnails,
+    // the unfinished files of resumed downloads and the other files written alongside the video)
+    let files = std::fs::read_dir(dir_path)
+        .unwrap()
+        .filter_map(std::result::Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            !path.extension().is_some_and(|ext| {
+                utils::SUBTITLE_EXTENSIONS
+                    .iter()
+                    .chain(&utils::THUMBNAIL_EXTENSIONS)
+                    .chain(&utils::PARTIAL_EXTENSIONS)
+                    .chain(&utils::SIDECAR_EXTENSIONS)
+                    .any(|s| ext == *s)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let [file_path] = files.as_slice() else {
+        return Err(i18n::tr(lang, Msg::DownloadFailed, &[]));
+    };
+
+    let file_path = file_path.to_str().unwrap();
+    let max_filesize = crate::max_filesize(url, false);
+
+    if utils::file_size(file_path) / 1000 / 1000 > max_filesize {
+        return Err(i18n::tr(lang, Msg::FileTooBig, &[&max_filesize]));
+    }
+
+    // convert the video, reducing the bitrate if it doesn't fit
+    let output = format!("{dir_path}/{}.mp4", utils::random_string(10));
+    let metadata = utils::probe(file_path)
+        .await
+        .ok_or_else(|| i18n::tr(lang, Msg::UnsupportedMedia, &[]))?;
+
+    crate::convert_video(
+        file_path,
+        &output,
+        &metadata,
+        crate::skips_to_fallback(&metadata),
+        false,
+        &[],
+    )
+    .await
+    .map_err(|e| e.message(lang))?;
+
+    // the converted video might have been scaled down (see MAX_OUTPUT_HEIGHT)
+    let metadata = utils::probe(&output).await.unwrap_or(metadata);
+
+    // the worker can start on the next download while the video is uploading
+    drop(worker);
+
+    let _upload = crate::UPLOADS
+        .get()
+        .expect("UPLOADS is not initialised")
+        .acquire()
+        .await;
+
+    let mut request = bot
+        .send_video(chat_id, InputFile::file(&output))
+        .width(metadata.width)
+        .height(metadata.height)
+        .duration(metadata.duration)
+        .supports_streaming(true);
+
+    let site_thumbnail = utils::find_thumbnail(file_path);
+
+    if let Some(thumbnail) =
+        utils::get_thumbnail(&output, site_thumbnail.as_deref(), metadata.duration).await
+    {
+        request = request.thumb(thumbnail);
+    }
+
+    let sent = request.await.map_err(|e| {
+        error!("failed to upload the video to the cache chat: {}", e);
+        i18n::tr(lang, Msg::UploadFailed, &[])
+    })?;
+
+    info!("the video has been uploaded to the cache chat");
+    crate::stats::record_bytes_sent(utils::file_size(&output));
+
+    let file_id = sent
+        .video()
+        .map(|video| video.file.id.clone())
+        .ok_or_else(|| i18n::tr(lang, Msg::UploadFailed, &[]))?;
+
+    Ok(Uploaded { file_id, info })
+}
@@ -1,40 +1,361 @@
 //! This is the main file of the application.
 
-use std::sync::OnceLock;
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
 
-use async_lock::Mutex;
+use async_lock::{Mutex, Semaphore, SemaphoreGuard};
 use dotenvy::dotenv;
+use log::LevelFilter;
+use log4rs::{
+    append::console::ConsoleAppender,
+    config::{Appender, Config, Root},
+    encode::json::JsonEncoder,
+};
 use teloxide::{
-    dispatching::UpdateHandler,
+    adaptors::{throttle::Limits, Throttle},
+    dispatching::{ShutdownToken, UpdateHandler},
     prelude::*,
-    types::{ChatKind, InputFile, MessageCommon, ParseMode},
+    types::{
+        ChatKind, ChatMemberKind, ChatMemberUpdated, InlineKeyboardButton, InlineKeyboardMarkup,
+        InputFile, InputMedia, InputMediaPhoto, Me, MessageCommon, MessageId, User,
+    },
+    update_listeners::webhooks,
+    utils::command::BotCommands,
+    ApiError, RequestError,
 };
-use tempfile::tempdir;
 
 #[macro_use]
 extern crate simple_log;
 
+mod cache;
+mod commands;
+#[cfg(test)]
+mod harness;
+mod health;
+mod i18n;
+mod inline;
+mod markup;
+mod stats;
+mod tasks;
 mod utils;
+mod whitelists;
+
+use commands::Command;
+use i18n::Msg;
+use markup::Formatted;
 
 static MAX_FILESIZE: OnceLock<u64> = OnceLock::new();
+static FALLBACK_MAX_FILESIZE: OnceLock<u64> = OnceLock::new();
+static FALLBACK_HOSTS: OnceLock<Vec<String>> = OnceLock::new();
+static MAX_DURATION: OnceLock<u64> = OnceLock::new();
+static LARGE_VIDEO_MB: OnceLock<u64> = OnceLock::new();
+static LARGE_VIDEO_SECS: OnceLock<u64> = OnceLock::new();
+static MAX_PLAYLIST_ITEMS: OnceLock<u32> = OnceLock::new();
+static MAX_TASKS_PER_USER: OnceLock<usize> = OnceLock::new();
+static CAPTION_INFO: OnceLock<bool> = OnceLock::new();
+static CAPTION_FOOTER: OnceLock<Option<String>> = OnceLock::new();
 static MAINTAINER: OnceLock<String> = OnceLock::new();
-static NETLOCS: OnceLock<String> = OnceLock::new();
-static MUTEX: OnceLock<Mutex<()>> = OnceLock::new();
+static WORKER_COUNT: OnceLock<u32> = OnceLock::new();
+static WORKERS: OnceLock<Semaphore> = OnceLock::new();
+static UPLOADS: OnceLock<Semaphore> = OnceLock::new();
+static PRECHECKS: OnceLock<Option<Semaphore>> = OnceLock::new();
+static REPORT_CHAT_ID: OnceLock<Option<ChatId>> = OnceLock::new();
+static LAST_REPORT: std::sync::Mutex<Option<(Instant, u32)>> = std::sync::Mutex::new(None);
 static COUNT: OnceLock<Mutex<u32>> = OnceLock::new();
+static UPDATING: Mutex<()> = Mutex::new(());
+static TASK_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static PREFER_SPLIT: OnceLock<bool> = OnceLock::new();
+static ALLOW_MULTI_URL: OnceLock<bool> = OnceLock::new();
+static DOCUMENT_FALLBACK: OnceLock<bool> = OnceLock::new();
+static REPLY_TO_TRIGGER: OnceLock<bool> = OnceLock::new();
+static DELIVERY_CHATS: OnceLock<Vec<(ChatId, ChatId)>> = OnceLock::new();
+static ALLOW_BITRATE_REDUCTION: OnceLock<bool> = OnceLock::new();
+static SHUTDOWN_GRACE: OnceLock<Duration> = OnceLock::new();
+static ACCEPTANCE_DELAY: OnceLock<Duration> = OnceLock::new();
+static IDLE_SHUTDOWN: OnceLock<Option<Duration>> = OnceLock::new();
+static LAST_ACTIVITY: std::sync::Mutex<Option<Instant>> = std::sync::Mutex::new(None);
+static LAST_REFRESH: std::sync::Mutex<Option<HashMap<(ChatId, MessageId), Instant>>> =
+    std::sync::Mutex::new(None);
+static ANIMATION_MAX_SECS: OnceLock<u32> = OnceLock::new();
+static ANIMATION_MAX_MB: OnceLock<u64> = OnceLock::new();
+static MIN_YTDLP_VERSION: OnceLock<utils::Version> = OnceLock::new();
+static MIN_FFMPEG_VERSION: OnceLock<utils::Version> = OnceLock::new();
+
+/// Maximum size of the downloaded files (in megabytes), unless `MAX_FILESIZE` says otherwise.
+const DEFAULT_MAX_FILESIZE: u64 = 250;
+
+/// How long the size of a video is estimated for before downloading it anyway.
+const PRECHECK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Minimum time between two failure reports sent to the maintainer.
+const REPORT_INTERVAL: Duration = Duration::from_mins(1);
+
+/// Maximum length of the yt-dlp errors included in a failure report.
+const MAX_REPORT_STDERR: usize = 1000;
+
+/// How often an idle bot checks whether the tasks in progress have finished, so that it can shut down.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The callback data of the button refreshing /stats.
+const REFRESH_STATS: &str = "refresh_stats";
+
+/// Minimum time between the refreshes of a /stats message.
+const STATS_REFRESH_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Maximum number of photos Telegram accepts in a single media group.
+const MAX_MEDIA_GROUP: usize = 10;
+
+/// How many times a video which Telegram refused as too large is converted again.
+const MAX_OVERSIZE_RETRIES: u32 = 1;
+
+/// Maximum length of a Telegram caption.
+const CAPTION_LIMIT: usize = 1024;
+
+/// Minimum time between download progress updates.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Maximum number of URLs downloaded from a single message, if `ALLOW_MULTI_URL` is set.
+const MAX_BATCH_URLS: usize = 5;
+
+/// How a download task ended.
+enum Outcome {
+    /// At least one file has been sent.
+    Sent,
+    /// Nothing has been sent (the user has already been told why).
+    Failed,
+    /// The task took longer than `TASK_TIMEOUT_SECS`.
+    TimedOut,
+    /// The user cancelled the task.
+    Cancelled,
+    /// The bot shut down before the task could be finished.
+    Interrupted,
+    /// The bot can no longer send anything to the chat (e.g. the user blocked it).
+    Abandoned,
+}
 
+#[allow(clippy::too_many_lines)] // sorry
 /// Initialises static variables.
 fn init_statics() {
     MAX_FILESIZE
+        .set(parse_filesize(
+            "MAX_FILESIZE",
+            std::env::var("MAX_FILESIZE").ok().as_deref(),
+            DEFAULT_MAX_FILESIZE,
+        ))
+        .expect("MAX_FILESIZE was already initialised");
+
+    // sites which routinely exceed MAX_FILESIZE can be given a larger limit
+    let max_filesize = *MAX_FILESIZE.get().expect("MAX_FILESIZE is not initialised");
+
+    FALLBACK_MAX_FILESIZE
+        .set(parse_filesize(
+            "FALLBACK_MAX_FILESIZE",
+            std::env::var("FALLBACK_MAX_FILESIZE").ok().as_deref(),
+            max_filesize,
+        ))
+        .expect("FALLBACK_MAX_FILESIZE was already initialised");
+
+    FALLBACK_HOSTS
+        .set(
+            std::env::var("FALLBACK_HOSTS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        )
+        .expect("FALLBACK_HOSTS was already initialised");
+
+    // zero means no limit, as long videos can be reduced in quality (or split) to fit
+    MAX_DURATION
         .set(
-            std::env::var("MAX_FILESIZE")
-                .unwrap_or_else(|_| "250".to_string())
+            std::env::var("MAX_DURATION_SECS")
+                .unwrap_or_else(|_| "0".to_string())
                 .parse()
                 .unwrap_or_else(|_| {
-                    warn!("failed to parse MAX_FILESIZE, using default value");
-                    250
+                    warn!("failed to parse MAX_DURATION_SECS, using default value");
+                    0
                 }),
         )
-        .expect("MAX_FILESIZE was already initialised");
+        .expect("MAX_DURATION was already initialised");
+
+    LARGE_VIDEO_MB
+        .set(
+            std::env::var("LARGE_VIDEO_MB")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse LARGE_VIDEO_MB, using default value");
+                    0
+                }),
+        )
+        .expect("LARGE_VIDEO_MB was already initialised");
+
+    LARGE_VIDEO_SECS
+        .set(
+            std::env::var("LARGE_VIDEO_SECS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse LARGE_VIDEO_SECS, using default value");
+                    0
+                }),
+        )
+        .expect("LARGE_VIDEO_SECS was already initialised");
+
+    MAX_PLAYLIST_ITEMS
+        .set(
+            std::env::var("MAX_PLAYLIST_ITEMS")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse MAX_PLAYLIST_ITEMS, using default value");
+                    1
+                }),
+        )
+        .expect("MAX_PLAYLIST_ITEMS was already initialised");
+
+    MAX_TASKS_PER_USER
+        .set(
+            std::env::var("MAX_TASKS_PER_USER")
+                .unwrap_or_else(|_| "3".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse MAX_TASKS_PER_USER, using default value");
+                    3
+                }),
+        )
+        .expect("MAX_TASKS_PER_USER was already initialised");
+
+    CAPTION_INFO
+        .set(
+            std::env::var("CAPTION_INFO")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse CAPTION_INFO, using default value");
+                    true
+                }),
+        )
+        .expect("CAPTION_INFO was already initialised");
+
+    // e.g. a disclaimer or a link back to the bot, added to every caption
+    CAPTION_FOOTER
+        .set(utils::optional_var("CAPTION_FOOTER"))
+        .expect("CAPTION_FOOTER was already initialised");
+
+    PREFER_SPLIT
+        .set(
+            std::env::var("PREFER_SPLIT")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse PREFER_SPLIT, using default value");
+                    false
+                }),
+        )
+        .expect("PREFER_SPLIT was already initialised");
+
+    ALLOW_MULTI_URL
+        .set(
+            std::env::var("ALLOW_MULTI_URL")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse ALLOW_MULTI_URL, using default value");
+                    false
+                }),
+        )
+        .expect("ALLOW_MULTI_URL was already initialised");
+
+    DOCUMENT_FALLBACK
+        .set(
+            std::env::var("DOCUMENT_FALLBACK")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse DOCUMENT_FALLBACK, using default value");
+                    true
+                }),
+        )
+        .expect("DOCUMENT_FALLBACK was already initialised");
+
+    REPLY_TO_TRIGGER
+        .set(
+            std::env::var("REPLY_TO_TRIGGER")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse REPLY_TO_TRIGGER, using default value");
+                    true
+                }),
+        )
+        .expect("REPLY_TO_TRIGGER was already initialised");
+
+    ALLOW_BITRATE_REDUCTION
+        .set(
+            std::env::var("ALLOW_BITRATE_REDUCTION")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse ALLOW_BITRATE_REDUCTION, using default value");
+                    true
+                }),
+        )
+        .expect("ALLOW_BITRATE_REDUCTION was already initialised");
+
+    // short silent clips are sent as animations, so that they autoplay like GIFs
+    ANIMATION_MAX_SECS
+        .set(
+            std::env::var("ANIMATION_MAX_SECS")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse ANIMATION_MAX_SECS, using default value");
+                    10
+                }),
+        )
+        .expect("ANIMATION_MAX_SECS was already initialised");
+
+    ANIMATION_MAX_MB
+        .set(
+            std::env::var("ANIMATION_MAX_MB")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse ANIMATION_MAX_MB, using default value");
+                    10
+                }),
+        )
+        .expect("ANIMATION_MAX_MB was already initialised");
+
+    // older binaries lack some of the flags and output formats the bot relies on
+    MIN_YTDLP_VERSION
+        .set(
+            std::env::var("MIN_YTDLP_VERSION")
+                .unwrap_or_else(|_| "2023.11.16".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse MIN_YTDLP_VERSION, using default value");
+                    utils::Version(vec![2023, 11, 16])
+                }),
+        )
+        .expect("MIN_YTDLP_VERSION was already initialised");
+
+    MIN_FFMPEG_VERSION
+        .set(
+            std::env::var("MIN_FFMPEG_VERSION")
+                .unwrap_or_else(|_| "4.4".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse MIN_FFMPEG_VERSION, using default value");
+                    utils::Version(vec![4, 4])
+                }),
+        )
+        .expect("MIN_FFMPEG_VERSION was already initialised");
 
     MAINTAINER
         .set({
@@ -53,34 +374,161 @@ fn init_statics() {
 
     // initialise the whitelist, as it's used to extract netlocs
     utils::init_statics();
+    whitelists::init_statics();
+    tasks::init_statics();
+    inline::init_statics();
+    cache::init_statics();
+    i18n::init_statics();
+    markup::init_statics();
+    stats::init_statics();
 
-    NETLOCS
+    TASK_TIMEOUT
+        .set(Duration::from_secs(
+            std::env::var("TASK_TIMEOUT_SECS")
+                .unwrap_or_else(|_| "600".to_string())
+                .parse()
+                .ok()
+                .filter(|&x| x > 0)
+                .unwrap_or_else(|| {
+                    warn!("failed to parse TASK_TIMEOUT_SECS, using default value");
+                    600
+                }),
+        ))
+        .expect("TASK_TIMEOUT was already initialised");
+
+    SHUTDOWN_GRACE
+        .set(Duration::from_secs(
+            std::env::var("SHUTDOWN_GRACE_SECS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse SHUTDOWN_GRACE_SECS, using default value");
+                    60
+                }),
+        ))
+        .expect("SHUTDOWN_GRACE was already initialised");
+
+    ACCEPTANCE_DELAY
+        .set(Duration::from_secs(
+            std::env::var("ACCEPTANCE_DELAY_SECS")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse ACCEPTANCE_DELAY_SECS, using default value");
+                    0
+                }),
+        ))
+        .expect("ACCEPTANCE_DELAY was already initialised");
+
+    IDLE_SHUTDOWN
         .set(
-            utils::WHITELIST
-                .get()
-                .expect("WHITELIST is not initialised")
-                .iter()
-                .map(|x| format!("`{x}`"))
-                .collect::<Vec<_>>()
-                .join(", "),
+            utils::optional_var("IDLE_SHUTDOWN_SECS")
+                .and_then(|x| {
+                    let secs = x.parse().ok();
+
+                    if secs.is_none() {
+                        warn!(
+                            "failed to parse IDLE_SHUTDOWN_SECS, the bot won't shut down when idle"
+                        );
+                    }
+
+                    secs
+                })
+                .filter(|&x| x > 0)
+                .map(Duration::from_secs),
+        )
+        .expect("IDLE_SHUTDOWN was already initialised");
+
+    WORKER_COUNT
+        .set(
+            std::env::var("WORKER_COUNT")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse()
+                .ok()
+                .filter(|&x| x > 0)
+                .unwrap_or_else(|| {
+                    warn!("failed to parse WORKER_COUNT, using default value");
+                    1
+                }),
         )
-        .expect("NETLOCS was already initialised");
+        .expect("WORKER_COUNT was already initialised");
+
+    WORKERS
+        .set(Semaphore::new(
+            *WORKER_COUNT.get().expect("WORKER_COUNT is not initialised") as usize,
+        ))
+        .expect("WORKERS was already initialised");
+
+    // uploads don't occupy the workers, so that they can start on the next download meanwhile
+    let worker_count = *WORKER_COUNT.get().expect("WORKER_COUNT is not initialised") as usize;
+
+    UPLOADS
+        .set(Semaphore::new(
+            std::env::var("MAX_CONCURRENT_UPLOADS")
+                .unwrap_or_else(|_| worker_count.to_string())
+                .parse()
+                .ok()
+                .filter(|&x| x > 0)
+                .unwrap_or_else(|| {
+                    warn!("failed to parse MAX_CONCURRENT_UPLOADS, using default value");
+                    worker_count
+                }),
+        ))
+        .expect("UPLOADS was already initialised");
+
+    // estimating the size runs before the queue, so it's limited separately
+    let size_precheck = std::env::var("SIZE_PRECHECK")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse()
+        .unwrap_or_else(|_| {
+            warn!("failed to parse SIZE_PRECHECK, using default value");
+            false
+        });
+
+    PRECHECKS
+        .set(size_precheck.then(|| {
+            Semaphore::new(
+                std::env::var("MAX_CONCURRENT_PRECHECKS")
+                    .unwrap_or_else(|_| "2".to_string())
+                    .parse()
+                    .ok()
+                    .filter(|&x| x > 0)
+                    .unwrap_or_else(|| {
+                        warn!("failed to parse MAX_CONCURRENT_PRECHECKS, using default value");
+                        2
+                    }),
+            )
+        }))
+        .expect("PRECHECKS was already initialised");
 
-    MUTEX
-        .set(Mutex::new(()))
-        .expect("MUTEX was already initialised");
+    REPORT_CHAT_ID
+        .set(
+            utils::optional_var("REPORT_ERRORS_TO_MAINTAINER").and_then(|x| {
+                let chat_id = x.parse().ok().map(ChatId);
+
+                if chat_id.is_none() {
+                    warn!(
+                        "failed to parse REPORT_ERRORS_TO_MAINTAINER, failures won't be reported"
+                    );
+                }
+
+                chat_id
+            }),
+        )
+        .expect("REPORT_CHAT_ID was already initialised");
 
     COUNT
         .set(Mutex::new(0))
         .expect("COUNT was already initialised");
 }
 
+#[allow(clippy::too_many_lines)] // sorry
 /// Starts the application.
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     // initialise the application, logger included
     dotenv().expect("failed to load .env file");
-    simple_log::quick!("info");
+    init_logger();
     init_statics();
 
     // make sure that the process can access essential binaries
@@ -88,19 +536,325 @@ async fn main() {
         assert!(which::which(x).is_ok(), "failed to find {x} in PATH");
     }
 
+    // make sure that the binaries aren't too old
+    check_version(
+        "yt-dlp",
+        MIN_YTDLP_VERSION
+            .get()
+            .expect("MIN_YTDLP_VERSION is not initialised"),
+    );
+    check_version(
+        "ffmpeg",
+        MIN_FFMPEG_VERSION
+            .get()
+            .expect("MIN_FFMPEG_VERSION is not initialised"),
+    );
+
+    // make sure that the cookie file exists, if one was specified
+    if let Some(path) = utils::COOKIES_FILE
+        .get()
+        .expect("COOKIES_FILE is not initialised")
+    {
+        assert!(
+            std::path::Path::new(path).is_file(),
+            "failed to find the cookie file: {path}"
+        );
+    }
+
+    // make sure that ffmpeg can encode the videos as configured
+    let codec = utils::VIDEO_CODEC
+        .get()
+        .expect("VIDEO_CODEC is not initialised");
+
+    assert!(
+        utils::has_encoder(codec),
+        "ffmpeg doesn't support the {codec} encoder"
+    );
+
+    // make sure that the proxies can be passed to yt-dlp
+    let proxies = utils::PROXY_URL
+        .get()
+        .expect("PROXY_URL is not initialised")
+        .iter()
+        .chain(
+            utils::PROXY_HOSTS
+                .get()
+                .expect("PROXY_HOSTS is not initialised")
+                .iter()
+                .map(|(_, proxy)| proxy),
+        )
+        .chain(
+            utils::PROXY_POOL
+                .get()
+                .expect("PROXY_POOL is not initialised"),
+        );
+
+    for proxy in proxies {
+        assert!(utils::is_valid_proxy(proxy), "invalid proxy URL: {proxy}");
+    }
+
+    // expose Prometheus metrics, if requested
+    if let Ok(addr) = std::env::var("METRICS_ADDR") {
+        let addr: std::net::SocketAddr = addr.trim().parse().expect("failed to parse METRICS_ADDR");
+
+        metrics_exporter_prometheus::PrometheusBuilder::new()
+            .with_http_listener(addr)
+            .install()
+            .expect("failed to start the metrics server");
+
+        info!("serving metrics on {}", addr);
+    }
+
+    // answer liveness and readiness probes, if requested
+    if let Some(addr) = utils::optional_var("HEALTHCHECK_ADDR") {
+        let addr = addr.parse().expect("failed to parse HEALTHCHECK_ADDR");
+        tokio::spawn(health::serve(addr));
+    }
+
     info!("application started");
 
-    let bot = Bot::from_env();
-    Dispatcher::builder(bot, schema())
-        .enable_ctrlc_handler()
+    // a local Bot API server allows for larger uploads (see TELEGRAM_UPLOAD_LIMIT_MB)
+    let bot = utils::optional_var("TELEGRAM_API_URL").map_or_else(teloxide::Bot::from_env, |url| {
+        teloxide::Bot::from_env()
+            .set_api_url(url.parse().expect("failed to parse TELEGRAM_API_URL"))
+    });
+
+    // the requests are queued per chat, keeping their order, so that Telegram's flood control isn't hit;
+    // the requests which hit it anyway are retried once Telegram allows it
+    let bot = bot.throttle(Limits::default());
+
+    // the files requested in some chats are sent to other chats instead, if the bot can post there
+    DELIVERY_CHATS
+        .set(check_delivery_chats(&bot, parse_delivery_chats()).await)
+        .expect("DELIVERY_CHATS was already initialised");
+
+    // resume the downloads which were queued before the restart
+    let persisted = tasks::take_persisted();
+
+    if !persisted.is_empty() {
+        info!("resuming {} persisted tasks", persisted.len());
+        let me = bot.get_me().await.expect("failed to retrieve 'me'");
+
+        for message in persisted {
+            let bot = bot.clone();
+            let me = me.clone();
+
+            tokio::spawn(async move {
+                change_count_by(1).await.ok();
+
+                if let Err(e) = handler(message, bot, me).await {
+                    error!("failed to resume a task: {}", e);
+                }
+
+                change_count_by(-1).await.ok();
+            });
+        }
+    }
+
+    let webhook = webhook_options();
+
+    let mut dispatcher = Dispatcher::builder(bot.clone(), schema())
         .distribution_function(|_| None::<std::convert::Infallible>)
-        .build()
-        .dispatch()
-        .await;
+        .build();
+
+    // on ^C, stop accepting new requests and give the downloads in progress some time to finish
+    let shutdown_token = dispatcher.shutdown_token();
+
+    tokio::spawn({
+        let shutdown_token = shutdown_token.clone();
+
+        async move {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("failed to listen for ^C");
+
+            info!("^C received, finishing the downloads in progress");
+            shut_down(&shutdown_token).await;
+        }
+    });
+
+    // the bot can also exit once it's idle, to be started again on demand (e.g. by systemd)
+    if let Some(idle) = *IDLE_SHUTDOWN
+        .get()
+        .expect("IDLE_SHUTDOWN is not initialised")
+    {
+        tokio::spawn(async move {
+            wait_until_idle(idle).await;
+
+            info!("no activity for {} seconds, shutting down", idle.as_secs());
+            shut_down(&shutdown_token).await;
+        });
+    }
+
+    // both modes share the dispatcher, so ^C is handled the same way
+    if let Some(options) = webhook {
+        info!("receiving updates via a webhook on {}", options.address);
+
+        let listener = webhooks::axum(bot, options)
+            .await
+            .expect("failed to set up the webhook");
+
+        dispatcher
+            .dispatch_with_listener(
+                listener,
+                LoggingErrorHandler::with_custom_text("an error from the webhook listener"),
+            )
+            .await;
+    } else {
+        info!("receiving updates via long polling");
+        dispatcher.dispatch().await;
+    }
+
+    utils::remove_cookies();
+    info!("application stopped");
+}
+
+/// Stops accepting new requests, giving the downloads in progress `SHUTDOWN_GRACE` to finish.
+async fn shut_down(shutdown_token: &ShutdownToken) {
+    tasks::shutdown_token().cancel();
+
+    // the bot might be shutting down already
+    let Ok(shutdown) = shutdown_token.shutdown() else {
+        return;
+    };
+
+    let grace = *SHUTDOWN_GRACE
+        .get()
+        .expect("SHUTDOWN_GRACE is not initialised");
+
+    if tokio::time::timeout(grace, shutdown).await.is_err() {
+        let dropped = tasks::cancel_all();
+        warn!("grace period expired, dropped {} tasks", dropped);
+    }
+}
+
+/// Records that an update has been received, which keeps the bot from shutting down when idle.
+fn record_activity() {
+    *LAST_ACTIVITY.lock().unwrap() = Some(Instant::now());
 }
 
+/// Waits until no update has been received for `idle` (since the bot started, if none has been),
+/// and no task is in progress, as the downloads which are running (or queued) defer the shutdown.
+async fn wait_until_idle(idle: Duration) {
+    let started = Instant::now();
+
+    loop {
+        let last_activity = LAST_ACTIVITY.lock().unwrap().unwrap_or(started);
+
+        if last_activity.elapsed() < idle {
+            tokio::time::sleep_until(tokio::time::Instant::from_std(last_activity + idle)).await;
+            continue;
+        }
+
+        let count = *COUNT.get().expect("COUNT is not initialised").lock().await;
+
+        if count == 0 && tasks::count() == 0 {
+            return;
+        }
+
+        tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+    }
+}
+
+/// What to do with the link found in a message.
+#[derive(Clone, Copy)]
+enum Action {
+    /// Download the media (in at most the specified resolution, optionally just a section of it)
+    /// and send it to the chat, optionally covered with a spoiler.
+    /// Retried downloads use the fallback path (see `DownloadOptions::fallback`).
+    Download {
+        media: utils::Media,
+        max_height: Option<u32>,
+        section: Option<utils::Section>,
+        spoiler: bool,
+        fallback: bool,
+    },
+    /// List the formats the video is available in.
+    ListFormats,
+    /// Report what would be downloaded (in at most the specified resolution).
+    Estimate { max_height: Option<u32> },
+    /// Send the thumbnail of the video as a photo.
+    Thumbnail,
+}
+
+/// The bot, with its requests throttled to stay within Telegram's limits.
+type Bot = Throttle<teloxide::Bot>;
+
 type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
+/// Reads the webhook settings; long polling is used unless both `WEBHOOK_URL` and `WEBHOOK_ADDR` are set.
+/// The URL is validated right away, as Telegram only accepts HTTPS URLs on a few ports.
+fn webhook_options() -> Option<webhooks::Options> {
+    let (url, addr) = match (
+        utils::optional_var("WEBHOOK_URL"),
+        utils::optional_var("WEBHOOK_ADDR"),
+    ) {
+        (Some(url), Some(addr)) => (url, addr),
+        (None, None) => return None,
+        _ => panic!("WEBHOOK_URL and WEBHOOK_ADDR have to be set together"),
+    };
+
+    let url: url::Url = url.parse().expect("failed to parse WEBHOOK_URL");
+    let addr = addr.parse().expect("failed to parse WEBHOOK_ADDR");
+
+    assert!(url.scheme() == "https", "WEBHOOK_URL has to use HTTPS");
+    assert!(
+        matches!(url.port_or_known_default(), Some(443 | 80 | 88 | 8443)),
+        "WEBHOOK_URL has to use one of the ports 443, 80, 88 or 8443"
+    );
+
+    Some(webhooks::Options::new(addr, url))
+}
+
+/// Initialises the logger, in the format selected by `LOG_FORMAT`.
+fn init_logger() {
+    let format = utils::optional_var("LOG_FORMAT");
+
+    // JSON lines are written to stdout only, as they're meant to be collected from there
+    if format.as_deref() == Some("json") {
+        let stdout = ConsoleAppender::builder()
+            .encoder(Box::new(JsonEncoder::new()))
+            .build();
+
+        let config = Config::builder()
+            .appender(Appender::builder().build("stdout", Box::new(stdout)))
+            .build(Root::builder().appender("stdout").build(LevelFilter::Info))
+            .expect("failed to configure the logger");
+
+        log4rs::init_config(config).expect("failed to initialise the logger");
+        return;
+    }
+
+    simple_log::quick!("info");
+
+    if let Some(format) = format.filter(|x| x != "pretty") {
+        warn!("unknown LOG_FORMAT {}, using the default format", format);
+    }
+}
+
+/// Logs the version of a binary, refusing to start if it's older than the minimum.
+/// Versions which can't be parsed (like development builds) only trigger a warning.
+fn check_version(binary: &str, minimum: &utils::Version) {
+    let Some(version) = utils::version_string(binary) else {
+        warn!("failed to get the version of {}", binary);
+        return;
+    };
+
+    info!("detected {} version {}", binary, version);
+
+    if let Ok(parsed) = version.parse::<utils::Version>() {
+        assert!(
+            parsed >= *minimum,
+            "{binary} {version} is too old, at least {minimum} is required"
+        );
+    } else {
+        warn!(
+            "failed to parse {} version {}, make sure it's at least {}",
+            binary, version, minimum
+        );
+    }
+}
+
 /// Defines routes for the bot.
 fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>> {
     let call = dptree::entry()
@@ -111,376 +865,3242 @@ fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>>
         // finally, we decrement the counter
         .endpoint(|| change_count_by(-1));
 
-    // we want to handle both messages and channel posts
+    let inline_call = dptree::entry()
+        .map_async(|| change_count_by(1))
+        .map_async(inline::handler)
+        .endpoint(|| change_count_by(-1));
+
+    // we want to handle both messages and channel posts, as well as inline queries
+    // (and changes of the bot's membership, to stop working for chats which can't be replied to,
+    // as well as the presses of the buttons under its messages)
+    // every update counts as activity, even if it isn't handled (see IDLE_SHUTDOWN_SECS)
     dptree::entry()
+        .inspect(record_activity)
         .branch(Update::filter_message().chain(call.clone()))
         .branch(Update::filter_channel_post().chain(call))
+        .branch(Update::filter_inline_query().chain(inline_call))
+        .branch(Update::filter_my_chat_member().endpoint(membership_handler))
+        .branch(Update::filter_callback_query().endpoint(callback_handler))
 }
 
-/// Changes COUNT by the specified delta.
-async fn change_count_by(delta: i32) -> HandlerResult {
-    let mut count = COUNT.get().expect("COUNT is not initialised").lock().await;
-    *count = u32::try_from((i64::from(*count) + i64::from(delta)).max(0)).unwrap_or(0);
-    drop(count);
-    Ok(())
+/// Returns the button the statistics are refreshed with.
+fn stats_keyboard(lang: i18n::Lang) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(
+        i18n::tr(lang, Msg::Refresh, &[]),
+        REFRESH_STATS,
+    )]])
 }
 
-#[allow(clippy::too_many_lines)] // sorry
+/// Handles the presses of the buttons under the bot's messages (so far, the one refreshing /stats).
+/// Each message is refreshed at most once per `STATS_REFRESH_INTERVAL`, as editing too often
+/// would get us rate limited.
+async fn callback_handler(query: CallbackQuery, bot: Bot) -> HandlerResult {
+    let message = query
+        .message
+        .as_ref()
+        .filter(|_| query.data.as_deref() == Some(REFRESH_STATS));
+
+    if let Some(message) = message {
+        let key = (message.chat.id, message.id);
+        let lang = i18n::lang(Some(&query.from));
+
+        let debounced = {
+            let mut last_refresh = LAST_REFRESH.lock().unwrap();
+            let refreshes = last_refresh.get_or_insert_with(HashMap::new);
+
+            refreshes.retain(|_, t| t.elapsed() < STATS_REFRESH_INTERVAL);
+            let debounced = refreshes.contains_key(&key);
+
+            if !debounced {
+                refreshes.insert(key, Instant::now());
+            }
+
+            drop(last_refresh);
+            debounced
+        };
+
+        if debounced {
+            debug!("stats refreshed too often, ignoring");
+        } else {
+            let result = bot
+                .edit_message_text(
+                    message.chat.id,
+                    message.id,
+                    commands::format_stats(lang, &stats::snapshot()),
+                )
+                .reply_markup(stats_keyboard(lang))
+                .await;
+
+            // nothing might have changed since the last refresh
+            match result {
+                Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+                Err(e) => error!("failed to refresh the stats: {}", e),
+            }
+        }
+    }
+
+    // the button keeps loading until the query is answered
+    bot.answer_callback_query(query.id)
+        .await
+        .log_on_error()
+        .await;
+
+    Ok(())
+}
+
+/// Handles changes of the bot's membership in a chat.
+/// Once a user blocks the bot (or it's removed from a group), the tasks of the chat are skipped,
+/// as their files couldn't be sent anyway.
+async fn membership_handler(update: ChatMemberUpdated) -> HandlerResult {
+    let chat_id = update.chat.id;
+
+    if update.new_chat_member.is_present() {
+        tasks::restore_chat(chat_id);
+        return Ok(());
+    }
+
+    let cancelled = tasks::abandon_chat(chat_id);
+
+    if cancelled > 0 {
+        info!(
+            "chat {} is no longer reachable, skipping its {} task(s)",
+            chat_id, cancelled
+        );
+    } else {
+        debug!("chat {} is no longer reachable", chat_id);
+    }
+
+    Ok(())
+}
+
+/// Changes COUNT by the specified delta.
+async fn change_count_by(delta: i32) -> HandlerResult {
+    let mut count = COUNT.get().expect("COUNT is not initialised").lock().await;
+    *count = u32::try_from((i64::from(*count) + i64::from(delta)).max(0)).unwrap_or(0);
+    drop(count);
+    Ok(())
+}
+
+#[allow(clippy::too_many_lines)] // sorry
 /// Handles incoming messages.
-async fn handler(message: Message, bot: Bot) -> HandlerResult {
+async fn handler(message: Message, bot: Bot, me: Me) -> HandlerResult {
     // if the message we received is a pin, ignore it
     if matches!(message.kind, teloxide::types::MessageKind::Pinned(_)) {
         debug!("message is a pin, ignoring");
         return Ok(());
     }
 
+    // the membership update might have been missed, but receiving a message means the chat is reachable
+    tasks::restore_chat(message.chat.id);
+
     let in_private_chat = matches!(message.chat.kind, ChatKind::Private(_));
-    let text = message.text().unwrap_or_default();
-    let url_info = utils::get_url_info(text);
+    let bot_name = me.user.username.clone().unwrap_or_default();
+    let lang = i18n::lang(message.from());
+
+    // commands change the way the link is processed
+    let (action, text) = match Command::parse(message_text(&message), &bot_name) {
+        Ok(Command::Cancel) => {
+            let msg = if tasks::cancel_latest(tasks::owner(&message)) {
+                Msg::Cancelling
+            } else {
+                Msg::NothingToCancel
+            };
+
+            bot.send_message(message.chat.id, i18n::tr(lang, msg, &[]))
+                .reply_to_message_id(message.id)
+                .await
+                .log_on_error()
+                .await;
+
+            return Ok(());
+        }
+        Ok(
+            command @ (Command::Whitelist
+            | Command::WhitelistAdd(_)
+            | Command::WhitelistRemove(_)
+            | Command::WhitelistReset),
+        ) => {
+            let msg = whitelist_command(&bot, &message, command).await;
+
+            bot.send_message(message.chat.id, msg)
+                .reply_to_message_id(message.id)
+                .formatted()
+                .await
+                .log_on_error()
+                .await;
+
+            return Ok(());
+        }
+        Ok(Command::Version) => {
+            bot.send_message(message.chat.id, commands::format_version(lang))
+                .reply_to_message_id(message.id)
+                .await
+                .log_on_error()
+                .await;
+
+            return Ok(());
+        }
+        Ok(Command::Stats) => {
+            bot.send_message(
+                message.chat.id,
+                commands::format_stats(lang, &stats::snapshot()),
+            )
+            .reply_to_message_id(message.id)
+            .reply_markup(stats_keyboard(lang))
+            .await
+            .log_on_error()
+            .await;
+
+            return Ok(());
+        }
+        Ok(Command::Help) => {
+            bot.send_message(
+                message.chat.id,
+                commands::help(lang, is_maintainer(&message)),
+            )
+            .reply_to_message_id(message.id)
+            .await
+            .log_on_error()
+            .await;
+
+            return Ok(());
+        }
+        Ok(Command::Ping) => {
+            // the latency is the time it takes to send the reply
+            let start = Instant::now();
+
+            let sent = bot
+                .send_message(message.chat.id, i18n::tr(lang, Msg::Pong, &[]))
+                .reply_to_message_id(message.id)
+                .await;
+
+            let latency = start.elapsed().as_millis();
+
+            match sent {
+                Ok(sent) => {
+                    bot.edit_message_text(
+                        message.chat.id,
+                        sent.id,
+                        i18n::tr(lang, Msg::PongLatency, &[&latency, &tasks::queue_length()]),
+                    )
+                    .await
+                    .log_on_error()
+                    .await;
+                }
+                Err(e) => error!("failed to answer the ping: {}", e),
+            }
+
+            return Ok(());
+        }
+        Ok(Command::Update) => {
+            // nobody else should be able to tell that the command exists
+            if !is_maintainer(&message) {
+                debug!("update requested by someone other than the maintainer, ignoring");
+                return Ok(());
+            }
+
+            bot.send_message(message.chat.id, update_ytdlp(lang).await)
+                .reply_to_message_id(message.id)
+                .await
+                .log_on_error()
+                .await;
+
+            return Ok(());
+        }
+        Ok(Command::Queue) => {
+            // the queue reveals who is downloading what, so it's only shown in private
+            if !is_maintainer(&message) || !in_private_chat {
+                debug!("queue requested by someone other than the maintainer, ignoring");
+                return Ok(());
+            }
+
+            bot.send_message(
+                message.chat.id,
+                format_queue(lang, &tasks::running(), &tasks::queued()),
+            )
+            .reply_to_message_id(message.id)
+            .await
+            .log_on_error()
+            .await;
+
+            return Ok(());
+        }
+        Ok(Command::Sysinfo) => {
+            // same as /update, the command is hidden from everyone else
+            if !is_maintainer(&message) {
+                debug!("sysinfo requested by someone other than the maintainer, ignoring");
+                return Ok(());
+            }
+
+            let info = utils::system_info().await;
+
+            bot.send_message(message.chat.id, commands::format_system_info(lang, &info))
+                .reply_to_message_id(message.id)
+                .formatted()
+                .await
+                .log_on_error()
+                .await;
+
+            return Ok(());
+        }
+        Ok(Command::FlushCache(args)) => {
+            // same as /update, the command is hidden from everyone else
+            if !is_maintainer(&message) {
+                debug!("cache flush requested by someone other than the maintainer, ignoring");
+                return Ok(());
+            }
+
+            let url = Some(args.trim()).filter(|url| !url.is_empty());
+            let removed = cache::flush(url);
+
+            info!("flushed {} cache entries", removed);
+
+            bot.send_message(
+                message.chat.id,
+                i18n::tr(lang, Msg::CacheFlushed, &[&removed]),
+            )
+            .reply_to_message_id(message.id)
+            .await
+            .log_on_error()
+            .await;
+
+            return Ok(());
+        }
+        Ok(command @ (Command::Allow(_) | Command::Disallow(_))) => {
+            // same as /update, the command is hidden from everyone else
+            if !is_maintainer(&message) {
+                debug!("global whitelist change requested by someone other than the maintainer, ignoring");
+                return Ok(());
+            }
+
+            bot.send_message(message.chat.id, global_whitelist_command(lang, &command))
+                .reply_to_message_id(message.id)
+                .formatted()
+                .await
+                .log_on_error()
+                .await;
+
+            return Ok(());
+        }
+        Ok(Command::Yeet(args)) => {
+            let (section, args) = commands::parse_section(&args);
+            let (max_height, args) = commands::parse_resolution(&args);
+
+            (
+                Action::Download {
+                    media: utils::Media::Video,
+                    max_height,
+                    section,
+                    spoiler: false,
+                    fallback: false,
+                },
+                link_text(&message, args),
+            )
+        }
+        Ok(Command::YeetSpoiler(args)) => {
+            let (section, args) = commands::parse_section(&args);
+            let (max_height, args) = commands::parse_resolution(&args);
+
+            (
+                Action::Download {
+                    media: utils::Media::Video,
+                    max_height,
+                    section,
+                    spoiler: true,
+                    fallback: false,
+                },
+                link_text(&message, args),
+            )
+        }
+        Ok(Command::Yoink(args)) => {
+            let (section, args) = commands::parse_section(&args);
+
+            (
+                Action::Download {
+                    media: utils::Media::Audio,
+                    max_height: None,
+                    section,
+                    spoiler: false,
+                    fallback: false,
+                },
+                link_text(&message, args),
+            )
+        }
+        Ok(Command::Retry) => {
+            let Some(failed) = tasks::take_failed(tasks::owner(&message)) else {
+                bot.send_message(message.chat.id, i18n::tr(lang, Msg::NothingToRetry, &[]))
+                    .reply_to_message_id(message.id)
+                    .await
+                    .log_on_error()
+                    .await;
+
+                return Ok(());
+            };
+
+            info!("retrying failed download: {}", failed.url);
+
+            (
+                Action::Download {
+                    media: failed.media,
+                    max_height: failed.max_height,
+                    section: failed.section,
+                    spoiler: failed.spoiler,
+                    // a download which failed on size fails the same way unless it can use the fallback
+                    fallback: true,
+                },
+                failed.url,
+            )
+        }
+        Ok(Command::Formats(args)) => (Action::ListFormats, link_text(&message, args)),
+        Ok(Command::Info(args)) => {
+            let (max_height, args) = commands::parse_resolution(&args);
+            (Action::Estimate { max_height }, link_text(&message, args))
+        }
+        Ok(Command::Thumb(args)) => (Action::Thumbnail, link_text(&message, args)),
+        Err(_) => (
+            Action::Download {
+                media: utils::Media::Video,
+                max_height: None,
+                section: None,
+                spoiler: false,
+                fallback: false,
+            },
+            message_text(&message).to_string(),
+        ),
+    };
+
+    // shortened links are checked against the whitelist by where they lead
+    let text = utils::resolve_shortened_urls(&text).await;
+    let whitelist = whitelists::effective(message.chat.id);
+    let url_info = utils::get_url_info(&text, &whitelist);
+
+    let allow_multi_url = *ALLOW_MULTI_URL
+        .get()
+        .expect("ALLOW_MULTI_URL is not initialised");
+
+    // several links can be downloaded at once, if ALLOW_MULTI_URL is set
+    let batch = (url_info.maybe_url.is_none()
+        && allow_multi_url
+        && (2..=MAX_BATCH_URLS).contains(&url_info.whitelisted_urls)
+        && matches!(action, Action::Download { .. }))
+    .then(|| url_info.urls.clone());
 
-    if url_info.maybe_url.is_none() {
+    // the reply is formatted (see PARSE_MODE), with the netlocs as inline code
+    if url_info.maybe_url.is_none() && batch.is_none() {
         let msg = if url_info.total_urls == 0 {
             debug!("no URLs found");
-            "No URLs found.".to_string()
+            i18n::tr_markup(lang, Msg::NoUrls, &[])
+        } else if url_info.whitelisted_urls == 0 && url_info.denied_urls > 0 {
+            debug!("only denied URLs found");
+            i18n::tr_markup(lang, Msg::NetlocDenied, &[])
         } else if url_info.whitelisted_urls == 0 {
             debug!("no whitelisted URLs found");
-            format!(
-                "No whitelisted URLs found.\n\nSupported netlocs: {}.",
-                *NETLOCS.get().expect("NETLOCS is not initialised")
+            i18n::tr_markup(
+                lang,
+                Msg::NoWhitelistedUrls,
+                &[&format_netlocs(lang, &whitelist)],
             )
+        } else if allow_multi_url && matches!(action, Action::Download { .. }) {
+            debug!("too many URLs found");
+            i18n::tr_markup(lang, Msg::TooManyUrls, &[&MAX_BATCH_URLS])
         } else {
             debug!("more than one URL found");
-            "Downloading more than one video at a time is unsupported.".to_string()
+            i18n::tr_markup(lang, Msg::MultipleUrls, &[])
         };
 
-        if in_private_chat {
-            bot.send_message(
-                message.chat.id,
-                format!(
-                    "{msg}\n\nFor more information, please contact {}.",
-                    *MAINTAINER.get().expect("MAINTAINER is not initialised")
-                )
-                .replace('.', r"\."),
-            )
+        if in_private_chat {
+            let maintainer = MAINTAINER.get().expect("MAINTAINER is not initialised");
+
+            bot.send_message(
+                message.chat.id,
+                i18n::tr_markup(
+                    lang,
+                    Msg::ContactMaintainer,
+                    &[&msg, &markup::escape(maintainer)],
+                ),
+            )
+            .reply_to_message_id(message.id)
+            .formatted()
+            .await
+            .log_on_error()
+            .await;
+        }
+
+        return Ok(());
+    }
+
+    // if the message is forwarded, ignore it
+    if message.forward_date().is_some() && !in_private_chat {
+        debug!("message is forwarded and not in private chat");
+        return Ok(());
+    }
+
+    let Some(urls) = batch else {
+        let url = url_info.maybe_url.unwrap();
+        // the download is boxed, so that the handler's future stays small
+        Box::pin(handle_url(&bot, &message, url, action, None)).await;
+
+        return Ok(());
+    };
+
+    info!("downloading a batch of {} URLs", urls.len());
+
+    // a single message is sent for the whole batch
+    let batch_msg_id = if in_private_chat {
+        bot.send_message(
+            message.chat.id,
+            i18n::tr(lang, Msg::BatchAccepted, &[&urls.len()]),
+        )
+        .reply_to_message_id(message.id)
+        .await
+        .map_err(|e| error!("failed to send batch message: {}", e))
+        .ok()
+        .map(|sent| sent.id)
+    } else {
+        None
+    };
+
+    // the URLs are queued in order (the next one is only handled once the previous one is queued),
+    // and their files are sent as soon as they're ready
+    let mut handles = Vec::new();
+
+    for url in urls {
+        let bot = bot.clone();
+        let message = message.clone();
+        let (queued, is_queued) = tokio::sync::oneshot::channel();
+
+        handles.push(tokio::spawn(async move {
+            handle_url(&bot, &message, url, action, Some(queued)).await
+        }));
+
+        // the sender is dropped without sending if the URL isn't queued, e.g. when it's rejected
+        is_queued.await.ok();
+    }
+
+    let mut any_sent = false;
+
+    for handle in handles {
+        match handle.await {
+            Ok(sent) => any_sent |= sent,
+            Err(e) => error!("failed to handle a URL of the batch: {}", e),
+        }
+    }
+
+    // in a group, the original message is replaced by the videos
+    if any_sent {
+        finish_request(&bot, &message).await;
+    }
+
+    if let Some(id) = batch_msg_id {
+        bot.delete_message(message.chat.id, id)
+            .await
+            .log_on_error()
+            .await;
+    }
+
+    info!("finished processing the batch");
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_lines)] // sorry
+/// Handles a single URL found in a message, returning whether a file has been sent.
+/// The URLs of a batch are handled alongside each other, so they don't get queue messages of their own;
+/// `queued` is dropped once the URL has been queued (or has been dealt with without queueing it).
+async fn handle_url(
+    bot: &Bot,
+    message: &Message,
+    url: String,
+    action: Action,
+    mut queued: Option<tokio::sync::oneshot::Sender<()>>,
+) -> bool {
+    let in_private_chat = matches!(message.chat.kind, ChatKind::Private(_));
+    let batched = queued.is_some();
+    let lang = i18n::lang(message.from());
+
+    let (media, max_height, section, spoiler, fallback) = match action {
+        Action::Download {
+            media,
+            max_height,
+            section,
+            spoiler,
+            fallback,
+        } => (media, max_height, section, spoiler, fallback),
+        Action::ListFormats => {
+            // listing the formats doesn't need to wait in the queue
+            let msg = utils::get_info(&url).await.map_or_else(
+                || i18n::tr_markup(lang, Msg::FormatsFailed, &[]),
+                |info| commands::format_list(lang, &info.formats),
+            );
+
+            bot.send_message(message.chat.id, msg)
+                .reply_to_message_id(message.id)
+                .formatted()
+                .await
+                .log_on_error()
+                .await;
+
+            return false;
+        }
+        Action::Estimate { max_height } => {
+            // estimating doesn't download anything, so it doesn't need to wait in the queue either
+            let msg = utils::estimate(&url, max_height).await.map_or_else(
+                || i18n::tr(lang, Msg::InfoFailed, &[]),
+                |estimate| commands::format_estimate(lang, &estimate),
+            );
+
+            bot.send_message(message.chat.id, msg)
+                .reply_to_message_id(message.id)
+                .await
+                .log_on_error()
+                .await;
+
+            return false;
+        }
+        Action::Thumbnail => return send_cover(bot, message, &url).await,
+    };
+
+    // links to a moment of a video are downloaded from that moment on
+    let section = section.or_else(|| utils::start_time(&url));
+
+    // a section which doesn't fit the video is ignored, rather than failing the download;
+    // the bitrate is then based on the duration of the downloaded part, as it's probed after downloading
+    // (sections aren't size-checked by `precheck`, so this is the only estimate of the video)
+    let section = match section {
+        Some(section)
+            if !section.fits(
+                limited_estimate(&url, max_height)
+                    .await
+                    .and_then(|estimate| estimate.duration),
+            ) =>
+        {
+            warn!("invalid section requested for {}: {:?}", url, section);
+
+            bot.send_message(message.chat.id, i18n::tr(lang, Msg::InvalidSection, &[]))
+                .reply_to_message_id(message.id)
+                .await
+                .log_on_error()
+                .await;
+
+            None
+        }
+        section => section,
+    };
+
+    // playlists are only downloaded if MAX_PLAYLIST_ITEMS allows for more than one item
+    let max_playlist_items = *MAX_PLAYLIST_ITEMS
+        .get()
+        .expect("MAX_PLAYLIST_ITEMS is not initialised");
+
+    let options = utils::DownloadOptions {
+        media,
+        playlist_end: (max_playlist_items > 1 && utils::is_playlist(&url))
+            .then_some(max_playlist_items),
+        max_height,
+        section,
+        fallback,
+    };
+
+    // the same file might already be downloading for someone else,
+    // in which case it's sent from the cache once that download finishes
+    let cache_key = cache::key(&url, options);
+
+    let in_flight = match cache_key.clone().map(cache::begin) {
+        Some(Ok(in_flight)) => Some(in_flight),
+        Some(Err(mut finished)) => {
+            info!("waiting for the download in progress: {}", url);
+
+            // the file is sent once the other download finishes, without queueing it
+            drop(queued.take());
+
+            // the channel is closed once the other download finishes
+            while finished.changed().await.is_ok() {}
+
+            if *finished.borrow() == Some(false) {
+                send_private_message(bot, message, i18n::tr(lang, Msg::DownloadFailed, &[])).await;
+                return false;
+            }
+
+            None
+        }
+        None => None,
+    };
+
+    // links which have already been sent are sent again straight from the cache
+    if let Some(entry) = cache_key.as_ref().and_then(cache::get) {
+        info!("sending a cached file for {}", url);
+        metrics::counter!("cache_hits_total").increment(1);
+
+        if send_cached(bot, message, media, &entry, spoiler).await {
+            if !batched {
+                finish_request(bot, message).await;
+            }
+
+            return true;
+        }
+
+        warn!("failed to send the cached file, downloading it again");
+    }
+
+    // obviously oversized videos are rejected right away, rather than after downloading them
+    let estimate = precheck(&url, options).await;
+
+    if let Some((size, limit)) = estimate
+        .as_ref()
+        .and_then(|estimate| exceeds_max_filesize(&url, fallback, estimate))
+    {
+        info!("rejecting video estimated at {} MB: {}", size, url);
+
+        send_private_message(
+            bot,
+            message,
+            i18n::tr(lang, Msg::EstimatedTooBig, &[&size, &limit]),
+        )
+        .await;
+
+        return false;
+    }
+
+    // don't let a single user flood the queue (the maintainer is exempt)
+    if let Some(user) = message.from() {
+        let max_tasks = *MAX_TASKS_PER_USER
+            .get()
+            .expect("MAX_TASKS_PER_USER is not initialised");
+
+        let user_tasks = tasks::count_for_user(user.id);
+
+        if user_tasks >= max_tasks && !is_maintainer(message) {
+            debug!("user {} has too many tasks in progress", user.id);
+
+            send_private_message(
+                bot,
+                message,
+                i18n::tr(lang, Msg::TooManyTasks, &[&user_tasks]),
+            )
+            .await;
+
+            return false;
+        }
+    }
+
+    // register the task, so that it can be cancelled (unless the queue is full)
+    let Some(task) = tasks::Task::register(message, &url) else {
+        warn!("the queue is full, rejecting {}", url);
+        metrics::counter!("downloads_rejected_total").increment(1);
+
+        send_private_message(bot, message, i18n::tr(lang, Msg::Overloaded, &[])).await;
+
+        return false;
+    };
+
+    drop(queued);
+
+    // we want to download up to WORKER_COUNT videos at a time
+    // first, acquire the counter mutex and get the current count
+    let count_lock = COUNT.get().expect("COUNT is not initialised").lock().await;
+    let count = *count_lock;
+    drop(count_lock);
+
+    let worker_count = *WORKER_COUNT.get().expect("WORKER_COUNT is not initialised");
+
+    // send a message if the bot is busy
+    // we also don't want to clutter non-private chats (nor send a message per URL of a batch)
+    let queue_msg = (in_private_chat && !batched).then(OnceLock::new);
+
+    // fast downloads don't need to be announced, so the message can be delayed if the queue is empty
+    // (text messages can't be edited into videos, so it's deleted once the video is sent instead)
+    let (mut queue_text, delay) = if count > worker_count {
+        (
+            i18n::tr(lang, Msg::QueuePosition, &[&(count - worker_count)]),
+            Duration::ZERO,
+        )
+    } else {
+        (
+            i18n::tr(lang, Msg::QueueEmpty, &[]),
+            *ACCEPTANCE_DELAY
+                .get()
+                .expect("ACCEPTANCE_DELAY is not initialised"),
+        )
+    };
+
+    // large videos set the expectations, so that the users don't think the bot got stuck
+    if estimate.as_ref().is_some_and(is_large) {
+        queue_text = format!("{queue_text}\n\n{}", i18n::tr(lang, Msg::LargeVideo, &[]));
+    }
+
+    if uses_fallback_limit(&url, fallback) {
+        queue_text = format!(
+            "{queue_text}\n\n{}",
+            i18n::tr(lang, Msg::ExtendedLimit, &[&max_filesize(&url, fallback)])
+        );
+    }
+
+    if let (Some(queue_msg), true) = (&queue_msg, delay.is_zero()) {
+        if let Some(id) = send_queue_message(bot, message, &queue_text).await {
+            queue_msg.set(id).ok();
+        }
+    }
+
+    let delayed_queue_msg = async {
+        if let (Some(queue_msg), false) = (&queue_msg, delay.is_zero()) {
+            tokio::time::sleep(delay).await;
+
+            if let Some(id) = send_queue_message(bot, message, &queue_text).await {
+                queue_msg.set(id).ok();
+            }
+        }
+
+        std::future::pending::<()>().await;
+    };
+
+    let timeout = *TASK_TIMEOUT.get().expect("TASK_TIMEOUT is not initialised");
+
+    // wait for a free worker, unless the task gets cancelled
+    // (dropping the download kills yt-dlp and ffmpeg, as they're spawned with kill_on_drop)
+    let outcome = tokio::select! {
+        worker = wait_for_worker(bot, message, &task, queue_msg.as_ref()) => {
+            let started = Instant::now();
+            let mut permit = Permit::Worker(worker);
+            let download = tokio::time::timeout(
+                timeout,
+                download_and_send(bot, message, &url, options, spoiler, queue_msg.as_ref(), &mut permit),
+            );
+
+            let outcome = tokio::select! {
+                result = download => match result {
+                    Ok(true) => Outcome::Sent,
+                    Ok(false) => Outcome::Failed,
+                    Err(_) => Outcome::TimedOut,
+                },
+                () = task.token().cancelled() => Outcome::Cancelled,
+            };
+
+            drop(permit);
+            stats::record_processing_time(started.elapsed());
+            outcome
+        }
+        () = task.token().cancelled() => Outcome::Cancelled,
+        // tasks which haven't started yet aren't started during the shutdown
+        () = tasks::shutdown_token().cancelled() => Outcome::Interrupted,
+        () = delayed_queue_msg => unreachable!("the queue message future never completes"),
+    };
+
+    // tasks cancelled at the end of the grace period weren't cancelled by the user
+    let outcome = match outcome {
+        Outcome::Cancelled if tasks::is_shutting_down() => Outcome::Interrupted,
+        Outcome::Cancelled if tasks::is_abandoned(message.chat.id) => Outcome::Abandoned,
+        outcome => outcome,
+    };
+
+    drop(task);
+
+    // requests for the same file are waiting for the result
+    // (unless the download was cancelled, then they download it themselves)
+    if let Some(in_flight) = in_flight {
+        match outcome {
+            Outcome::Sent => in_flight.finish(true),
+            Outcome::Failed | Outcome::TimedOut => in_flight.finish(false),
+            Outcome::Cancelled | Outcome::Interrupted | Outcome::Abandoned => drop(in_flight),
+        }
+    }
+
+    // failed downloads can be retried with /retry
+    if matches!(outcome, Outcome::Failed | Outcome::TimedOut) {
+        tasks::remember_failed(
+            tasks::owner(message),
+            tasks::Failed::new(&url, media, max_height, section, spoiler),
+        );
+    }
+
+    match outcome {
+        Outcome::Sent => {
+            metrics::counter!("downloads_succeeded_total").increment(1);
+            stats::record_download_completed();
+        }
+        Outcome::Failed => metrics::counter!("downloads_failed_total").increment(1),
+        Outcome::TimedOut => {
+            info!("download timed out: {}", url);
+            metrics::counter!("downloads_timed_out_total").increment(1);
+
+            send_private_message(
+                bot,
+                message,
+                i18n::tr(lang, Msg::TimedOut, &[&timeout.as_secs()]),
+            )
+            .await;
+        }
+        Outcome::Interrupted => {
+            info!("download interrupted by the shutdown: {}", url);
+
+            let msg = if tasks::is_persistent() {
+                Msg::Restarting
+            } else {
+                Msg::ShuttingDown
+            };
+
+            send_private_message(bot, message, i18n::tr(lang, msg, &[])).await;
+        }
+        Outcome::Abandoned => {
+            // there's nobody to tell about it
+            info!("download skipped, the chat is no longer reachable: {}", url);
+            metrics::counter!("downloads_abandoned_total").increment(1);
+        }
+        Outcome::Cancelled => {
+            info!("download cancelled: {}", url);
+            metrics::counter!("downloads_cancelled_total").increment(1);
+
+            bot.send_message(message.chat.id, i18n::tr(lang, Msg::Cancelled, &[]))
+                .reply_to_message_id(message.id)
+                .await
+                .log_on_error()
+                .await;
+        }
+    }
+
+    // in a group, the original message is replaced by the video
+    // (the messages of batches are replaced once all of their videos are sent)
+    if !batched && matches!(outcome, Outcome::Sent) {
+        finish_request(bot, message).await;
+    }
+
+    // remove leftover message
+    if let Some(&id) = queue_msg.as_ref().and_then(OnceLock::get) {
+        bot.delete_message(message.chat.id, id)
+            .await
+            .log_on_error()
+            .await;
+    }
+
+    info!("finished processing");
+
+    matches!(outcome, Outcome::Sent)
+}
+
+/// The permit held by a task in progress: downloading and converting takes up a worker,
+/// while uploading only takes up an upload slot, so that the worker can start on the next task.
+/// Updates of a chat are handled one at a time, so its files are still sent in order.
+#[allow(dead_code)] // the guards are only held, until they're dropped
+enum Permit {
+    Worker(SemaphoreGuard<'static>),
+    Upload(SemaphoreGuard<'static>),
+    None,
+}
+
+impl Permit {
+    /// Makes sure that the task holds a worker, releasing its upload slot.
+    async fn work(&mut self) {
+        if !matches!(self, Self::Worker(_)) {
+            *self = Self::None;
+            *self = Self::Worker(
+                WORKERS
+                    .get()
+                    .expect("WORKERS is not initialised")
+                    .acquire()
+                    .await,
+            );
+        }
+    }
+
+    /// Makes sure that the task holds an upload slot, releasing its worker.
+    async fn upload(&mut self) {
+        if !matches!(self, Self::Upload(_)) {
+            *self = Self::None;
+            *self = Self::Upload(
+                UPLOADS
+                    .get()
+                    .expect("UPLOADS is not initialised")
+                    .acquire()
+                    .await,
+            );
+        }
+    }
+}
+
+/// Sends the message about the position of a task in the queue, returning its ID,
+/// so that it can be updated as the task moves up the queue and deleted once it finishes.
+async fn send_queue_message(bot: &Bot, message: &Message, text: &str) -> Option<MessageId> {
+    match bot
+        .send_message(message.chat.id, text)
+        .reply_to_message_id(message.id)
+        .await
+    {
+        Ok(x) => Some(x.id),
+        Err(e) => {
+            error!("failed to send queue message: {}", e);
+            None
+        }
+    }
+}
+
+/// Waits for a free worker, updating the queue message as the task moves up the queue.
+async fn wait_for_worker(
+    bot: &Bot,
+    message: &Message,
+    task: &tasks::Task,
+    queue_msg: Option<&OnceLock<MessageId>>,
+) -> SemaphoreGuard<'static> {
+    let acquire = WORKERS.get().expect("WORKERS is not initialised").acquire();
+    tokio::pin!(acquire);
+
+    let mut changes = tasks::queue_changes();
+    let mut last_position = task.position();
+    let mut last_edit: Option<Instant> = None;
+    let mut listening = queue_msg.is_some();
+    let mut outdated = false;
+
+    loop {
+        // editing too often would get us rate limited, so the changes are batched
+        let next_edit = last_edit.map_or_else(tokio::time::Instant::now, |t| {
+            tokio::time::Instant::from_std(t + PROGRESS_INTERVAL)
+        });
+
+        tokio::select! {
+            guard = &mut acquire => {
+                task.start();
+                return guard;
+            }
+            result = changes.changed(), if listening => {
+                // the sender lives as long as the bot, but stop listening just in case
+                listening = result.is_ok();
+                outdated = listening;
+            }
+            () = tokio::time::sleep_until(next_edit), if outdated => {
+                outdated = false;
+
+                let (Some(&id), Some(position)) =
+                    (queue_msg.and_then(OnceLock::get), task.position())
+                else {
+                    continue;
+                };
+
+                if last_position == Some(position) {
+                    continue;
+                }
+
+                last_position = Some(position);
+                last_edit = Some(Instant::now());
+
+                bot.edit_message_text(
+                    message.chat.id,
+                    id,
+                    i18n::tr(i18n::lang(message.from()), Msg::QueuePosition, &[&position]),
+                )
+                .await
+                .log_on_error()
+                .await;
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_lines)] // sorry
+/// Downloads the media from an URL and sends it to the chat.
+async fn download_and_send(
+    bot: &Bot,
+    message: &Message,
+    url: &str,
+    options: utils::DownloadOptions,
+    spoiler: bool,
+    queue_msg: Option<&OnceLock<MessageId>>,
+    permit: &mut Permit,
+) -> bool {
+    let lang = i18n::lang(message.from());
+    let utils::DownloadOptions {
+        media,
+        playlist_end,
+        section,
+        fallback,
+        ..
+    } = options;
+
+    info!("downloading video from {}", url);
+    metrics::counter!("downloads_started_total").increment(1);
+
+    let preflight = if playlist_end.is_none() {
+        utils::preflight(url).await
+    } else {
+        None
+    };
+
+    // ongoing livestreams would be downloaded indefinitely (recordings of finished ones are fine)
+    if preflight.as_ref().is_some_and(utils::Preflight::is_ongoing) {
+        info!("rejecting livestream: {}", url);
+
+        send_private_message(bot, message, i18n::tr(lang, Msg::Livestream, &[])).await;
+
+        return false;
+    }
+
+    // videos which are too long would only fail the size check after a whole download
+    let duration = preflight
+        .and_then(|preflight| preflight.duration)
+        .map(|duration| section.map_or(duration, |section| section.length(duration)));
+
+    if let Some((duration, limit)) = duration.and_then(exceeds_max_duration) {
+        info!("rejecting video longer than {} seconds: {}", limit, url);
+
+        send_private_message(
+            bot,
+            message,
+            i18n::tr(lang, Msg::TooLong, &[&minutes(duration), &minutes(limit)]),
+        )
+        .await;
+
+        return false;
+    }
+
+    let temp_dir = utils::temp_dir().unwrap();
+    let dir_path = temp_dir.path().to_str().unwrap();
+
+    // download the video, reporting the progress by editing the queue message
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    // the permit of the site is released once the download finishes, fails or is cancelled
+    let download = async {
+        let _domain = tasks::acquire_domain(url).await;
+
+        utils::download(url, dir_path, options, move |percentage| {
+            progress_tx.send(percentage).ok();
+        })
+        .await
+    };
+
+    let report_progress = async {
+        let mut last_edit: Option<Instant> = None;
+
+        // the channel is closed once the download finishes
+        while let Some(percentage) = progress_rx.recv().await {
+            let Some(&id) = queue_msg.and_then(OnceLock::get) else {
+                continue;
+            };
+
+            // editing too often would get us rate limited
+            if last_edit.is_some_and(|t| t.elapsed() < PROGRESS_INTERVAL) {
+                continue;
+            }
+
+            last_edit = Some(Instant::now());
+
+            bot.edit_message_text(
+                message.chat.id,
+                id,
+                i18n::tr(lang, Msg::DownloadProgress, &[&format!("{percentage:.1}")]),
+            )
+            .await
+            .log_on_error()
+            .await;
+        }
+    };
+
+    // fetch the video info for the caption alongside (playlists have no single title)
+    let caption_info = *CAPTION_INFO.get().expect("CAPTION_INFO is not initialised");
+
+    let get_info = async {
+        if caption_info && playlist_end.is_none() {
+            utils::get_info(url).await
+        } else {
+            None
+        }
+    };
+
+    let download_start = Instant::now();
+    let (result, (), info) = tokio::join!(download, report_progress, get_info);
+
+    metrics::histogram!("download_duration_seconds").record(download_start.elapsed());
+
+    if let Err(failure) = &result {
+        report_failure(bot, message, url, failure).await;
+    }
+
+    let error = result.as_ref().err().map(|failure| &failure.error);
+
+    // some failures are the video's fault, which is worth telling the user
+    // (e.g. videos which require signing in can't be downloaded without cookies)
+    let reason = match error {
+        Some(utils::DownloadError::AuthRequired) => Some(Msg::AuthRequired),
+        Some(utils::DownloadError::Private) => Some(Msg::PrivateVideo),
+        Some(utils::DownloadError::Unavailable) => Some(Msg::VideoUnavailable),
+        Some(utils::DownloadError::FormatUnavailable) => Some(Msg::FormatUnavailable),
+        _ => None,
+    };
+
+    if let Some(reason) = reason {
+        send_private_message(bot, message, i18n::tr(lang, reason, &[])).await;
+
+        return false;
+    }
+
+    // the proxy is the maintainer's problem, rather than the video's
+    if matches!(error, Some(utils::DownloadError::Proxy)) {
+        send_private_message(
+            bot,
+            message,
+            i18n::tr(
+                lang,
+                Msg::ProxyFailed,
+                &[MAINTAINER.get().expect("MAINTAINER is not initialised")],
+            ),
+        )
+        .await;
+
+        return false;
+    }
+
+    // find all files in the directory
+    // (sorted, so that playlist items are sent in order; unfinished files of resumed downloads
+    // and the other files yt-dlp writes alongside the media are skipped)
+    let mut files = std::fs::read_dir(dir_path)
+        .unwrap()
+        .filter_map(std::result::Result::ok)
+        .filter(|entry| {
+            !entry.path().extension().is_some_and(|ext| {
+                utils::PARTIAL_EXTENSIONS
+                    .iter()
+                    .chain(&utils::SIDECAR_EXTENSIONS)
+                    .any(|s| ext == *s)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    files.sort_by_key(std::fs::DirEntry::file_name);
+
+    // subtitles are written alongside the videos
+    let (subtitles, files): (Vec<_>, Vec<_>) = files.into_iter().partition(|entry| {
+        entry
+            .path()
+            .extension()
+            .is_some_and(|ext| utils::SUBTITLE_EXTENSIONS.iter().any(|s| ext == *s))
+    });
+
+    // so are the thumbnails, which are looked up by the name of the video later;
+    // images without a video are what has actually been downloaded (e.g. the photos of a carousel)
+    let is_image = |entry: &std::fs::DirEntry| {
+        entry
+            .path()
+            .extension()
+            .is_some_and(|ext| utils::THUMBNAIL_EXTENSIONS.iter().any(|s| ext == *s))
+    };
+
+    let stems = files
+        .iter()
+        .filter(|entry| !is_image(entry))
+        .filter_map(|entry| entry.path().file_stem().map(std::ffi::OsStr::to_os_string))
+        .collect::<Vec<_>>();
+
+    let (images, mut files): (Vec<_>, Vec<_>) = files.into_iter().partition(is_image);
+
+    // a single video is expected, so any other files ffprobe can't read aren't media
+    if playlist_end.is_none() && files.len() > 1 {
+        let mut media = Vec::with_capacity(files.len());
+
+        for entry in files {
+            let is_media = match entry.path().to_str() {
+                Some(path) => utils::probe(path).await.is_some(),
+                None => false,
+            };
+
+            if is_media {
+                media.push(entry);
+            } else {
+                debug!("skipping {:?}, which isn't a media file", entry.file_name());
+            }
+        }
+
+        files = media;
+    }
+
+    let images = images
+        .iter()
+        .map(std::fs::DirEntry::path)
+        .filter(|path| {
+            path.file_stem()
+                .is_some_and(|stem| !stems.iter().any(|s| s == stem))
+        })
+        .map(|path| path.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+
+    let subtitles = subtitles
+        .iter()
+        .map(|entry| entry.path().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+
+    // photos don't need to be converted, so they are sent right away (audio-only requests skip them)
+    let mut any_sent = false;
+
+    if !images.is_empty() && media == utils::Media::Video {
+        permit.upload().await;
+        any_sent = send_photos(
+            bot,
+            message,
+            &images,
+            caption(message, info.as_ref()),
+            spoiler,
+        )
+        .await;
+
+        if files.is_empty() {
+            return any_sent;
+        }
+
+        permit.work().await;
+    }
+
+    // check if yt-dlp downloaded the video by checking if dir contains a file
+    // (or at least one file, if we're downloading a playlist)
+    if files.is_empty() || (playlist_end.is_none() && files.len() != 1) {
+        let msg = if files.is_empty() {
+            i18n::tr(lang, Msg::NoFilesFound, &[])
+        } else {
+            i18n::tr(lang, Msg::FilesFound, &[&files.len()])
+        };
+
+        send_private_message(bot, message, msg).await;
+
+        return false;
+    }
+
+    // some playlist items may fail to download, we'll send the rest
+    if result.is_err() && playlist_end.is_none() {
+        send_private_message(bot, message, i18n::tr(lang, Msg::ExtractorFailed, &[])).await;
+
+        return false;
+    }
+
+    // if files exceed MAX_FILESIZE megabytes combined, skip the ones that don't fit
+    let max_filesize = max_filesize(url, fallback);
+
+    let mut total_megabytes = 0;
+    let mut accepted_files = Vec::new();
+    let mut skipped_files = Vec::new();
+
+    for entry in files {
+        let bytes = entry.metadata().unwrap().len();
+        let megabytes = bytes / 1000 / 1000;
+
+        let path = entry.path();
+
+        if total_megabytes + megabytes > max_filesize {
+            skipped_files.push(path);
+        } else {
+            total_megabytes += megabytes;
+            accepted_files.push(path);
+        }
+    }
+
+    if accepted_files.is_empty() {
+        send_private_message(
+            bot,
+            message,
+            i18n::tr(lang, Msg::FileTooBig, &[&max_filesize]),
+        )
+        .await;
+
+        return false;
+    }
+
+    // convert and send the files one by one
+    for file_path in accepted_files {
+        let file_path = file_path.to_str().unwrap().to_string();
+        info!("file downloaded to {}", file_path);
+
+        // subtitles are named after the video, e.g. id.mp4 -> id.en.srt
+        let stem = std::path::Path::new(&file_path)
+            .with_extension("")
+            .to_string_lossy()
+            .to_string();
+
+        let video_subtitles = subtitles
+            .iter()
+            .filter(|s| s.starts_with(&format!("{stem}.")))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let (sent, file_id) = match media {
+            utils::Media::Video => {
+                process_video(
+                    bot,
+                    message,
+                    url,
+                    &file_path,
+                    temp_dir.path(),
+                    info.as_ref(),
+                    &video_subtitles,
+                    spoiler,
+                    fallback,
+                    permit,
+                )
+                .await
+            }
+            utils::Media::Audio => {
+                process_audio(bot, message, &file_path, info.as_ref(), permit).await
+            }
+        };
+
+        any_sent |= sent;
+
+        // remember the file, so that it doesn't have to be processed again
+        if let (Some(key), Some(file_id)) = (cache::key(url, options), file_id) {
+            cache::insert(key, file_id, info.clone());
+        }
+    }
+
+    // let the user know which playlist items didn't make it
+    if !skipped_files.is_empty() {
+        let names = skipped_files
+            .iter()
+            .filter_map(|p| p.file_stem())
+            .map(|s| s.to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        warn!("skipped playlist items: {}", names);
+
+        send_private_message(
+            bot,
+            message,
+            i18n::tr(
+                lang,
+                Msg::SkippedItems,
+                &[&skipped_files.len(), &max_filesize, &names],
+            ),
+        )
+        .await;
+    }
+
+    any_sent
+}
+
+/// Reports a failed download to `REPORT_ERRORS_TO_MAINTAINER`, if it's set.
+/// At most one report is sent per `REPORT_INTERVAL`; the next one mentions how many were skipped.
+async fn report_failure(bot: &Bot, message: &Message, url: &str, failure: &utils::DownloadFailure) {
+    let Some(chat_id) = *REPORT_CHAT_ID
+        .get()
+        .expect("REPORT_CHAT_ID is not initialised")
+    else {
+        return;
+    };
+
+    // the number of failures skipped since the last report, unless this one is skipped as well
+    let skipped = {
+        let mut last_report = LAST_REPORT.lock().unwrap();
+
+        let skipped = match *last_report {
+            Some((sent, skipped)) if sent.elapsed() < REPORT_INTERVAL => {
+                *last_report = Some((sent, skipped + 1));
+                None
+            }
+            last => {
+                *last_report = Some((Instant::now(), 0));
+                Some(last.map_or(0, |(_, skipped)| skipped))
+            }
+        };
+
+        drop(last_report);
+        skipped
+    };
+
+    let Some(skipped) = skipped else {
+        debug!(
+            "not reporting the failure of {}, reported one recently",
+            url
+        );
+        return;
+    };
+
+    let user = message.from().map_or_else(
+        || format!("chat {}", message.chat.id),
+        |user| {
+            user.username.as_ref().map_or_else(
+                || format!("user {}", user.id),
+                |username| format!("@{username}"),
+            )
+        },
+    );
+
+    // the actual error is usually at the end
+    let stderr = failure.stderr.trim();
+    let stderr = stderr
+        .char_indices()
+        .rev()
+        .nth(MAX_REPORT_STDERR - 1)
+        .map_or(stderr, |(i, _)| &stderr[i..]);
+
+    let skipped = if skipped > 0 {
+        format!("\n\n({skipped} other failure(s) since the previous report weren't reported)")
+    } else {
+        String::new()
+    };
+
+    let report = format!(
+        "Download failed ({:?})\nURL: {url}\nUser: {user}\n\n{stderr}{skipped}",
+        failure.error
+    );
+
+    bot.send_message(chat_id, report).await.log_on_error().await;
+}
+
+/// Returns the text a command should look for a link in.
+/// If no link was given, the replied-to message is used instead.
+fn link_text(message: &Message, args: String) -> String {
+    if args.trim().is_empty() {
+        message
+            .reply_to_message()
+            .map(message_text)
+            .unwrap_or_default()
+            .to_string()
+    } else {
+        args
+    }
+}
+
+/// Returns the text of a message, or its caption if it's a media message.
+/// Links are often shared as photos or videos with the link in the caption.
+fn message_text(message: &Message) -> &str {
+    message
+        .text()
+        .or_else(|| message.caption())
+        .unwrap_or_default()
+}
+
+/// Checks a duration (in seconds) against `MAX_DURATION_SECS`,
+/// returning it (rounded up) along with the limit if it's exceeded.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn exceeds_max_duration(duration: f64) -> Option<(u64, u64)> {
+    let limit = *MAX_DURATION.get().expect("MAX_DURATION is not initialised");
+    let duration = duration.ceil() as u64;
+
+    (limit > 0 && duration > limit).then_some((duration, limit))
+}
+
+/// Parses the file size limit (in megabytes) set in the environment variable `name`, such as `MAX_FILESIZE`.
+/// Unset and invalid values fall back to `default`, and so does zero, which would reject every video.
+fn parse_filesize(name: &str, value: Option<&str>, default: u64) -> u64 {
+    let Some(value) = value else {
+        return default;
+    };
+
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|&x| x > 0)
+        .unwrap_or_else(|| {
+            warn!("failed to parse {} ({}), using default value", name, value);
+            default
+        })
+}
+
+/// Estimates the size (and the duration) of a video if `SIZE_PRECHECK` is set.
+/// Audio, playlists and sections aren't estimated, as the estimate would be far off;
+/// neither are videos while too many estimates are running.
+async fn precheck(url: &str, options: utils::DownloadOptions) -> Option<utils::Estimate> {
+    if options.media != utils::Media::Video
+        || options.playlist_end.is_some()
+        || options.section.is_some()
+    {
+        return None;
+    }
+
+    limited_estimate(url, options.max_height).await
+}
+
+/// Estimates a video if `SIZE_PRECHECK` is set, unless too many estimates are running
+/// or the estimate takes longer than `PRECHECK_TIMEOUT`.
+async fn limited_estimate(url: &str, max_height: Option<u32>) -> Option<utils::Estimate> {
+    let prechecks = PRECHECKS.get().expect("PRECHECKS is not initialised");
+
+    // the size is checked after downloading anyway, so the estimate can be skipped when busy
+    let Some(_precheck) = prechecks.as_ref()?.try_acquire() else {
+        debug!(
+            "too many size estimates running, skipping the estimate of {}",
+            url
+        );
+        return None;
+    };
+
+    tokio::time::timeout(PRECHECK_TIMEOUT, utils::estimate(url, max_height))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Checks whether a download gets the larger `FALLBACK_MAX_FILESIZE`, i.e. whether it's a `/retry`
+/// (see `DownloadOptions::fallback`) or its URL points to one of `FALLBACK_HOSTS`.
+/// The hosts match their subdomains as well, like the whitelist entries.
+fn uses_fallback_limit(url: &str, fallback: bool) -> bool {
+    let max_filesize = *MAX_FILESIZE.get().expect("MAX_FILESIZE is not initialised");
+    let fallback_max_filesize = *FALLBACK_MAX_FILESIZE
+        .get()
+        .expect("FALLBACK_MAX_FILESIZE is not initialised");
+
+    fallback_max_filesize > max_filesize
+        && (fallback
+            || url::Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .is_some_and(|host| {
+                    FALLBACK_HOSTS
+                        .get()
+                        .expect("FALLBACK_HOSTS is not initialised")
+                        .iter()
+                        .any(|entry| utils::host_matches(&host, entry))
+                }))
+}
+
+/// Returns the maximum size (in megabytes) of the files downloaded from an URL (see `uses_fallback_limit`).
+fn max_filesize(url: &str, fallback: bool) -> u64 {
+    if uses_fallback_limit(url, fallback) {
+        *FALLBACK_MAX_FILESIZE
+            .get()
+            .expect("FALLBACK_MAX_FILESIZE is not initialised")
+    } else {
+        *MAX_FILESIZE.get().expect("MAX_FILESIZE is not initialised")
+    }
+}
+
+/// Returns the estimated size of a video (in megabytes) along with its size limit if it's exceeded.
+fn exceeds_max_filesize(
+    url: &str,
+    fallback: bool,
+    estimate: &utils::Estimate,
+) -> Option<(u64, u64)> {
+    let max_filesize = max_filesize(url, fallback);
+    let size = estimate.filesize? / 1000 / 1000;
+
+    (size > max_filesize).then_some((size, max_filesize))
+}
+
+/// Checks whether a video is estimated to be larger (or longer) than `LARGE_VIDEO_MB` (or `LARGE_VIDEO_SECS`),
+/// so that the user can be told that it may take a while.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn is_large(estimate: &utils::Estimate) -> bool {
+    let large_size = *LARGE_VIDEO_MB
+        .get()
+        .expect("LARGE_VIDEO_MB is not initialised");
+    let large_duration = *LARGE_VIDEO_SECS
+        .get()
+        .expect("LARGE_VIDEO_SECS is not initialised");
+
+    let size = estimate.filesize.map(|filesize| filesize / 1000 / 1000);
+    let duration = estimate.duration.map(|duration| duration.ceil() as u64);
+
+    (large_size > 0 && size.is_some_and(|size| size > large_size))
+        || (large_duration > 0 && duration.is_some_and(|duration| duration > large_duration))
+}
+
+/// Formats a duration (in seconds) as whole minutes, rounded up.
+const fn minutes(seconds: u64) -> u64 {
+    seconds.div_ceil(60)
+}
+
+/// Checks whether the message was sent by the maintainer.
+fn is_maintainer(message: &Message) -> bool {
+    message.from().is_some_and(is_maintainer_user)
+}
+
+/// Checks whether the user is the maintainer.
+fn is_maintainer_user(user: &User) -> bool {
+    let maintainer = MAINTAINER.get().expect("MAINTAINER is not initialised");
+
+    // MAINTAINER is prefixed with an @ only if it was actually set
+    maintainer.strip_prefix('@').is_some_and(|maintainer| {
+        user.username
+            .as_deref()
+            .is_some_and(|username| username.eq_ignore_ascii_case(maintainer))
+    })
+}
+
+/// Updates yt-dlp, returning a reply describing the result.
+/// Downloads keep running in the meantime, but only one update can run at a time.
+async fn update_ytdlp(lang: i18n::Lang) -> String {
+    let _guard = UPDATING.lock().await;
+
+    let unknown = || i18n::tr(lang, Msg::Unknown, &[]);
+    let old_version = utils::version_string("yt-dlp").unwrap_or_else(unknown);
+
+    info!("updating yt-dlp {}", old_version);
+
+    if let Err(e) = utils::update_ytdlp().await {
+        error!("failed to update yt-dlp: {}", e);
+        return i18n::tr(lang, Msg::UpdateFailed, &[&old_version, &e]);
+    }
+
+    let new_version = utils::version_string("yt-dlp").unwrap_or_else(unknown);
+
+    info!("yt-dlp {} updated to {}", old_version, new_version);
+
+    if new_version == old_version {
+        i18n::tr(lang, Msg::AlreadyUpToDate, &[&new_version])
+    } else {
+        i18n::tr(lang, Msg::Updated, &[&old_version, &new_version])
+    }
+}
+
+/// Maximum number of tasks listed by `/queue`.
+const MAX_QUEUE_LISTED: usize = 20;
+
+/// Maximum length of the URLs listed by `/queue`.
+const MAX_QUEUE_URL_LEN: usize = 60;
+
+/// Lists the running tasks and the tasks waiting for a worker, truncated to `MAX_QUEUE_LISTED` entries each.
+fn format_queue(lang: i18n::Lang, running: &[tasks::Queued], queued: &[tasks::Queued]) -> String {
+    if running.is_empty() && queued.is_empty() {
+        return i18n::tr(lang, Msg::QueueListEmpty, &[]);
+    }
+
+    let list = |tasks: &[tasks::Queued], entry: Msg| {
+        let mut lines = tasks
+            .iter()
+            .take(MAX_QUEUE_LISTED)
+            .enumerate()
+            .map(|(i, task)| {
+                let mut url = utils::sanitize_url(&task.url);
+
+                if url.chars().count() > MAX_QUEUE_URL_LEN {
+                    url = url.chars().take(MAX_QUEUE_URL_LEN - 1).collect::<String>() + "…";
+                }
+
+                i18n::tr(
+                    lang,
+                    entry,
+                    &[
+                        &(i + 1),
+                        &url,
+                        &task.chat_id,
+                        &task.since.elapsed().as_secs(),
+                    ],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        if tasks.len() > MAX_QUEUE_LISTED {
+            lines.push(i18n::tr(
+                lang,
+                Msg::More,
+                &[&(tasks.len() - MAX_QUEUE_LISTED)],
+            ));
+        }
+
+        lines.join("\n")
+    };
+
+    let mut sections = Vec::new();
+
+    if !running.is_empty() {
+        sections.push(format!(
+            "{}\n{}",
+            i18n::tr(lang, Msg::DownloadsInProgress, &[&running.len()]),
+            list(running, Msg::RunningEntry)
+        ));
+    }
+
+    if !queued.is_empty() {
+        sections.push(format!(
+            "{}\n{}",
+            i18n::tr(lang, Msg::QueuedDownloads, &[&queued.len()]),
+            list(queued, Msg::WaitingEntry)
+        ));
+    }
+
+    sections.join("\n\n")
+}
+
+/// Lists the netlocs of a whitelist as inline code, for formatted messages.
+fn format_netlocs(lang: i18n::Lang, whitelist: &[String]) -> String {
+    if whitelist.is_empty() {
+        return i18n::tr_markup(lang, Msg::NoNetlocs, &[]);
+    }
+
+    whitelist
+        .iter()
+        .map(|x| markup::code_inline(x))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Checks whether the sender of a message can change the settings of the chat.
+async fn is_chat_admin(bot: &Bot, message: &Message) -> bool {
+    if is_maintainer(message) {
+        return true;
+    }
+
+    // channel posts can only be made by the admins
+    let Some(user) = message.from() else {
+        return message.chat.is_channel();
+    };
+
+    bot.get_chat_administrators(message.chat.id)
+        .await
+        .is_ok_and(|admins| admins.iter().any(|member| member.user.id == user.id))
+}
+
+/// Shows or changes the whitelist of a chat, returning a formatted reply.
+async fn whitelist_command(bot: &Bot, message: &Message, command: Command) -> String {
+    let chat_id = message.chat.id;
+    let lang = i18n::lang(message.from());
+
+    if !matches!(command, Command::Whitelist) && !is_chat_admin(bot, message).await {
+        return i18n::tr_markup(lang, Msg::AdminsOnly, &[]);
+    }
+
+    let whitelist = match command {
+        Command::WhitelistAdd(args) => {
+            let entries = parse_entries(&args);
+            let (allowed, rejected): (Vec<_>, Vec<_>) =
+                entries.into_iter().partition(|e| whitelists::is_allowed(e));
+
+            if !rejected.is_empty() {
+                return format!(
+                    "{} {}{}",
+                    i18n::tr_markup(lang, Msg::NotGloballyAllowed, &[]),
+                    format_netlocs(lang, &rejected),
+                    markup::escape(".")
+                );
+            }
+
+            let mut whitelist = whitelists::effective(chat_id);
+
+            for entry in allowed {
+                if !whitelist.contains(&entry) {
+                    whitelist.push(entry);
+                }
+            }
+
+            whitelists::set(chat_id, Some(whitelist.clone()));
+            whitelist
+        }
+        Command::WhitelistRemove(args) => {
+            let entries = parse_entries(&args);
+            let mut whitelist = whitelists::effective(chat_id);
+
+            whitelist.retain(|w| !entries.contains(w));
+            whitelists::set(chat_id, Some(whitelist.clone()));
+            whitelist
+        }
+        Command::WhitelistReset => {
+            whitelists::set(chat_id, None);
+            whitelists::effective(chat_id)
+        }
+        _ => whitelists::effective(chat_id),
+    };
+
+    let allowed_by = if whitelists::get(chat_id).is_some() {
+        Msg::AllowedByChat
+    } else {
+        Msg::AllowedByGlobal
+    };
+
+    format!(
+        "{} {}{}",
+        i18n::tr_markup(lang, allowed_by, &[]),
+        format_netlocs(lang, &whitelist),
+        markup::escape(".")
+    )
+}
+
+/// Changes the global whitelist, returning a formatted reply.
+fn global_whitelist_command(lang: i18n::Lang, command: &Command) -> String {
+    let (Command::Allow(args) | Command::Disallow(args)) = command else {
+        return i18n::tr_markup(lang, Msg::UnknownCommand, &[]);
+    };
+
+    let entries = parse_entries(args);
+
+    if entries.is_empty() {
+        return i18n::tr_markup(lang, Msg::NoNetlocsGiven, &[]);
+    }
+
+    let (valid, invalid): (Vec<_>, Vec<_>) =
+        entries.into_iter().partition(|e| whitelists::is_valid(e));
+
+    if !invalid.is_empty() {
+        return format!(
+            "{} {}{}",
+            i18n::tr_markup(lang, Msg::InvalidNetlocs, &[]),
+            format_netlocs(lang, &invalid),
+            markup::escape(".")
+        );
+    }
+
+    let whitelist = if matches!(command, Command::Allow(_)) {
+        info!("allowing netlocs globally: {:?}", valid);
+        whitelists::change_global(&valid, &[])
+    } else {
+        info!("disallowing netlocs globally: {:?}", valid);
+        whitelists::change_global(&[], &valid)
+    };
+
+    format!(
+        "{} {}{}",
+        i18n::tr_markup(lang, Msg::AllowedByGlobal, &[]),
+        format_netlocs(lang, &whitelist),
+        markup::escape(".")
+    )
+}
+
+/// Splits the arguments of a whitelist command into lowercase netlocs.
+fn parse_entries(args: &str) -> Vec<String> {
+    args.split(|c: char| c == ',' || c.is_whitespace())
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Sends a reply to the message, but only if it was sent in a private chat.
+async fn send_private_message(bot: &Bot, message: &Message, text: impl Into<String>) {
+    if matches!(message.chat.kind, ChatKind::Private(_)) {
+        bot.send_message(message.chat.id, text)
+            .reply_to_message_id(message.id)
+            .allow_sending_without_reply(true)
+            .await
+            .log_on_error()
+            .await;
+    }
+}
+
+/// Composes a caption for the media sent in response to the message.
+/// In groups, it credits the original poster; the video info is appended if available.
+fn caption(message: &Message, info: Option<&utils::VideoInfo>) -> String {
+    fit_caption(&caption_text(message, info))
+}
+
+/// Appends `CAPTION_FOOTER` (if set) to a caption, shortening the caption so that Telegram accepts it.
+/// The footer is always kept whole, as it's usually an attribution or a disclaimer.
+fn fit_caption(text: &str) -> String {
+    let Some(footer) = CAPTION_FOOTER
+        .get()
+        .expect("CAPTION_FOOTER is not initialised")
+    else {
+        return text.chars().take(CAPTION_LIMIT).collect();
+    };
+
+    if text.is_empty() {
+        return footer.chars().take(CAPTION_LIMIT).collect();
+    }
+
+    let room = CAPTION_LIMIT.saturating_sub(footer.chars().count() + 2);
+    let text = text.chars().take(room).collect::<String>();
+
+    format!("{text}\n\n{footer}")
+        .chars()
+        .take(CAPTION_LIMIT)
+        .collect()
+}
+
+/// Composes the text of a caption (see `caption`), without the footer and regardless of its length.
+fn caption_text(message: &Message, info: Option<&utils::VideoInfo>) -> String {
+    let in_private_chat = matches!(message.chat.kind, ChatKind::Private(_));
+    let text = message_text(message);
+    let mut username = None;
+
+    if let Some(user) = message.from() {
+        username.clone_from(&user.username);
+    } else if let teloxide::types::MessageKind::Common(MessageCommon {
+        ref author_signature,
+        ..
+    }) = message.kind
+    {
+        username.clone_from(author_signature); // channel post
+    }
+
+    let prefix = username
+        .map(|username| {
+            i18n::tr(
+                i18n::lang(message.from()),
+                Msg::OriginalPoster,
+                &[&username],
+            )
+        })
+        .unwrap_or_default();
+
+    let mut parts = Vec::new();
+
+    if !in_private_chat {
+        parts.push(format!("{prefix}\n{text}"));
+    }
+
+    if let Some(info) = info {
+        parts.push(info.describe());
+    }
+
+    parts.join("\n\n")
+}
+
+/// Sends a downloaded audio file to the chat, returning whether it has been sent.
+async fn process_audio(
+    bot: &Bot,
+    message: &Message,
+    file_path: &str,
+    info: Option<&utils::VideoInfo>,
+    permit: &mut Permit,
+) -> (bool, Option<String>) {
+    let lang = i18n::lang(message.from());
+
+    // audio files don't need to be converted, but we'd like to know their duration
+    let Some(metadata) = utils::probe(file_path).await else {
+        warn!("{} has no audio stream, skipping it", file_path);
+        send_private_message(bot, message, i18n::tr(lang, Msg::UnsupportedMedia, &[])).await;
+
+        return (false, None);
+    };
+
+    permit.upload().await;
+
+    let mut request = bot
+        .send_audio(delivery_chat(message), InputFile::file(file_path))
+        .duration(metadata.duration)
+        .allow_sending_without_reply(true);
+
+    let caption = caption(message, info);
+    if !caption.is_empty() {
+        request = request.caption(caption);
+    }
+
+    if let Some(reply_to) = reply_target(message) {
+        request = request.reply_to_message_id(reply_to);
+    }
+
+    match request.await {
+        Ok(sent) => {
+            info!("the audio has been sent");
+            stats::record_bytes_sent(utils::file_size(file_path));
+            (true, sent.audio().map(|audio| audio.file.id.clone()))
+        }
+        Err(e) => {
+            error!("failed to send the audio: {}", e);
+            (false, None)
+        }
+    }
+}
+
+#[allow(clippy::too_many_lines, clippy::too_many_arguments)] // sorry
+/// Converts a downloaded video and sends it to the chat, returning whether it has been sent,
+/// along with its file id (unless it had to be split into parts or was sent as an animation).
+async fn process_video(
+    bot: &Bot,
+    message: &Message,
+    url: &str,
+    file_path: &str,
+    dir_path: &std::path::Path,
+    info: Option<&utils::VideoInfo>,
+    subtitles: &[String],
+    spoiler: bool,
+    fallback: bool,
+    permit: &mut Permit,
+) -> (bool, Option<String>) {
+    let in_private_chat = matches!(message.chat.kind, ChatKind::Private(_));
+    let lang = i18n::lang(message.from());
+
+    // the previous playlist item might have been uploading
+    permit.work().await;
+
+    let filename = format!("{}.mp4", utils::random_string(10));
+    let full_path = dir_path.join(&filename);
+    let full_path_str = full_path.to_str().unwrap();
+
+    // probe the video for metadata
+    // (yt-dlp occasionally saves something else, like an image or a playlist manifest)
+    let Some(metadata) = utils::probe(file_path).await else {
+        warn!("{} has no video or audio streams, skipping it", file_path);
+        send_private_message(bot, message, i18n::tr(lang, Msg::UnsupportedMedia, &[])).await;
+
+        return (false, None);
+    };
+
+    let original_bitrate = metadata.bitrate;
+
+    if metadata.rotation != 0 {
+        debug!("the video is rotated by {} degrees", metadata.rotation);
+    }
+
+    let site_thumbnail = utils::find_thumbnail(file_path);
+
+    // if the fallback bitrate is much lower than the original bitrate, skip to fallback
+    let skip_to_fallback = skips_to_fallback(&metadata);
+
+    // long videos can be split into parts instead, keeping their quality
+    if skip_to_fallback && *PREFER_SPLIT.get().expect("PREFER_SPLIT is not initialised") {
+        // splitting only copies the streams, so it doesn't need a worker
+        permit.upload().await;
+
+        if let Some(sent) = split_and_send(
+            bot, message, file_path, dir_path, &metadata, info, subtitles, spoiler,
+        )
+        .await
+        {
+            return (sent, None);
+        }
+
+        warn!("failed to split the video, reducing the bitrate instead");
+        permit.work().await;
+    }
+
+    let Converted {
+        mut reduced_bitrate,
+        mut subtitles_embedded,
+    } = match convert_video(
+        file_path,
+        full_path_str,
+        &metadata,
+        skip_to_fallback,
+        fallback,
+        subtitles,
+    )
+    .await
+    {
+        Ok(converted) => converted,
+        Err(e) => {
+            send_private_message(bot, message, e.message(lang)).await;
+            return (false, None);
+        }
+    };
+
+    let allow_reduction = allows_reduction(fallback);
+
+    // the converted video might have been scaled down (see MAX_OUTPUT_HEIGHT) or turned upright,
+    // so its dimensions are sent to Telegram rather than the downloaded file's
+    // (the downloaded file's metadata is still needed, in case the video has to be converted again)
+    let probed = utils::probe(full_path_str).await;
+    let output_metadata = probed.as_ref().unwrap_or(&metadata);
+
+    let chat_id = delivery_chat(message);
+
+    permit.upload().await;
+
+    // videos Telegram can't play would show up as broken, so they are sent as files instead
+    let playable = !*DOCUMENT_FALLBACK
+        .get()
+        .expect("DOCUMENT_FALLBACK is not initialised")
+        || utils::is_playable(full_path_str).await;
+
+    let mut retries = 0;
+
+    let sent = loop {
+        let caption = caption(message, info);
+
+        let sent = if !playable {
+            warn!("the converted video isn't playable, sending it as a document");
+
+            send_document_file(
+                bot,
+                message,
+                full_path_str,
+                site_thumbnail.as_deref(),
+                output_metadata,
+                caption,
+            )
+            .await
+        } else if is_animation(full_path_str, output_metadata) {
+            send_animation_file(
+                bot,
+                message,
+                full_path_str,
+                site_thumbnail.as_deref(),
+                output_metadata,
+                caption,
+                spoiler,
+            )
+            .await
+        } else {
+            send_video_file(
+                bot,
+                message,
+                full_path_str,
+                site_thumbnail.as_deref(),
+                output_metadata,
+                caption,
+                spoiler,
+            )
+            .await
+        };
+
+        // the server might enforce a lower limit than the configured one (e.g. a local Bot API server),
+        // so the video is converted again with a lower bitrate (but only so many times)
+        match sent {
+            Err(RequestError::Api(ApiError::RequestEntityTooLarge))
+                if allow_reduction && retries < MAX_OVERSIZE_RETRIES =>
+            {
+                retries += 1;
+
+                let Some(bitrate) = oversize_bitrate(full_path_str).await else {
+                    break None;
+                };
+
+                warn!(
+                    "the video was too large for Telegram, converting it again at {} kbps",
+                    bitrate
+                );
+
+                permit.work().await;
+
+                let (exit_success, embedded) = convert_with_subtitles(
+                    file_path,
+                    full_path_str,
+                    &metadata,
+                    Some(bitrate),
+                    subtitles,
+                )
+                .await;
+
+                if !exit_success {
+                    tokio::fs::remove_file(&full_path).await.ok();
+                    error!(
+                        "failed to convert the video (bitrate adjusted to {} kbps): {}",
+                        bitrate, url
+                    );
+
+                    send_private_message(bot, message, i18n::tr(lang, Msg::ConvertFailed, &[]))
+                        .await;
+                    return (false, None);
+                }
+
+                subtitles_embedded = embedded;
+                reduced_bitrate = Some(bitrate);
+
+                permit.upload().await;
+            }
+            sent => break sent.ok(),
+        }
+    };
+
+    let Some(sent) = sent else {
+        return (false, None);
+    };
+
+    if !playable {
+        send_private_message(bot, message, i18n::tr(lang, Msg::SentAsDocument, &[])).await;
+    }
+
+    // if in a private chat and the bitrate was reduced, send a warning
+    if let (true, Some(reduced_bitrate)) = (in_private_chat, reduced_bitrate) {
+        let reduction_percentage =
+            (1.0 - f64::from(reduced_bitrate) / f64::from(original_bitrate)) * 100.0;
+
+        bot.send_message(
+            message.chat.id,
+            i18n::tr(
+                lang,
+                Msg::BitrateReduced,
+                &[
+                    &original_bitrate,
+                    &reduced_bitrate,
+                    &format!("{reduction_percentage:.1}"),
+                ],
+            ),
+        )
+        .reply_to_message_id(message.id)
+        .await
+        .log_on_error()
+        .await;
+    }
+
+    // subtitles which couldn't be embedded are sent as separate files
+    if !subtitles_embedded {
+        for subtitle in subtitles {
+            bot.send_document(chat_id, InputFile::file(subtitle))
+                .reply_to_message_id(sent.id)
+                .await
+                .log_on_error()
+                .await;
+        }
+    }
+
+    (true, sent.video().map(|video| video.file.id.clone()))
+}
+
+/// Calculates the video bitrate (in kbps) which makes a video fit within the upload limit.
+/// Returns `None` if the duration of the video is unknown.
+fn fallback_bitrate(metadata: &utils::Probe) -> Option<u32> {
+    if metadata.duration == 0 {
+        return None;
+    }
+
+    let upload_limit = *utils::UPLOAD_LIMIT
+        .get()
+        .expect("UPLOAD_LIMIT is not initialised");
+
+    let audio_bitrate = utils::audio_allowance(metadata);
+
+    // notice that we reserved the bitrate of the audio (FFMPEG_AUDIO_BITRATE, unless it's copied)
+    // the total bitrate has been reduced by 3% to account for container overhead
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let calculated_bitrate = (((f64::from(upload_limit) * 8000.0 / f64::from(metadata.duration))
+        - f64::from(audio_bitrate)
+        - 5.0)
+        * 0.97)
+        .floor() as u32;
+
+    Some(calculated_bitrate)
+}
+
+/// Calculates a lower video bitrate (in kbps) for a converted video which Telegram refused as too large.
+/// Returns `None` if the bitrate of the video is unknown.
+async fn oversize_bitrate(path: &str) -> Option<u32> {
+    let metadata = utils::probe(path).await?;
+    let video_bitrate = metadata
+        .bitrate
+        .checked_sub(utils::audio_allowance(&metadata))?;
+
+    Some(video_bitrate * 3 / 4).filter(|&bitrate| bitrate > 0)
+}
+
+/// Splits a video into parts which fit within the upload limit and sends them to the chat.
+/// Returns `None` if the video couldn't be split, so that its bitrate can be reduced instead.
+#[allow(clippy::too_many_arguments)]
+async fn split_and_send(
+    bot: &Bot,
+    message: &Message,
+    file_path: &str,
+    dir_path: &std::path::Path,
+    metadata: &utils::Probe,
+    info: Option<&utils::VideoInfo>,
+    subtitles: &[String],
+    spoiler: bool,
+) -> Option<bool> {
+    let upload_limit = *utils::UPLOAD_LIMIT
+        .get()
+        .expect("UPLOAD_LIMIT is not initialised");
+
+    // estimate the size of the video (in megabytes), leaving 10% of headroom per part
+    let audio_bitrate = utils::audio_allowance(metadata);
+
+    let estimated_size =
+        f64::from(metadata.bitrate + audio_bitrate) * f64::from(metadata.duration) / 8000.0;
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let part_count = (estimated_size / (f64::from(upload_limit) * 0.9))
+        .ceil()
+        .max(1.0) as u32;
+
+    // the parts get a directory of their own, as there might be other videos in the playlist
+    let parts_dir = dir_path.join(utils::random_string(10));
+    std::fs::create_dir(&parts_dir).ok()?;
+
+    let parts = utils::split(
+        file_path,
+        metadata,
+        parts_dir.to_str()?,
+        metadata.duration.div_ceil(part_count),
+    )
+    .await;
+
+    // every part has to fit, otherwise the video would be sent incomplete
+    let limit = u64::from(upload_limit) * 1000 * 1000;
+
+    if parts.is_empty() || parts.iter().any(|part| utils::file_size(part) > limit) {
+        std::fs::remove_dir_all(&parts_dir).ok();
+        return None;
+    }
+
+    info!("split the video into {} parts", parts.len());
+
+    let caption = caption_text(message, info);
+    let mut first_sent = None;
+
+    let site_thumbnail = utils::find_thumbnail(file_path);
+
+    for (i, part) in parts.iter().enumerate() {
+        let label = i18n::tr(
+            i18n::lang(message.from()),
+            Msg::Part,
+            &[&(i + 1), &parts.len()],
+        );
+
+        let caption = if caption.is_empty() {
+            fit_caption(&label)
+        } else {
+            fit_caption(&format!("{label}\n\n{caption}"))
+        };
+
+        let metadata = utils::probe(part).await.unwrap_or_default();
+        let sent = send_video_file(
+            bot,
+            message,
+            part,
+            site_thumbnail.as_deref(),
+            &metadata,
+            caption,
+            spoiler,
+        )
+        .await;
+
+        first_sent = first_sent.or_else(|| sent.ok());
+    }
+
+    // subtitles can't be embedded into the parts, so they're sent as separate files
+    if let Some(sent) = &first_sent {
+        for subtitle in subtitles {
+            bot.send_document(delivery_chat(message), InputFile::file(subtitle))
+                .reply_to_message_id(sent.id)
+                .await
+                .log_on_error()
+                .await;
+        }
+    }
+
+    Some(first_sent.is_some())
+}
+
+/// Sends a previously sent file to the chat again, returning whether it has been sent.
+/// Only videos can be covered with a spoiler.
+async fn send_cached(
+    bot: &Bot,
+    message: &Message,
+    media: utils::Media,
+    entry: &cache::Entry,
+    spoiler: bool,
+) -> bool {
+    let file = InputFile::file_id(entry.file_id.clone());
+    let caption = caption(message, entry.info.as_ref());
+
+    let reply_to = reply_target(message);
+
+    let result = match media {
+        utils::Media::Video => {
+            let mut request = bot
+                .send_video(delivery_chat(message), file)
+                .supports_streaming(true)
+                .has_spoiler(spoiler)
+                .allow_sending_without_reply(true);
+
+            if !caption.is_empty() {
+                request = request.caption(caption);
+            }
+
+            if let Some(reply_to) = reply_to {
+                request = request.reply_to_message_id(reply_to);
+            }
+
+            request.await
+        }
+        utils::Media::Audio => {
+            let mut request = bot
+                .send_audio(delivery_chat(message), file)
+                .allow_sending_without_reply(true);
+
+            if !caption.is_empty() {
+                request = request.caption(caption);
+            }
+
+            if let Some(reply_to) = reply_to {
+                request = request.reply_to_message_id(reply_to);
+            }
+
+            request.await
+        }
+    };
+
+    match result {
+        Ok(_) => {
+            info!("the cached file has been sent");
+            true
+        }
+        Err(e) => {
+            error!("failed to send the cached file: {}", e);
+            false
+        }
+    }
+}
+
+/// Sends a converted video to the chat (optionally covered with a spoiler),
+/// returning the sent message.
+async fn send_video_file(
+    bot: &Bot,
+    message: &Message,
+    path: &str,
+    site_thumbnail: Option<&str>,
+    metadata: &utils::Probe,
+    caption: String,
+    spoiler: bool,
+) -> Result<Message, RequestError> {
+    let thumbnail = utils::get_thumbnail(path, site_thumbnail, metadata.duration).await;
+
+    let mut request = bot
+        .send_video(delivery_chat(message), InputFile::file(path))
+        .width(metadata.width)
+        .height(metadata.height)
+        .duration(metadata.duration)
+        .supports_streaming(true)
+        .has_spoiler(spoiler)
+        .allow_sending_without_reply(true);
+
+    if let Some(thumbnail) = thumbnail {
+        request = request.thumb(thumbnail);
+    }
+
+    if !caption.is_empty() {
+        request = request.caption(caption);
+    }
+
+    if let Some(reply_to) = reply_target(message) {
+        request = request.reply_to_message_id(reply_to);
+    }
+
+    match request.await {
+        Ok(sent) => {
+            info!("the video has been sent");
+            stats::record_bytes_sent(utils::file_size(path));
+            Ok(sent)
+        }
+        Err(e) => {
+            error!("failed to send the video: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Reads `DELIVERY_CHATS`, e.g. `-1001234=-1005678,42=-1005678` (origin and destination chat ids).
+fn parse_delivery_chats() -> Vec<(ChatId, ChatId)> {
+    std::env::var("DELIVERY_CHATS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .filter_map(|pair| {
+            let parsed = pair
+                .split_once('=')
+                .and_then(|(origin, destination)| {
+                    Some((
+                        origin.trim().parse().ok()?,
+                        destination.trim().parse().ok()?,
+                    ))
+                })
+                .map(|(origin, destination)| (ChatId(origin), ChatId(destination)));
+
+            if parsed.is_none() {
+                warn!("failed to parse a DELIVERY_CHATS entry: {}", pair);
+            }
+
+            parsed
+        })
+        .collect()
+}
+
+/// Keeps the delivery chats the bot can send files to, so that the other requests are answered where they were made.
+async fn check_delivery_chats(
+    bot: &Bot,
+    delivery_chats: Vec<(ChatId, ChatId)>,
+) -> Vec<(ChatId, ChatId)> {
+    if delivery_chats.is_empty() {
+        return delivery_chats;
+    }
+
+    let me = bot.get_me().await.expect("failed to retrieve 'me'");
+    let mut checked = Vec::new();
+
+    for (origin, destination) in delivery_chats {
+        // in channels only the admins can post, and the members of other chats might be restricted
+        let can_post = match (
+            bot.get_chat(destination).await,
+            bot.get_chat_member(destination, me.id).await,
+        ) {
+            (Ok(chat), Ok(member)) if chat.is_channel() => member.kind.can_post_messages(),
+            (Ok(_), Ok(member)) => match member.kind {
+                ChatMemberKind::Restricted(restricted) => restricted.can_send_media_messages,
+                kind => kind.is_present(),
+            },
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("failed to check delivery chat {}: {}", destination, e);
+                false
+            }
+        };
+
+        if can_post {
+            info!(
+                "files requested in chat {} are sent to chat {}",
+                origin, destination
+            );
+            checked.push((origin, destination));
+        } else {
+            warn!(
+                "the bot can't send files to chat {}, sending them to chat {} instead",
+                destination, origin
+            );
+        }
+    }
+
+    checked
+}
+
+/// Returns the chat the files requested with a message are sent to (see `DELIVERY_CHATS`).
+fn delivery_chat(message: &Message) -> ChatId {
+    DELIVERY_CHATS
+        .get()
+        .expect("DELIVERY_CHATS is not initialised")
+        .iter()
+        .find(|(origin, _)| *origin == message.chat.id)
+        .map_or(message.chat.id, |&(_, destination)| destination)
+}
+
+/// Finishes a request once its files have been sent: in a group, the original message is replaced by them,
+/// and if they've been sent to another chat, the request is answered with a confirmation instead.
+async fn finish_request(bot: &Bot, message: &Message) {
+    if delivery_chat(message) != message.chat.id {
+        let lang = i18n::lang(message.from());
+
+        bot.send_message(message.chat.id, i18n::tr(lang, Msg::Delivered, &[]))
+            .reply_to_message_id(message.id)
+            .allow_sending_without_reply(true)
+            .await
+            .log_on_error()
+            .await;
+    } else if !matches!(message.chat.kind, ChatKind::Private(_)) {
+        bot.delete_message(message.chat.id, message.id)
+            .await
+            .log_on_error()
+            .await;
+    }
+}
+
+/// Returns the message which the files sent in response to a message should reply to.
+/// In groups, that's the message it replied to (if any), so that the files show up next to it,
+/// or else the message itself, unless `REPLY_TO_TRIGGER` is disabled.
+/// The files sent to another chat (see `DELIVERY_CHATS`) can't reply to anything.
+fn reply_target(message: &Message) -> Option<MessageId> {
+    let in_private_chat = matches!(message.chat.kind, ChatKind::Private(_));
+
+    if delivery_chat(message) != message.chat.id {
+        return None;
+    }
+
+    if in_private_chat {
+        return Some(message.id);
+    }
+
+    let reply_to_trigger = *REPLY_TO_TRIGGER
+        .get()
+        .expect("REPLY_TO_TRIGGER is not initialised");
+
+    message
+        .reply_to_message()
+        .map(|reply_to_message| reply_to_message.id)
+        .or_else(|| reply_to_trigger.then_some(message.id))
+}
+
+/// Sends the thumbnail of a video as a photo, returning whether it has been sent.
+/// The video isn't downloaded, so the thumbnail doesn't need to wait in the queue.
+async fn send_cover(bot: &Bot, message: &Message, url: &str) -> bool {
+    let lang = i18n::lang(message.from());
+    let temp_dir = utils::temp_dir().unwrap();
+    let dir_path = temp_dir.path().to_str().unwrap();
+
+    let Some(cover_path) = utils::download_cover(url, dir_path).await else {
+        info!("no thumbnail found for {}", url);
+
+        bot.send_message(message.chat.id, i18n::tr(lang, Msg::NoThumbnail, &[]))
             .reply_to_message_id(message.id)
-            .parse_mode(ParseMode::MarkdownV2)
             .await
             .log_on_error()
             .await;
-        }
 
-        return Ok(());
-    }
+        return false;
+    };
 
-    let url = url_info.maybe_url.unwrap();
+    let mut request = bot.send_photo(delivery_chat(message), InputFile::file(&cover_path));
 
-    // if the message is forwarded, ignore it
-    if message.forward_date().is_some() && !in_private_chat {
-        debug!("message is forwarded and not in private chat");
-        return Ok(());
+    if let Some(reply_to) = reply_target(message) {
+        request = request.reply_to_message_id(reply_to);
     }
 
-    // we want to download one video at a time
-    // first, acquire the counter mutex and get the current count
-    let count_lock = COUNT.get().expect("COUNT is not initialised").lock().await;
-    let count = *count_lock;
-    drop(count_lock);
+    match request.await {
+        Ok(_) => {
+            stats::record_bytes_sent(utils::file_size(&cover_path));
+            true
+        }
+        Err(e) => {
+            error!("failed to send the thumbnail: {}", e);
 
-    // send a message if the bot is busy
-    let mut queue_msg_id = None;
+            send_private_message(bot, message, i18n::tr(lang, Msg::UploadFailed, &[])).await;
+            false
+        }
+    }
+}
 
-    // we also don't want to clutter non-private chats
-    if in_private_chat {
-        let msg = if count >= 2 {
-            format!(
-                "Request accepted.\nYour position in the queue: {}.",
-                count - 1
-            )
-        } else {
-            "Request accepted.\nThe queue is empty, downloading now.".to_string()
-        };
+/// Sends photos to the chat as media groups (of up to `MAX_MEDIA_GROUP` photos each),
+/// returning whether any have been sent. The caption goes with the first photo.
+async fn send_photos(
+    bot: &Bot,
+    message: &Message,
+    paths: &[String],
+    caption: String,
+    spoiler: bool,
+) -> bool {
+    let reply_to = reply_target(message);
 
-        let queue_msg_result = bot
-            .send_message(message.chat.id, msg)
-            .reply_to_message_id(message.id)
-            .await;
+    let mut any_sent = false;
 
-        // we'd like to delete the queue message later
-        queue_msg_id = match queue_msg_result {
-            Ok(x) => Some(x.id),
-            Err(e) => {
-                error!("failed to send queue message: {}", e);
-                None
-            }
-        };
-    };
+    for (i, group) in paths.chunks(MAX_MEDIA_GROUP).enumerate() {
+        let media = group.iter().enumerate().map(|(j, path)| {
+            let mut photo = InputMediaPhoto::new(InputFile::file(path));
+            photo.has_spoiler = spoiler;
 
-    // wait for the mutex to be unlocked
-    let _guard = MUTEX.get().expect("MUTEX is not initialised").lock().await;
-    info!("downloading video from {}", url);
+            if i == 0 && j == 0 && !caption.is_empty() {
+                photo = photo.caption(caption.clone());
+            }
 
-    let filename = format!("{}.mp4", utils::random_string(10));
-    let temp_dir = tempdir().unwrap();
-    let dir_path = temp_dir.path().to_str().unwrap();
-    let full_path = temp_dir.path().join(&filename);
-    let full_path_str = full_path.to_str().unwrap();
+            InputMedia::Photo(photo)
+        });
 
-    // download the video
-    let exit_success = utils::download(&url, dir_path).await;
+        let mut request = bot
+            .send_media_group(delivery_chat(message), media)
+            .allow_sending_without_reply(true);
 
-    // find all files in the directory
-    let mut files = std::fs::read_dir(dir_path)
-        .unwrap()
-        .filter_map(std::result::Result::ok)
-        .collect::<Vec<_>>();
+        if let Some(reply_to) = reply_to {
+            request = request.reply_to_message_id(reply_to);
+        }
 
-    // check if yt-dlp downloaded the video by checking if dir contains a file
-    if files.len() != 1 {
-        if !in_private_chat {
-            return Ok(());
+        match request.await {
+            Ok(_) => {
+                info!("{} photo(s) have been sent", group.len());
+                stats::record_bytes_sent(group.iter().map(|path| utils::file_size(path)).sum());
+                any_sent = true;
+            }
+            Err(e) => error!("failed to send the photos: {}", e),
         }
+    }
 
-        let flen = files.len();
-        let msg = if flen == 0 {
-            "no".to_string()
-        } else {
-            flen.to_string()
-        };
+    any_sent
+}
 
-        bot.send_message(
-            message.chat.id,
-            format!("Failed to download video ({msg} files found)."),
-        )
-        .reply_to_message_id(message.id)
-        .await
-        .log_on_error()
-        .await;
+/// Sends a converted video to the chat as a document, returning the sent message.
+/// Documents can't be covered with a spoiler.
+async fn send_document_file(
+    bot: &Bot,
+    message: &Message,
+    path: &str,
+    site_thumbnail: Option<&str>,
+    metadata: &utils::Probe,
+    caption: String,
+) -> Result<Message, RequestError> {
+    let thumbnail = utils::get_thumbnail(path, site_thumbnail, metadata.duration).await;
+
+    let mut request = bot
+        .send_document(delivery_chat(message), InputFile::file(path))
+        .allow_sending_without_reply(true);
+
+    if let Some(thumbnail) = thumbnail {
+        request = request.thumb(thumbnail);
     }
 
-    if !exit_success {
-        if in_private_chat {
-            bot.send_message(
-                message.chat.id,
-                "Failed to download video (extractor exited with non-zero code).",
-            )
-            .reply_to_message_id(message.id)
-            .await
-            .log_on_error()
-            .await;
+    if !caption.is_empty() {
+        request = request.caption(caption);
+    }
+
+    if let Some(reply_to) = reply_target(message) {
+        request = request.reply_to_message_id(reply_to);
+    }
+
+    match request.await {
+        Ok(sent) => {
+            info!("the video has been sent as a document");
+            stats::record_bytes_sent(utils::file_size(path));
+            Ok(sent)
+        }
+        Err(e) => {
+            error!("failed to send the document: {}", e);
+            Err(e)
         }
-        return Ok(());
     }
+}
 
-    // get the path...
-    let entry = files.pop().unwrap();
-    let file_path = entry.path().to_str().unwrap().to_string();
+/// Checks whether a converted video should be sent as an animation,
+/// i.e. whether it's a short clip without audio.
+fn is_animation(path: &str, metadata: &utils::Probe) -> bool {
+    let max_secs = *ANIMATION_MAX_SECS
+        .get()
+        .expect("ANIMATION_MAX_SECS is not initialised");
 
-    // if file exceeds MAX_FILESIZE megabytes, bail
-    let bytes = entry.metadata().unwrap().len();
-    let megabytes = bytes / 1000 / 1000;
+    let max_megabytes = *ANIMATION_MAX_MB
+        .get()
+        .expect("ANIMATION_MAX_MB is not initialised");
 
-    let max_filesize = *MAX_FILESIZE.get().expect("MAX_FILESIZE is not initialised");
+    !metadata.has_audio
+        && metadata.duration > 0
+        && metadata.duration <= max_secs
+        && utils::file_size(path) / 1000 / 1000 < max_megabytes
+}
 
-    if megabytes > max_filesize {
-        if in_private_chat {
-            bot.send_message(
-                message.chat.id,
-                format!("Failed to convert video (base file size exceeds {max_filesize} MB)."),
-            )
-            .reply_to_message_id(message.id)
-            .await
-            .log_on_error()
-            .await;
+/// Sends a converted video to the chat as an animation (optionally covered with a spoiler),
+/// returning the sent message.
+async fn send_animation_file(
+    bot: &Bot,
+    message: &Message,
+    path: &str,
+    site_thumbnail: Option<&str>,
+    metadata: &utils::Probe,
+    caption: String,
+    spoiler: bool,
+) -> Result<Message, RequestError> {
+    let thumbnail = utils::get_thumbnail(path, site_thumbnail, metadata.duration).await;
+
+    let reply_to = reply_target(message);
+
+    let mut request = bot
+        .send_animation(delivery_chat(message), InputFile::file(path))
+        .width(metadata.width)
+        .height(metadata.height)
+        .duration(metadata.duration)
+        .has_spoiler(spoiler)
+        .allow_sending_without_reply(true);
+
+    if let Some(reply_to) = reply_to {
+        request = request.reply_to_message_id(reply_to);
+    }
+
+    if let Some(thumbnail) = thumbnail {
+        request = request.thumb(thumbnail);
+    }
+
+    if !caption.is_empty() {
+        request = request.caption(caption);
+    }
+
+    match request.await {
+        Ok(sent) => {
+            info!("the animation has been sent");
+            stats::record_bytes_sent(utils::file_size(path));
+            Ok(sent)
+        }
+        Err(e) => {
+            error!("failed to send the animation: {}", e);
+            Err(e)
         }
-        return Ok(());
     }
+}
 
-    info!("video downloaded to {}", file_path);
+/// Converts a video, embedding the subtitles if possible.
+/// Returns whether the conversion succeeded and whether the subtitles were embedded.
+async fn convert_with_subtitles(
+    input: &str,
+    output: &str,
+    metadata: &utils::Probe,
+    bitrate: Option<u32>,
+    subtitles: &[String],
+) -> (bool, bool) {
+    if !subtitles.is_empty() && utils::convert(input, output, metadata, bitrate, subtitles).await {
+        return (true, true);
+    }
 
-    // ...and probe the video for metadata
-    let metadata = utils::probe(&file_path).unwrap_or_default();
-    let original_bitrate = metadata.bitrate;
+    (
+        utils::convert(input, output, metadata, bitrate, &[]).await,
+        false,
+    )
+}
 
-    // calculate the fallback bitrate
-    let fallback_bitrate: Option<u32> = if metadata.duration != 0 {
-        // notice that we reserved 128 kbps for the audio
-        // the total bitrate has been reduced by 3% to account for container overhead
-        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
-        let calculated_bitrate =
-            (((f64::from(50 * 8000) / f64::from(metadata.duration)) - 128.0 - 5.0) * 0.97).floor()
-                as u32;
+/// Checks whether the bitrate of a video can be reduced to fit in the upload limit,
+/// which retried downloads (see `DownloadOptions::fallback`) are always allowed to.
+fn allows_reduction(fallback: bool) -> bool {
+    fallback
+        || *ALLOW_BITRATE_REDUCTION
+            .get()
+            .expect("ALLOW_BITRATE_REDUCTION is not initialised")
+}
 
-        Some(calculated_bitrate)
-    } else {
-        None
+/// Checks whether a video should be converted with the fallback bitrate right away,
+/// i.e. whether the fallback bitrate is less than 85% of the original bitrate
+/// (so that the plain conversion would most likely exceed the upload limit).
+fn skips_to_fallback(metadata: &utils::Probe) -> bool {
+    let Some(fallback_bitrate) = fallback_bitrate(metadata) else {
+        return false;
     };
 
-    // if the fallback bitrate is less than 85% of the original bitrate, skip to fallback
-    let mut skip_to_fallback = false;
+    let ratio = f64::from(fallback_bitrate) / f64::from(metadata.bitrate);
 
-    let mut reduction_percentage = None;
-    if fallback_bitrate.is_some() {
-        let ratio = f64::from(fallback_bitrate.unwrap()) / f64::from(original_bitrate);
-        reduction_percentage = Some((1.0 - ratio) * 100.0);
+    if ratio < 0.85 {
+        warn!(
+            "fallback bitrate ({} kbps) is {} lower than the original bitrate ({} kbps)",
+            fallback_bitrate,
+            format!("{:.1}%", (1.0 - ratio) * 100.0),
+            metadata.bitrate
+        );
+    }
 
-        if ratio < 0.85 {
-            warn!(
-                "fallback bitrate ({} kbps) is {} lower than the original bitrate ({} kbps)",
-                fallback_bitrate.unwrap(),
-                format!("{:.1}%", reduction_percentage.unwrap()),
-                original_bitrate
-            );
+    ratio < 0.85
+}
+
+/// A video converted by `convert_video`.
+struct Converted {
+    /// The video bitrate (in kbps) the video was converted with, if it had to be reduced.
+    reduced_bitrate: Option<u32>,
+    subtitles_embedded: bool,
+}
 
-            skip_to_fallback = true;
+/// Reasons why `convert_video` failed.
+enum ConvertError {
+    /// The video doesn't fit in the upload limit, and `ALLOW_BITRATE_REDUCTION` is disabled.
+    ExceedsUploadLimit,
+    Failed,
+}
+
+impl ConvertError {
+    /// Returns the message telling the user why the video wasn't converted.
+    fn message(&self, lang: i18n::Lang) -> String {
+        match self {
+            Self::ExceedsUploadLimit => {
+                let upload_limit = *utils::UPLOAD_LIMIT
+                    .get()
+                    .expect("UPLOAD_LIMIT is not initialised");
+
+                i18n::tr(lang, Msg::ExceedsUploadLimit, &[&upload_limit])
+            }
+            Self::Failed => i18n::tr(lang, Msg::ConvertFailed, &[]),
         }
     }
+}
+
+/// Converts a downloaded video to an .mp4 file Telegram can play. The video is converted as it is first
+/// (unless `skip_to_fallback`, see `skips_to_fallback`), and if that fails (or the output reaches
+/// the upload limit), it's converted again with the fallback bitrate (if bitrate reduction is allowed).
+/// Leftover files are removed if the conversion fails.
+async fn convert_video(
+    input: &str,
+    output: &str,
+    metadata: &utils::Probe,
+    skip_to_fallback: bool,
+    fallback: bool,
+    subtitles: &[String],
+) -> Result<Converted, ConvertError> {
+    let allow_reduction = allows_reduction(fallback);
+
+    // some users would rather get an error than a video of noticeably worse quality
+    if skip_to_fallback && !allow_reduction {
+        info!("the video exceeds the upload limit and bitrate reduction is disabled");
+        return Err(ConvertError::ExceedsUploadLimit);
+    }
 
-    let mut bitrate_reduced = false;
+    let fallback_bitrate = fallback_bitrate(metadata).filter(|_| allow_reduction);
+
+    let mut reduced_bitrate = None;
+    let mut subtitles_embedded = false;
+    let mut exceeds_limit = false;
+    let convert_start = Instant::now();
 
     // first, try to convert the video without adjusting the bitrate
     // (if it seems unlikely that the conversion will fail)
     let exit_success = if skip_to_fallback {
         false
     } else {
-        utils::convert(&file_path, full_path_str, None).await
+        let (exit_success, embedded) =
+            convert_with_subtitles(input, output, metadata, None, subtitles).await;
+
+        // the estimate can be off, in which case the output is cut short at the upload limit
+        exceeds_limit = utils::reaches_upload_limit(output);
+
+        if exceeds_limit {
+            warn!("the converted video reached the upload limit");
+        }
+
+        subtitles_embedded = embedded;
+        exit_success && !exceeds_limit
     };
 
     // if the conversion failed, try to adjust the bitrate
     // this cannot be done if metadata is not available
     if exit_success {
         info!("converted the video (no bitrate adjustment)");
-    } else if !exit_success && fallback_bitrate.is_some() {
-        let exit_success = utils::convert(&file_path, full_path_str, fallback_bitrate).await;
+    } else if let Some(fallback_bitrate) = fallback_bitrate {
+        let (exit_success, embedded) =
+            convert_with_subtitles(input, output, metadata, Some(fallback_bitrate), subtitles)
+                .await;
+
+        subtitles_embedded = embedded;
 
         if exit_success {
             info!(
                 "converted the video (bitrate adjusted to {} kbps)",
-                fallback_bitrate.unwrap(),
+                fallback_bitrate,
             );
-            bitrate_reduced = true;
+            reduced_bitrate = Some(fallback_bitrate);
         } else {
             // remove leftover files
-            tokio::fs::remove_file(&full_path).await.unwrap();
+            tokio::fs::remove_file(output).await.ok();
             error!(
                 "failed to convert the video (bitrate adjusted to {} kbps): {}",
-                fallback_bitrate.unwrap(),
-                url
+                fallback_bitrate, input
             );
         }
     } else {
         // remove leftover files
-        tokio::fs::remove_file(&full_path).await.unwrap();
+        tokio::fs::remove_file(output).await.ok();
         error!(
             "failed to convert the video (no bitrate adjustment): {}",
-            url
+            input
         );
     }
 
-    if full_path.exists() {
+    metrics::histogram!("convert_duration_seconds").record(convert_start.elapsed());
+
+    if std::path::Path::new(output).exists() {
         info!("video converted successfully");
     } else {
-        error!(
-            "failed to download video: path {} does not exist",
-            full_path_str
-        );
+        error!("failed to download video: path {} does not exist", output);
 
-        if in_private_chat {
-            bot.send_message(message.chat.id, "Failed to convert the video.")
-                .reply_to_message_id(message.id)
-                .await
-                .log_on_error()
-                .await;
-        }
-        return Ok(());
+        // the bitrate isn't reduced if it's disabled, so the video can't fit in the upload limit
+        return Err(if exceeds_limit && !allow_reduction {
+            ConvertError::ExceedsUploadLimit
+        } else {
+            ConvertError::Failed
+        });
     }
 
-    let file = InputFile::file(&full_path);
-    let chat_id = message.chat.id;
-    let mut username = None;
+    Ok(Converted {
+        reduced_bitrate,
+        subtitles_embedded,
+    })
+}
 
-    if let Some(user) = message.from() {
-        username = user.username.clone();
-    } else if let teloxide::types::MessageKind::Common(MessageCommon {
-        ref author_signature,
-        ..
-    }) = message.kind
-    {
-        username = author_signature.clone(); // channel post
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filesizes() {
+        assert_eq!(
+            parse_filesize("MAX_FILESIZE", Some("100"), DEFAULT_MAX_FILESIZE),
+            100
+        );
+        assert_eq!(
+            parse_filesize("MAX_FILESIZE", Some(" 2000 "), DEFAULT_MAX_FILESIZE),
+            2000
+        );
     }
 
-    let mut prefix = String::new();
-    if let Some(username) = username {
-        prefix = format!("[original poster: {username}]");
-    };
+    #[test]
+    fn defaults_unset_filesizes() {
+        assert_eq!(
+            parse_filesize("MAX_FILESIZE", None, DEFAULT_MAX_FILESIZE),
+            DEFAULT_MAX_FILESIZE
+        );
+    }
 
-    let message_with_prefix = format!("{prefix}\n{text}");
-    let thumbnail = utils::get_thumbnail(full_path_str).await;
+    #[test]
+    fn defaults_invalid_filesizes() {
+        assert_eq!(
+            parse_filesize("MAX_FILESIZE", Some(""), DEFAULT_MAX_FILESIZE),
+            DEFAULT_MAX_FILESIZE
+        );
+        assert_eq!(
+            parse_filesize("MAX_FILESIZE", Some("abc"), DEFAULT_MAX_FILESIZE),
+            DEFAULT_MAX_FILESIZE
+        );
+        assert_eq!(
+            parse_filesize("MAX_FILESIZE", Some("-5"), DEFAULT_MAX_FILESIZE),
+            DEFAULT_MAX_FILESIZE
+        );
+        assert_eq!(
+            parse_filesize("MAX_FILESIZE", Some("1.5"), DEFAULT_MAX_FILESIZE),
+            DEFAULT_MAX_FILESIZE
+        );
+        assert_eq!(
+            parse_filesize("MAX_FILESIZE", Some("100MB"), DEFAULT_MAX_FILESIZE),
+            DEFAULT_MAX_FILESIZE
+        );
+    }
 
-    let mut request = bot
-        .send_video(chat_id, file)
-        .width(metadata.width)
-        .height(metadata.height)
-        .duration(metadata.duration)
-        .supports_streaming(true)
-        .reply_to_message_id(message.id);
+    #[test]
+    fn defaults_to_the_given_limit() {
+        // FALLBACK_MAX_FILESIZE falls back to MAX_FILESIZE
+        assert_eq!(parse_filesize("FALLBACK_MAX_FILESIZE", None, 500), 500);
+        assert_eq!(
+            parse_filesize("FALLBACK_MAX_FILESIZE", Some("5OO"), 500),
+            500
+        );
+        assert_eq!(
+            parse_filesize("FALLBACK_MAX_FILESIZE", Some("1000"), 500),
+            1000
+        );
+    }
 
-    if let Some(thumbnail) = thumbnail {
-        request = request.thumb(thumbnail);
+    #[test]
+    fn defaults_zero_filesizes() {
+        assert_eq!(
+            parse_filesize("MAX_FILESIZE", Some("0"), DEFAULT_MAX_FILESIZE),
+            DEFAULT_MAX_FILESIZE
+        );
     }
 
-    // if in a private chat, send the video directly
-    if in_private_chat {
-        if let Err(e) = request.await {
-            error!("failed to send the video: {}", e);
-        } else {
-            info!("the video has been sent");
-        }
+    /// Runs the handler on a message, returning the texts it sent (or edited) to the mock Bot API.
+    async fn answer(message: Message) -> Vec<String> {
+        harness::init();
 
-        // if the bitrate was reduced, send a warning
-        if bitrate_reduced {
-            bot.send_message(
-                chat_id,
-                format!(
-                    "Warning: the bitrate of the video has been reduced \
-                    from {} kbps to {} kbps ({:.1}% reduction) to meet \
-                    Telegram's file size limit.",
-                    original_bitrate,
-                    fallback_bitrate.unwrap(),
-                    reduction_percentage.unwrap_or_default(),
-                ),
-            )
-            .reply_to_message_id(message.id)
+        let backend = harness::Backend::start().await;
+        handler(message, backend.bot(), harness::me())
             .await
-            .log_on_error()
-            .await;
-        }
-    } else {
-        // if in a group, send the video with the original message
-        request = request.caption(message_with_prefix);
+            .unwrap();
 
-        // if the message was a reply, send the video as a reply
-        if let Some(reply_to_message) = message.reply_to_message() {
-            request = request.reply_to_message_id(reply_to_message.id);
-        }
+        backend.texts()
+    }
 
-        if let Err(e) = request.await {
-            error!("failed to send the video: {}", e);
-        } else {
-            // delete the original message
-            info!("the video has been sent");
-            bot.delete_message(chat_id, message.id)
-                .await
-                .log_on_error()
-                .await;
-        }
+    /// The reply to a message which can't be downloaded, as sent in private.
+    fn contact_maintainer(msg: &str) -> String {
+        i18n::tr_markup(
+            i18n::Lang::English,
+            Msg::ContactMaintainer,
+            &[&msg, &markup::escape(&format!("@{}", harness::MAINTAINER))],
+        )
     }
 
-    // remove leftover message
-    if let Some(id) = queue_msg_id {
-        bot.delete_message(chat_id, id).await.log_on_error().await;
+    #[test]
+    fn parses_commands_addressed_to_the_bot() {
+        assert_eq!(
+            Command::parse("/yeet@yeet_bot https://example.com/video", "yeet_bot").ok(),
+            Some(Command::Yeet("https://example.com/video".to_string()))
+        );
+        assert_eq!(
+            Command::parse("/ping", "yeet_bot").ok(),
+            Some(Command::Ping)
+        );
+
+        // commands of other bots are treated like any other message
+        assert!(Command::parse("/ping@other_bot", "yeet_bot").is_err());
+        assert!(Command::parse("https://example.com/video", "yeet_bot").is_err());
     }
 
-    info!("finished processing");
+    #[tokio::test]
+    async fn asks_for_a_link_in_private() {
+        let message = harness::private_message(101, "someone", "en", "hello");
 
-    Ok(())
+        assert_eq!(
+            answer(message).await,
+            [contact_maintainer(&i18n::tr_markup(
+                i18n::Lang::English,
+                Msg::NoUrls,
+                &[]
+            ))]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_messages_without_links_in_groups() {
+        let message = harness::group_message(-102, 102, "hello");
+
+        assert!(answer(message).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lists_the_whitelist_for_other_sites() {
+        harness::init();
+
+        let message = harness::private_message(103, "someone", "en", "https://other.org/video");
+        let netlocs = format_netlocs(i18n::Lang::English, &whitelists::effective(message.chat.id));
+
+        assert_eq!(
+            answer(message).await,
+            [contact_maintainer(&i18n::tr_markup(
+                i18n::Lang::English,
+                Msg::NoWhitelistedUrls,
+                &[&netlocs]
+            ))]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_denied_links() {
+        let message =
+            harness::private_message(104, "someone", "en", "https://denied.example.com/video");
+
+        assert_eq!(
+            answer(message).await,
+            [contact_maintainer(&i18n::tr_markup(
+                i18n::Lang::English,
+                Msg::NetlocDenied,
+                &[]
+            ))]
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_pings() {
+        let message = harness::private_message(105, "someone", "en", "/ping");
+        let texts = answer(message).await;
+
+        // the reply is edited to show the latency
+        assert_eq!(texts.len(), 2);
+        assert_eq!(texts[0], i18n::tr(i18n::Lang::English, Msg::Pong, &[]));
+        assert!(texts[1].starts_with("Pong! Latency to Telegram: "));
+    }
+
+    #[tokio::test]
+    async fn answers_in_the_language_of_the_user() {
+        let message = harness::private_message(106, "someone", "pl", "/cancel");
+
+        assert_eq!(
+            answer(message).await,
+            [i18n::tr(i18n::Lang::Polish, Msg::NothingToCancel, &[])]
+        );
+    }
+
+    #[tokio::test]
+    async fn has_nothing_to_retry() {
+        let message = harness::private_message(107, "someone", "en", "/retry");
+
+        assert_eq!(
+            answer(message).await,
+            [i18n::tr(i18n::Lang::English, Msg::NothingToRetry, &[])]
+        );
+    }
+
+    #[tokio::test]
+    async fn shows_the_maintainer_commands_to_the_maintainer_only() {
+        let message = harness::private_message(108, "someone", "en", "/help");
+
+        assert_eq!(
+            answer(message).await,
+            [commands::help(i18n::Lang::English, false)]
+        );
+
+        let message = harness::private_message(109, harness::MAINTAINER, "en", "/help");
+
+        assert_eq!(
+            answer(message).await,
+            [commands::help(i18n::Lang::English, true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_maintainer_commands_from_everyone_else() {
+        for command in [
+            "/update",
+            "/queue",
+            "/sysinfo",
+            "/flush_cache",
+            "/allow example.org",
+        ] {
+            let message = harness::private_message(110, "someone", "en", command);
+
+            assert!(answer(message).await.is_empty(), "{command} was answered");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ffmpeg"]
+    async fn sends_a_sample_video() {
+        harness::init();
+
+        let backend = harness::Backend::start().await;
+        let message = harness::private_message(111, "someone", "en", "https://example.com/video");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = harness::sample_video(dir.path());
+
+        let (sent, _) = process_video(
+            &backend.bot(),
+            &message,
+            "https://example.com/video",
+            &path,
+            dir.path(),
+            None,
+            &[],
+            false,
+            false,
+            &mut Permit::None,
+        )
+        .await;
+
+        assert!(sent);
+        assert!(backend
+            .requests()
+            .iter()
+            .any(|request| request.method == "sendvideo"));
+    }
 }
@@ -0,0 +1,234 @@
+//! Formatting of the replies, in the parse mode chosen with `PARSE_MODE`.
+//! Every reply which formats (or escapes) its text goes through here, so the mode can't get mixed up.
+
+use std::{str::FromStr, sync::OnceLock};
+
+use teloxide::{
+    payloads::SendMessage,
+    requests::HasPayload,
+    types::ParseMode,
+    utils::{html, markdown},
+};
+
+static MARKUP: OnceLock<Markup> = OnceLock::new();
+
+/// Ways the replies can be formatted in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Markup {
+    MarkdownV2,
+    Html,
+    /// Plain text, which can't break, but isn't formatted either.
+    None,
+}
+
+impl FromStr for Markup {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "markdownv2" => Ok(Self::MarkdownV2),
+            "html" => Ok(Self::Html),
+            "none" => Ok(Self::None),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Initialises the parse mode, which defaults to `MarkdownV2`.
+pub fn init_statics() {
+    MARKUP
+        .set(
+            std::env::var("PARSE_MODE")
+                .unwrap_or_else(|_| "MarkdownV2".to_string())
+                .parse()
+                .unwrap_or_else(|()| {
+                    warn!("failed to parse PARSE_MODE, using default value");
+                    Markup::MarkdownV2
+                }),
+        )
+        .expect("MARKUP was already initialised");
+}
+
+fn markup() -> Markup {
+    *MARKUP.get().expect("MARKUP is not initialised")
+}
+
+/// Returns the parse mode the formatted replies are sent with (or `None` for plain text).
+pub fn parse_mode() -> Option<ParseMode> {
+    match markup() {
+        Markup::MarkdownV2 => Some(ParseMode::MarkdownV2),
+        Markup::Html => Some(ParseMode::Html),
+        Markup::None => None,
+    }
+}
+
+/// Lets the messages formatted here be sent in the parse mode they were formatted for.
+pub trait Formatted {
+    /// Sets the parse mode of the message (or leaves it unset, for plain text).
+    #[must_use]
+    fn formatted(self) -> Self;
+}
+
+impl<R: HasPayload<Payload = SendMessage>> Formatted for R {
+    fn formatted(self) -> Self {
+        self.with_payload_mut(|payload| payload.parse_mode = parse_mode())
+    }
+}
+
+/// Escapes text, so that it's shown as it is.
+pub fn escape(s: &str) -> String {
+    markup().escape(s)
+}
+
+/// Formats text as bold.
+pub fn bold(s: &str) -> String {
+    markup().bold(s)
+}
+
+/// Formats text as inline code.
+pub fn code_inline(s: &str) -> String {
+    markup().code_inline(s)
+}
+
+/// Formats text as a block of code.
+pub fn code_block(s: &str) -> String {
+    markup().code_block(s)
+}
+
+impl Markup {
+    fn escape(self, s: &str) -> String {
+        match self {
+            // teloxide leaves backslashes alone, which would otherwise escape the next character
+            Self::MarkdownV2 => markdown::escape(&s.replace('\\', r"\\")),
+            Self::Html => html::escape(s),
+            Self::None => s.to_string(),
+        }
+    }
+
+    fn bold(self, s: &str) -> String {
+        match self {
+            Self::MarkdownV2 => markdown::bold(&self.escape(s)),
+            Self::Html => html::bold(&html::escape(s)),
+            Self::None => s.to_string(),
+        }
+    }
+
+    fn code_inline(self, s: &str) -> String {
+        match self {
+            Self::MarkdownV2 => markdown::code_inline(s),
+            Self::Html => html::code_inline(s),
+            Self::None => s.to_string(),
+        }
+    }
+
+    fn code_block(self, s: &str) -> String {
+        match self {
+            Self::MarkdownV2 => markdown::code_block(s),
+            Self::Html => html::code_block(s),
+            Self::None => s.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_parse_modes() {
+        assert_eq!("MarkdownV2".parse(), Ok(Markup::MarkdownV2));
+        assert_eq!(" html ".parse(), Ok(Markup::Html));
+        assert_eq!("NONE".parse(), Ok(Markup::None));
+        assert_eq!("markdown".parse::<Markup>(), Err(()));
+    }
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(Markup::MarkdownV2.escape("a_b*c"), "a\\_b\\*c");
+        assert_eq!(Markup::Html.escape("<a & b>"), "&lt;a &amp; b&gt;");
+        assert_eq!(Markup::None.escape("a_b <c>"), "a_b <c>");
+    }
+
+    /// Every character `MarkdownV2` reserves, along with the backslash which escapes them.
+    const SPECIAL: &str = r"_*[]()~`>#+-=|{}.!\";
+
+    #[test]
+    fn escapes_every_special_character() {
+        assert_eq!(
+            Markup::MarkdownV2.escape(SPECIAL),
+            r"\_\*\[\]\(\)\~\`\>\#\+\-\=\|\{\}\.\!\\"
+        );
+        assert_eq!(Markup::Html.escape(SPECIAL), SPECIAL.replace('>', "&gt;"));
+        assert_eq!(Markup::None.escape(SPECIAL), SPECIAL);
+        assert_eq!(
+            Markup::Html.escape("<b>&amp;</b>"),
+            "&lt;b&gt;&amp;amp;&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn escapes_urls() {
+        let url = "https://www.youtube.com/watch?v=a_b-c&t=1m2s#x+y!";
+
+        assert_eq!(
+            Markup::MarkdownV2.escape(url),
+            r"https://www\.youtube\.com/watch?v\=a\_b\-c&t\=1m2s\#x\+y\!"
+        );
+        assert_eq!(
+            Markup::Html.escape(url),
+            "https://www.youtube.com/watch?v=a_b-c&amp;t=1m2s#x+y!"
+        );
+        assert_eq!(Markup::None.escape(url), url);
+
+        assert_eq!(
+            Markup::MarkdownV2.bold(url),
+            r"*https://www\.youtube\.com/watch?v\=a\_b\-c&t\=1m2s\#x\+y\!*"
+        );
+        assert_eq!(
+            Markup::Html.bold(url),
+            "<b>https://www.youtube.com/watch?v=a_b-c&amp;t=1m2s#x+y!</b>"
+        );
+        assert_eq!(Markup::None.bold(url), url);
+    }
+
+    #[test]
+    fn escapes_filenames() {
+        let filename = r"[1080p] {clip} (1.5x) ~final~ `v2` |a>b| C:\videos\*.mp4";
+
+        assert_eq!(
+            Markup::MarkdownV2.escape(filename),
+            r"\[1080p\] \{clip\} \(1\.5x\) \~final\~ \`v2\` \|a\>b\| C:\\videos\\\*\.mp4"
+        );
+        assert_eq!(Markup::Html.escape(filename), filename.replace('>', "&gt;"));
+        assert_eq!(Markup::None.escape(filename), filename);
+    }
+
+    #[test]
+    fn keeps_code_as_it_is() {
+        // only the backticks and backslashes need escaping in code spans
+        assert_eq!(
+            Markup::MarkdownV2.code_inline(SPECIAL),
+            r"`_*[]()~\`>#+-=|{}.!\\`"
+        );
+        assert_eq!(
+            Markup::MarkdownV2.code_block("youtube.com\nvimeo.com"),
+            "```\nyoutube.com\nvimeo.com\n```"
+        );
+        assert_eq!(
+            Markup::Html.code_inline("a<b&c"),
+            "<code>a&lt;b&amp;c</code>"
+        );
+        assert_eq!(Markup::Html.code_block("a<b"), "<pre>a&lt;b</pre>");
+        assert_eq!(Markup::None.code_inline(SPECIAL), SPECIAL);
+        assert_eq!(Markup::None.code_block(SPECIAL), SPECIAL);
+    }
+
+    #[test]
+    fn formats_text() {
+        assert_eq!(Markup::MarkdownV2.bold("1.5"), "*1\\.5*");
+        assert_eq!(Markup::Html.bold("a<b"), "<b>a&lt;b</b>");
+        assert_eq!(Markup::None.bold("a"), "a");
+        assert_eq!(Markup::Html.code_inline("x"), "<code>x</code>");
+        assert_eq!(Markup::None.code_block("x"), "x");
+    }
+}
@@ -0,0 +1,69 @@
+//! Cumulative usage statistics, reported by `/stats`.
+//! They are kept in memory, so they only cover the current run.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static DOWNLOADS_COMPLETED: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static TASKS_PROCESSED: AtomicU64 = AtomicU64::new(0);
+static PROCESSING_MILLIS: AtomicU64 = AtomicU64::new(0);
+
+/// A snapshot of the statistics.
+pub struct Stats {
+    pub downloads_completed: u64,
+    pub bytes_sent: u64,
+    pub average_processing_time: Option<Duration>,
+    pub uptime: Duration,
+    pub queue_length: usize,
+    pub queue_capacity: Option<usize>,
+}
+
+/// Starts measuring the uptime.
+pub fn init_statics() {
+    STARTED
+        .set(Instant::now())
+        .expect("STARTED was already initialised");
+}
+
+/// Records a download which has been sent to the chat.
+pub fn record_download_completed() {
+    DOWNLOADS_COMPLETED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records a file which has been uploaded to Telegram.
+pub fn record_bytes_sent(bytes: u64) {
+    BYTES_SENT.fetch_add(bytes, Ordering::Relaxed);
+    metrics::counter!("bytes_sent_total").increment(bytes);
+}
+
+/// Records how long a worker has spent on a task.
+pub fn record_processing_time(duration: Duration) {
+    TASKS_PROCESSED.fetch_add(1, Ordering::Relaxed);
+    PROCESSING_MILLIS.fetch_add(
+        u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+        Ordering::Relaxed,
+    );
+}
+
+/// Takes a snapshot of the statistics.
+pub fn snapshot() -> Stats {
+    let tasks_processed = TASKS_PROCESSED.load(Ordering::Relaxed);
+
+    Stats {
+        downloads_completed: DOWNLOADS_COMPLETED.load(Ordering::Relaxed),
+        bytes_sent: BYTES_SENT.load(Ordering::Relaxed),
+        average_processing_time: (tasks_processed > 0).then(|| {
+            Duration::from_millis(PROCESSING_MILLIS.load(Ordering::Relaxed) / tasks_processed)
+        }),
+        uptime: STARTED.get().expect("STARTED is not initialised").elapsed(),
+        queue_length: crate::tasks::queue_length(),
+        queue_capacity: crate::tasks::queue_capacity(),
+    }
+}
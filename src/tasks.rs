@@ -0,0 +1,596 @@
+//! Bookkeeping of the tasks in progress, so that they can be cancelled and their place in the queue is known.
+//! Optionally, the tasks are persisted on disk, so that they survive restarts.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use async_lock::{Semaphore, SemaphoreGuardArc};
+use teloxide::types::{ChatId, Message, UserId};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::utils::{self, Media, Section};
+
+/// Identifies who requested a task (the user is unknown in channels).
+pub type Owner = (ChatId, Option<UserId>);
+
+/// Tasks of each owner, along with their identifiers, in order of registration.
+type Registry = HashMap<Owner, Vec<(u64, CancellationToken)>>;
+
+static TASKS: OnceLock<Mutex<Registry>> = OnceLock::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static ABANDONED: Mutex<Option<HashSet<ChatId>>> = Mutex::new(None);
+
+static WAITING: OnceLock<Mutex<VecDeque<Queued>>> = OnceLock::new();
+static RUNNING: Mutex<Vec<Queued>> = Mutex::new(Vec::new());
+static QUEUE_CHANGED: OnceLock<watch::Sender<()>> = OnceLock::new();
+static MAX_QUEUE_SIZE: OnceLock<usize> = OnceLock::new();
+
+static SHUTDOWN: OnceLock<CancellationToken> = OnceLock::new();
+
+static FAILED: OnceLock<Mutex<HashMap<Owner, Failed>>> = OnceLock::new();
+
+static DOMAINS: OnceLock<Mutex<HashMap<String, Arc<Semaphore>>>> = OnceLock::new();
+static MAX_DOWNLOADS_PER_DOMAIN: OnceLock<usize> = OnceLock::new();
+
+/// How long failed downloads can be retried for.
+const RETRY_TTL: Duration = Duration::from_hours(1);
+
+/// Maximum number of failed downloads remembered for retrying.
+const MAX_FAILED: usize = 1000;
+
+static STATE_PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+static PERSISTED: OnceLock<Mutex<BTreeMap<u64, Message>>> = OnceLock::new();
+
+/// Initialises the task registry.
+pub fn init_statics() {
+    TASKS
+        .set(Mutex::new(HashMap::new()))
+        .expect("TASKS was already initialised");
+
+    WAITING
+        .set(Mutex::new(VecDeque::new()))
+        .expect("WAITING was already initialised");
+
+    QUEUE_CHANGED
+        .set(watch::Sender::new(()))
+        .expect("QUEUE_CHANGED was already initialised");
+
+    // 0 means the queue is unbounded
+    MAX_QUEUE_SIZE
+        .set(
+            std::env::var("MAX_QUEUE_SIZE")
+                .unwrap_or_else(|_| "100".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse MAX_QUEUE_SIZE, using default value");
+                    100
+                }),
+        )
+        .expect("MAX_QUEUE_SIZE was already initialised");
+
+    FAILED
+        .set(Mutex::new(HashMap::new()))
+        .expect("FAILED was already initialised");
+
+    DOMAINS
+        .set(Mutex::new(HashMap::new()))
+        .expect("DOMAINS was already initialised");
+
+    // a limit of zero would block every download
+    MAX_DOWNLOADS_PER_DOMAIN
+        .set(
+            std::env::var("MAX_DOWNLOADS_PER_DOMAIN")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse()
+                .ok()
+                .filter(|&x| x > 0)
+                .unwrap_or_else(|| {
+                    warn!("failed to parse MAX_DOWNLOADS_PER_DOMAIN, using default value");
+                    1
+                }),
+        )
+        .expect("MAX_DOWNLOADS_PER_DOMAIN was already initialised");
+
+    SHUTDOWN
+        .set(CancellationToken::new())
+        .expect("SHUTDOWN was already initialised");
+
+    STATE_PATH
+        .set(
+            std::env::var("QUEUE_STATE_PATH")
+                .ok()
+                .filter(|x| !x.trim().is_empty())
+                .map(PathBuf::from),
+        )
+        .expect("STATE_PATH was already initialised");
+
+    PERSISTED
+        .set(Mutex::new(BTreeMap::new()))
+        .expect("PERSISTED was already initialised");
+}
+
+/// Writes the messages which requested the persisted tasks to `QUEUE_STATE_PATH`.
+fn save(persisted: &BTreeMap<u64, Message>) {
+    let Some(path) = STATE_PATH.get().expect("STATE_PATH is not initialised") else {
+        return;
+    };
+
+    let messages = persisted.values().collect::<Vec<_>>();
+
+    match serde_json::to_vec(&messages) {
+        Ok(json) => {
+            if let Err(e) = std::fs::write(path, json) {
+                error!("failed to save the queue state: {}", e);
+            }
+        }
+        Err(e) => error!("failed to serialise the queue state: {}", e),
+    }
+}
+
+/// Takes the messages which requested the tasks persisted before the restart.
+pub fn take_persisted() -> Vec<Message> {
+    let Some(path) = STATE_PATH.get().expect("STATE_PATH is not initialised") else {
+        return Vec::new();
+    };
+
+    // the file doesn't exist on the first run
+    let messages = std::fs::read(path).map_or_else(
+        |_| Vec::new(),
+        |json| {
+            serde_json::from_slice(&json).unwrap_or_else(|e| {
+                error!("failed to parse the queue state: {}", e);
+                Vec::new()
+            })
+        },
+    );
+
+    // the tasks are persisted again once they are registered
+    save(&BTreeMap::new());
+
+    messages
+}
+
+/// Returns the owner of a task requested by the message.
+pub fn owner(message: &Message) -> Owner {
+    (message.chat.id, message.from().map(|u| u.id))
+}
+
+/// A task waiting for a worker (since it's been registered), or running (since it's been started).
+#[derive(Clone, Debug)]
+pub struct Queued {
+    id: u64,
+    pub url: String,
+    pub chat_id: ChatId,
+    pub since: Instant,
+}
+
+/// A registered task, removed from the registry when dropped.
+pub struct Task {
+    owner: Owner,
+    id: u64,
+    token: CancellationToken,
+}
+
+impl Task {
+    /// Registers a new task requested by the message, unless the queue is full.
+    pub fn register(message: &Message, url: &str) -> Option<Self> {
+        Self::new(owner(message), message.chat.id, url, Some(message))
+    }
+
+    /// Registers a new task requested by an inline query, unless the queue is full.
+    /// The task belongs to the private chat of the user (so that it can be cancelled there),
+    /// and it isn't persisted, as the query can't be answered after a restart anyway.
+    pub fn register_inline(user_id: UserId, url: &str) -> Option<Self> {
+        let chat_id = ChatId::from(user_id);
+
+        Self::new((chat_id, Some(user_id)), chat_id, url, None)
+    }
+
+    /// Registers a new task, persisting the message which requested it (if there's one).
+    fn new(owner: Owner, chat_id: ChatId, url: &str, message: Option<&Message>) -> Option<Self> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let token = CancellationToken::new();
+
+        // the capacity is checked while holding the lock, so that it can't be exceeded
+        let mut waiting = WAITING
+            .get()
+            .expect("WAITING is not initialised")
+            .lock()
+            .unwrap();
+
+        if queue_capacity().is_some_and(|capacity| waiting.len() >= capacity) {
+            return None;
+        }
+
+        // every task waits in the queue until it gets a worker
+        waiting.push_back(Queued {
+            id,
+            url: url.to_string(),
+            chat_id,
+            since: Instant::now(),
+        });
+        drop(waiting);
+        notify_queue_changed();
+
+        TASKS
+            .get()
+            .expect("TASKS is not initialised")
+            .lock()
+            .unwrap()
+            .entry(owner)
+            .or_default()
+            .push((id, token.clone()));
+
+        if let (Some(message), true) = (message, is_persistent()) {
+            let mut persisted = PERSISTED
+                .get()
+                .expect("PERSISTED is not initialised")
+                .lock()
+                .unwrap();
+
+            persisted.insert(id, message.clone());
+            save(&persisted);
+            drop(persisted);
+        }
+
+        Some(Self { owner, id, token })
+    }
+
+    /// Returns the token which is cancelled when the owner cancels the task.
+    pub const fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Marks the task as started, moving it from the queue to the running tasks.
+    pub fn start(&self) {
+        if let Some(queued) = leave_queue(self.id) {
+            RUNNING.lock().unwrap().push(Queued {
+                since: Instant::now(),
+                ..queued
+            });
+        }
+    }
+
+    /// Returns the position of the task in the queue (starting at 1),
+    /// or `None` if it has already started.
+    pub fn position(&self) -> Option<usize> {
+        WAITING
+            .get()
+            .expect("WAITING is not initialised")
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|queued| queued.id == self.id)
+            .map(|index| index + 1)
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        let mut tasks = TASKS
+            .get()
+            .expect("TASKS is not initialised")
+            .lock()
+            .unwrap();
+
+        if let Some(owned) = tasks.get_mut(&self.owner) {
+            owned.retain(|(id, _)| *id != self.id);
+
+            if owned.is_empty() {
+                tasks.remove(&self.owner);
+            }
+        }
+
+        drop(tasks);
+
+        // tasks cancelled while waiting leave the queue as well
+        leave_queue(self.id);
+        RUNNING
+            .lock()
+            .unwrap()
+            .retain(|running| running.id != self.id);
+
+        // tasks interrupted by the shutdown are resumed after the restart
+        if is_shutting_down() {
+            return;
+        }
+
+        let mut persisted = PERSISTED
+            .get()
+            .expect("PERSISTED is not initialised")
+            .lock()
+            .unwrap();
+
+        if persisted.remove(&self.id).is_some() {
+            save(&persisted);
+        }
+    }
+}
+
+/// Removes a task from the queue, notifying the tasks behind it.
+/// Returns the task, unless it had already left the queue.
+fn leave_queue(id: u64) -> Option<Queued> {
+    let mut waiting = WAITING
+        .get()
+        .expect("WAITING is not initialised")
+        .lock()
+        .unwrap();
+
+    let queued = waiting
+        .iter()
+        .position(|queued| queued.id == id)
+        .and_then(|index| waiting.remove(index));
+
+    drop(waiting);
+
+    if queued.is_some() {
+        notify_queue_changed();
+    }
+
+    queued
+}
+
+/// Notifies the waiting tasks that their positions in the queue might have changed.
+fn notify_queue_changed() {
+    QUEUE_CHANGED
+        .get()
+        .expect("QUEUE_CHANGED is not initialised")
+        .send_replace(());
+}
+
+/// Returns the number of tasks waiting for a worker.
+pub fn queue_length() -> usize {
+    WAITING
+        .get()
+        .expect("WAITING is not initialised")
+        .lock()
+        .unwrap()
+        .len()
+}
+
+/// Returns the tasks waiting for a worker, in order.
+pub fn queued() -> Vec<Queued> {
+    WAITING
+        .get()
+        .expect("WAITING is not initialised")
+        .lock()
+        .unwrap()
+        .iter()
+        .cloned()
+        .collect()
+}
+
+/// Returns the tasks which have been started, in order.
+pub fn running() -> Vec<Queued> {
+    RUNNING.lock().unwrap().clone()
+}
+
+/// Returns the maximum number of tasks waiting for a worker, if it's limited.
+pub fn queue_capacity() -> Option<usize> {
+    Some(
+        *MAX_QUEUE_SIZE
+            .get()
+            .expect("MAX_QUEUE_SIZE is not initialised"),
+    )
+    .filter(|x| *x > 0)
+}
+
+/// Returns a receiver which is notified whenever the queue changes.
+pub fn queue_changes() -> watch::Receiver<()> {
+    QUEUE_CHANGED
+        .get()
+        .expect("QUEUE_CHANGED is not initialised")
+        .subscribe()
+}
+
+/// Cancels the most recent task of the owner, returning whether there was one.
+pub fn cancel_latest(owner: Owner) -> bool {
+    let tasks = TASKS
+        .get()
+        .expect("TASKS is not initialised")
+        .lock()
+        .unwrap();
+
+    // tasks which were already cancelled are skipped
+    tasks
+        .get(&owner)
+        .and_then(|owned| owned.iter().rev().find(|(_, t)| !t.is_cancelled()))
+        .map(|(_, token)| token.cancel())
+        .is_some()
+}
+
+/// Cancels the tasks of a chat the bot can no longer send anything to
+/// (e.g. because the user blocked it), returning how many were still running.
+pub fn abandon_chat(chat_id: ChatId) -> usize {
+    ABANDONED
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashSet::new)
+        .insert(chat_id);
+
+    TASKS
+        .get()
+        .expect("TASKS is not initialised")
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|((chat, _), _)| *chat == chat_id)
+        .flat_map(|(_, owned)| owned)
+        .filter(|(_, token)| !token.is_cancelled())
+        .inspect(|(_, token)| token.cancel())
+        .count()
+}
+
+/// Marks a chat as reachable again (e.g. because the user unblocked the bot).
+pub fn restore_chat(chat_id: ChatId) {
+    if let Some(abandoned) = ABANDONED.lock().unwrap().as_mut() {
+        abandoned.remove(&chat_id);
+    }
+}
+
+/// Returns whether the bot can no longer send anything to a chat, see `abandon_chat`.
+pub fn is_abandoned(chat_id: ChatId) -> bool {
+    ABANDONED
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|abandoned| abandoned.contains(&chat_id))
+}
+
+/// Counts the tasks requested by the user, in all chats.
+pub fn count_for_user(user_id: UserId) -> usize {
+    TASKS
+        .get()
+        .expect("TASKS is not initialised")
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|((_, user), _)| *user == Some(user_id))
+        .map(|(_, owned)| owned.len())
+        .sum()
+}
+
+/// Waits until another download from the site of a URL is allowed, as downloading a lot
+/// from a single site at once can get the server banned. The permit is released once it's dropped.
+/// URLs without a host aren't limited.
+pub async fn acquire_domain(url: &str) -> Option<SemaphoreGuardArc> {
+    let host = url::Url::parse(url).ok()?.host_str()?.to_string();
+    let domain = utils::registrable_domain(&host);
+
+    let semaphore = DOMAINS
+        .get()
+        .expect("DOMAINS is not initialised")
+        .lock()
+        .unwrap()
+        .entry(domain)
+        .or_insert_with(|| {
+            Arc::new(Semaphore::new(
+                *MAX_DOWNLOADS_PER_DOMAIN
+                    .get()
+                    .expect("MAX_DOWNLOADS_PER_DOMAIN is not initialised"),
+            ))
+        })
+        .clone();
+
+    Some(semaphore.acquire_arc().await)
+}
+
+/// Counts all the registered tasks, whether they're waiting or in progress.
+pub fn count() -> usize {
+    TASKS
+        .get()
+        .expect("TASKS is not initialised")
+        .lock()
+        .unwrap()
+        .values()
+        .map(Vec::len)
+        .sum()
+}
+
+/// A download which failed, remembered so that its owner can `/retry` it.
+#[derive(Clone, Debug)]
+pub struct Failed {
+    pub url: String,
+    pub media: Media,
+    pub max_height: Option<u32>,
+    pub section: Option<Section>,
+    pub spoiler: bool,
+    at: Instant,
+}
+
+impl Failed {
+    /// Describes a download which has just failed.
+    pub fn new(
+        url: &str,
+        media: Media,
+        max_height: Option<u32>,
+        section: Option<Section>,
+        spoiler: bool,
+    ) -> Self {
+        Self {
+            url: url.to_string(),
+            media,
+            max_height,
+            section,
+            spoiler,
+            at: Instant::now(),
+        }
+    }
+}
+
+/// Remembers the latest failed download of the owner, forgetting the oldest one if there are too many.
+pub fn remember_failed(owner: Owner, failed: Failed) {
+    let mut remembered = FAILED
+        .get()
+        .expect("FAILED is not initialised")
+        .lock()
+        .unwrap();
+
+    remembered.retain(|_, f| f.at.elapsed() <= RETRY_TTL);
+
+    if remembered.len() >= MAX_FAILED && !remembered.contains_key(&owner) {
+        let oldest = remembered
+            .iter()
+            .min_by_key(|(_, f)| f.at)
+            .map(|(owner, _)| *owner);
+
+        if let Some(oldest) = oldest {
+            remembered.remove(&oldest);
+        }
+    }
+
+    remembered.insert(owner, failed);
+    drop(remembered);
+}
+
+/// Takes the latest failed download of the owner, unless it failed too long ago.
+pub fn take_failed(owner: Owner) -> Option<Failed> {
+    FAILED
+        .get()
+        .expect("FAILED is not initialised")
+        .lock()
+        .unwrap()
+        .remove(&owner)
+        .filter(|f| f.at.elapsed() <= RETRY_TTL)
+}
+
+/// Returns whether the tasks are saved to `QUEUE_STATE_PATH`.
+pub fn is_persistent() -> bool {
+    STATE_PATH
+        .get()
+        .expect("STATE_PATH is not initialised")
+        .is_some()
+}
+
+/// Returns the token which is cancelled once the bot starts shutting down.
+/// Tasks still waiting in the queue should stop waiting then.
+pub fn shutdown_token() -> &'static CancellationToken {
+    SHUTDOWN.get().expect("SHUTDOWN is not initialised")
+}
+
+/// Returns whether the bot is shutting down.
+pub fn is_shutting_down() -> bool {
+    shutdown_token().is_cancelled()
+}
+
+/// Cancels all the tasks, returning how many were still running.
+pub fn cancel_all() -> usize {
+    TASKS
+        .get()
+        .expect("TASKS is not initialised")
+        .lock()
+        .unwrap()
+        .values()
+        .flatten()
+        .filter(|(_, token)| !token.is_cancelled())
+        .inspect(|(_, token)| token.cancel())
+        .count()
+}
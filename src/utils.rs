@@ -1,28 +1,453 @@
 //! Utility functions used throughout the project.
 
-use std::{ops::Div, sync::OnceLock};
+use std::{
+    fmt,
+    io::Write,
+    ops::Div,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
 
-use async_process::Command;
+use async_process::{Command, Stdio};
+use futures_lite::{io::BufReader, AsyncBufReadExt, AsyncReadExt, StreamExt};
 use linkify::{LinkFinder, LinkKind};
 use rand::{distributions::Alphanumeric, Rng};
+use sysinfo::{Disk, Disks, Process, ProcessesToUpdate, System};
 use teloxide::types::InputFile;
+use tempfile::{TempDir, TempPath};
+use tokio::io::AsyncWriteExt;
 use url::Url;
 
 pub static WHITELIST: OnceLock<Vec<String>> = OnceLock::new();
+static DENYLIST: OnceLock<Vec<String>> = OnceLock::new();
+pub static COOKIES_FILE: OnceLock<Option<String>> = OnceLock::new();
+static COOKIES_TEMP_FILE: Mutex<Option<TempPath>> = Mutex::new(None);
+static COOKIES_FROM_BROWSER: OnceLock<Option<String>> = OnceLock::new();
+pub static PROXY_URL: OnceLock<Option<String>> = OnceLock::new();
+pub static PROXY_HOSTS: OnceLock<Vec<(String, String)>> = OnceLock::new();
+pub static PROXY_POOL: OnceLock<Vec<String>> = OnceLock::new();
+static HOST_ARGS: OnceLock<Vec<(String, Vec<String>)>> = OnceLock::new();
+static CREDENTIALS: OnceLock<Vec<(String, Credentials)>> = OnceLock::new();
+static NEXT_PROXY: AtomicUsize = AtomicUsize::new(0);
+static SUBTITLE_LANGS: OnceLock<Option<String>> = OnceLock::new();
+static SPONSORBLOCK_REMOVE: OnceLock<Option<String>> = OnceLock::new();
+static FORMAT_SORT: OnceLock<Option<String>> = OnceLock::new();
+static OUTPUT_TEMPLATE: OnceLock<String> = OnceLock::new();
+static DOWNLOAD_RETRIES: OnceLock<u32> = OnceLock::new();
+static RESUME_DOWNLOADS: OnceLock<bool> = OnceLock::new();
+static SHORTENERS: OnceLock<Vec<String>> = OnceLock::new();
+pub static UPLOAD_LIMIT: OnceLock<u32> = OnceLock::new();
+pub static VIDEO_CODEC: OnceLock<String> = OnceLock::new();
+static PRESET: OnceLock<Option<String>> = OnceLock::new();
+pub static AUDIO_BITRATE: OnceLock<u32> = OnceLock::new();
+static COPY_AUDIO: OnceLock<bool> = OnceLock::new();
+static MAX_OUTPUT_HEIGHT: OnceLock<Option<u32>> = OnceLock::new();
+static WORK_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+static THUMBNAILS: OnceLock<Option<async_lock::Semaphore>> = OnceLock::new();
 
+/// The yt-dlp output template of the downloaded files, relative to the directory of the task.
+const DEFAULT_OUTPUT_TEMPLATE: &str = "%(id)s.%(ext)s";
+
+/// Maximum number of redirects followed when resolving a shortened URL.
+const MAX_REDIRECTS: usize = 5;
+
+/// Delay before the first retry of a failed download; doubled after every attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Extensions of the subtitle files written by yt-dlp.
+pub const SUBTITLE_EXTENSIONS: [&str; 3] = ["srt", "vtt", "ass"];
+
+/// Videos shorter than this (in seconds) get their first frame as the thumbnail.
+const MIN_THUMBNAIL_SEEK_DURATION: u32 = 5;
+
+/// Codecs ffprobe reports for the video streams of images.
+const STILL_IMAGE_CODECS: [&str; 5] = ["mjpeg", "png", "webp", "bmp", "tiff"];
+
+/// Extensions of the unfinished files written by yt-dlp, which are kept to resume the download.
+pub const PARTIAL_EXTENSIONS: [&str; 2] = ["part", "ytdl"];
+
+/// Extensions of the other files yt-dlp may write alongside the media
+/// (descriptions, info JSON files and annotations), which are never sent.
+pub const SIDECAR_EXTENSIONS: [&str; 3] = ["description", "json", "xml"];
+
+/// Extensions of the direct links to media files, which are downloaded without yt-dlp.
+const DIRECT_EXTENSIONS: [&str; 4] = ["mp4", "webm", "mov", "gif"];
+
+/// Extensions of the thumbnails written by yt-dlp.
+pub const THUMBNAIL_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "webp", "png"];
+
+/// Reads an optional environment variable, treating empty values as unset.
+pub fn optional_var(key: &str) -> Option<String> {
+    std::env::var(key)
+        .map(|x| x.trim().to_string())
+        .ok()
+        .filter(|x| !x.is_empty())
+}
+
+#[allow(clippy::too_many_lines)] // sorry
 /// Initialise the whitelist of websites to allow downloads from.
 /// Format: `site1.com,site2.net,site3.edu`.
+/// Also initialises the optional yt-dlp and ffmpeg settings.
 pub fn init_statics() {
     WHITELIST
         .set(
             std::env::var("WHITELIST")
                 .expect("WHITELIST environment variable not set")
                 .split(',')
-                .map(|s| s.trim().to_string())
+                .map(|s| s.trim().to_lowercase())
                 .filter(|s| !s.is_empty())
                 .collect(),
         )
         .expect("WHITELIST was already initialised");
+
+    // entries such as `co.uk` or `github.io` would allow every site under them
+    for entry in WHITELIST.get().expect("WHITELIST is not initialised") {
+        if is_public_suffix(entry) {
+            warn!("WHITELIST entry {} is a public suffix", entry);
+        }
+    }
+
+    // the denylist is optional and takes precedence over the whitelist
+    DENYLIST
+        .set(
+            std::env::var("DENYLIST")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        )
+        .expect("DENYLIST was already initialised");
+
+    // the cookies can also be given directly, e.g. when mounting a file isn't an option
+    let cookies_content = std::env::var("COOKIES_CONTENT")
+        .ok()
+        .filter(|x| !x.trim().is_empty());
+
+    let cookies_file = match (optional_var("COOKIES_FILE"), cookies_content) {
+        (Some(path), Some(_)) => {
+            warn!("both COOKIES_FILE and COOKIES_CONTENT are set, ignoring COOKIES_CONTENT");
+            Some(path)
+        }
+        (Some(path), None) => Some(path),
+        (None, Some(content)) => Some(write_cookies(&content)),
+        (None, None) => None,
+    };
+
+    COOKIES_FILE
+        .set(cookies_file)
+        .expect("COOKIES_FILE was already initialised");
+
+    COOKIES_FROM_BROWSER
+        .set(optional_var("COOKIES_FROM_BROWSER"))
+        .expect("COOKIES_FROM_BROWSER was already initialised");
+
+    PROXY_URL
+        .set(optional_var("PROXY_URL"))
+        .expect("PROXY_URL was already initialised");
+
+    // format: `site1.com=socks5://127.0.0.1:1080,site2.net=http://proxy:8080`
+    PROXY_HOSTS
+        .set(
+            std::env::var("PROXY_HOSTS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|s| s.split_once('='))
+                .map(|(host, proxy)| (host.trim().to_string(), proxy.trim().to_string()))
+                .filter(|(host, proxy)| !host.is_empty() && !proxy.is_empty())
+                .collect(),
+        )
+        .expect("PROXY_HOSTS was already initialised");
+
+    // format: `youtube.com=--extractor-args youtube:player_client=android;site2.net=--flag`
+    // (separated with semicolons, as the arguments might contain commas)
+    HOST_ARGS
+        .set(
+            std::env::var("YTDLP_HOST_ARGS")
+                .unwrap_or_default()
+                .split(';')
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| {
+                    let (host, args) = s
+                        .split_once('=')
+                        .unwrap_or_else(|| panic!("invalid YTDLP_HOST_ARGS entry: {s}"));
+
+                    let host = host.trim().to_lowercase();
+                    let args = args
+                        .split_whitespace()
+                        .map(str::to_string)
+                        .collect::<Vec<_>>();
+
+                    assert!(
+                        !host.is_empty() && args.first().is_some_and(|a| a.starts_with('-')),
+                        "invalid YTDLP_HOST_ARGS entry: {s}"
+                    );
+
+                    (host, args)
+                })
+                .collect(),
+        )
+        .expect("HOST_ARGS was already initialised");
+
+    // the credentials can also be given directly, e.g. as a secret of a container
+    let credentials_content = std::env::var("SITE_CREDENTIALS")
+        .ok()
+        .filter(|x| !x.trim().is_empty());
+
+    let credentials = match (optional_var("SITE_CREDENTIALS_FILE"), credentials_content) {
+        (Some(path), content) => {
+            if content.is_some() {
+                warn!("both SITE_CREDENTIALS_FILE and SITE_CREDENTIALS are set, ignoring SITE_CREDENTIALS");
+            }
+
+            std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("failed to read SITE_CREDENTIALS_FILE: {e}"))
+        }
+        (None, Some(content)) => content,
+        (None, None) => String::new(),
+    };
+
+    CREDENTIALS
+        .set(parse_credentials(&credentials))
+        .expect("CREDENTIALS was already initialised");
+
+    // the files have to end up in the temporary directory of the task, so the template can't leave it
+    OUTPUT_TEMPLATE
+        .set(
+            std::env::var("OUTPUT_TEMPLATE")
+                .ok()
+                .filter(|x| !x.trim().is_empty())
+                .map_or_else(
+                    || DEFAULT_OUTPUT_TEMPLATE.to_string(),
+                    |x| {
+                        if is_valid_template(&x) {
+                            x
+                        } else {
+                            warn!("invalid OUTPUT_TEMPLATE, using default value");
+                            DEFAULT_OUTPUT_TEMPLATE.to_string()
+                        }
+                    },
+                ),
+        )
+        .expect("OUTPUT_TEMPLATE was already initialised");
+
+    // format: `socks5://127.0.0.1:1080,socks5://127.0.0.1:1081`
+    PROXY_POOL
+        .set(
+            std::env::var("PROXY_POOL")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        )
+        .expect("PROXY_POOL was already initialised");
+
+    // subtitles default to the original language (as reported by YouTube) and English
+    SUBTITLE_LANGS
+        .set(
+            std::env::var("EMBED_SUBTITLES")
+                .is_ok_and(|x| x.trim() == "true")
+                .then(|| {
+                    optional_var("SUBTITLE_LANGS").unwrap_or_else(|| ".*-orig,en.*".to_string())
+                }),
+        )
+        .expect("SUBTITLE_LANGS was already initialised");
+
+    SPONSORBLOCK_REMOVE
+        .set(optional_var("SPONSORBLOCK_REMOVE"))
+        .expect("SPONSORBLOCK_REMOVE was already initialised");
+
+    // e.g. `vcodec:h264,res,br`, see https://github.com/yt-dlp/yt-dlp#sorting-formats
+    FORMAT_SORT
+        .set(optional_var("FORMAT_SORT"))
+        .expect("FORMAT_SORT was already initialised");
+
+    DOWNLOAD_RETRIES
+        .set(
+            std::env::var("DOWNLOAD_RETRIES")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse DOWNLOAD_RETRIES, using default value");
+                    2
+                }),
+        )
+        .expect("DOWNLOAD_RETRIES was already initialised");
+
+    // thumbnails are made with ffmpeg, which low-powered hosts might want to spare
+    let generate_thumbnails = std::env::var("GENERATE_THUMBNAILS")
+        .unwrap_or_else(|_| "true".to_string())
+        .parse()
+        .unwrap_or_else(|_| {
+            warn!("failed to parse GENERATE_THUMBNAILS, using default value");
+            true
+        });
+
+    let max_thumbnails = std::env::var("MAX_CONCURRENT_THUMBNAILS")
+        .unwrap_or_else(|_| "2".to_string())
+        .parse()
+        .ok()
+        .filter(|&x| x > 0)
+        .unwrap_or_else(|| {
+            warn!("failed to parse MAX_CONCURRENT_THUMBNAILS, using default value");
+            2
+        });
+
+    THUMBNAILS
+        .set(generate_thumbnails.then(|| async_lock::Semaphore::new(max_thumbnails)))
+        .expect("THUMBNAILS was already initialised");
+
+    RESUME_DOWNLOADS
+        .set(
+            std::env::var("RESUME_DOWNLOADS")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse RESUME_DOWNLOADS, using default value");
+                    false
+                }),
+        )
+        .expect("RESUME_DOWNLOADS was already initialised");
+
+    // links from these hosts are resolved before they're checked against the whitelist
+    SHORTENERS
+        .set(
+            std::env::var("SHORTENERS")
+                .unwrap_or_else(|_| "t.co,bit.ly,tinyurl.com,is.gd,ow.ly,buff.ly".to_string())
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        )
+        .expect("SHORTENERS was already initialised");
+
+    // the public Bot API limits uploads to 50 MB, a local Bot API server allows for up to 2 GB
+    UPLOAD_LIMIT
+        .set(
+            std::env::var("TELEGRAM_UPLOAD_LIMIT_MB")
+                .unwrap_or_else(|_| "50".to_string())
+                .parse()
+                .ok()
+                .filter(|&x| x > 0)
+                .unwrap_or_else(|| {
+                    warn!("failed to parse TELEGRAM_UPLOAD_LIMIT_MB, using default value");
+                    50
+                }),
+        )
+        .expect("UPLOAD_LIMIT was already initialised");
+
+    // e.g. `h264_nvenc` for hardware encoding, or `libx265` for better compression
+    VIDEO_CODEC
+        .set(optional_var("FFMPEG_VIDEO_CODEC").unwrap_or_else(|| "libx264".to_string()))
+        .expect("VIDEO_CODEC was already initialised");
+
+    PRESET
+        .set(optional_var("FFMPEG_PRESET"))
+        .expect("PRESET was already initialised");
+
+    // the bitrate is in kbps, with an optional `k` suffix (as in ffmpeg)
+    AUDIO_BITRATE
+        .set(
+            std::env::var("FFMPEG_AUDIO_BITRATE")
+                .unwrap_or_else(|_| "128".to_string())
+                .trim()
+                .trim_end_matches('k')
+                .parse()
+                .ok()
+                .filter(|&x| x > 0)
+                .unwrap_or_else(|| {
+                    warn!("failed to parse FFMPEG_AUDIO_BITRATE, using default value");
+                    128
+                }),
+        )
+        .expect("AUDIO_BITRATE was already initialised");
+
+    COPY_AUDIO
+        .set(
+            std::env::var("COPY_AUDIO")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .unwrap_or_else(|_| {
+                    warn!("failed to parse COPY_AUDIO, using default value");
+                    false
+                }),
+        )
+        .expect("COPY_AUDIO was already initialised");
+
+    // taller videos are scaled down while converting, which makes them smaller and faster to encode
+    MAX_OUTPUT_HEIGHT
+        .set(optional_var("MAX_OUTPUT_HEIGHT").and_then(|x| {
+            let height = x.parse().ok().filter(|&x: &u32| x > 0);
+
+            if height.is_none() {
+                warn!("failed to parse MAX_OUTPUT_HEIGHT, videos aren't scaled down");
+            }
+
+            height
+        }))
+        .expect("MAX_OUTPUT_HEIGHT was already initialised");
+
+    WORK_DIR
+        .set(optional_var("WORK_DIR").map(PathBuf::from))
+        .expect("WORK_DIR was already initialised");
+
+    // better to find out now than on the first download
+    if let Some(dir) = WORK_DIR.get().expect("WORK_DIR is not initialised") {
+        assert!(
+            dir.is_dir(),
+            "WORK_DIR {} is not a directory",
+            dir.display()
+        );
+
+        temp_dir().unwrap_or_else(|e| panic!("WORK_DIR {} is not writable: {e}", dir.display()));
+    }
+}
+
+/// Writes the cookies from `COOKIES_CONTENT` to a temporary file, returning its path.
+/// The file is only readable by the bot (the content itself is never logged).
+fn write_cookies(content: &str) -> String {
+    let mut file = tempfile::Builder::new()
+        .prefix("cookies")
+        .suffix(".txt")
+        .tempfile()
+        .expect("failed to create the cookie file");
+
+    file.write_all(content.as_bytes())
+        .and_then(|()| file.flush())
+        .expect("failed to write the cookie file");
+
+    let path = file.into_temp_path();
+    let path_str = path.to_string_lossy().to_string();
+
+    info!("wrote the cookies from COOKIES_CONTENT to {}", path_str);
+    *COOKIES_TEMP_FILE.lock().unwrap() = Some(path);
+
+    path_str
+}
+
+/// Removes the cookie file written from `COOKIES_CONTENT`, if there is one.
+pub fn remove_cookies() {
+    let path = COOKIES_TEMP_FILE.lock().unwrap().take();
+
+    if let Some(Err(e)) = path.map(TempPath::close) {
+        warn!("failed to remove the cookie file: {}", e);
+    }
+}
+
+/// Creates a temporary directory for a download in `WORK_DIR` (or the system temp directory).
+/// The directory is removed once it's dropped.
+pub fn temp_dir() -> std::io::Result<TempDir> {
+    WORK_DIR
+        .get()
+        .expect("WORK_DIR is not initialised")
+        .as_ref()
+        .map_or_else(tempfile::tempdir, tempfile::tempdir_in)
 }
 
 /// Obtain a random string of specified length.
@@ -37,12 +462,159 @@ pub fn random_string(size: usize) -> String {
 /// Information about URLs found in a message.
 pub struct URLInfo {
     pub maybe_url: Option<String>,
+    /// All the whitelisted URLs, in order.
+    pub urls: Vec<String>,
     pub total_urls: usize,
     pub whitelisted_urls: usize,
+    pub denied_urls: usize,
+}
+
+/// Extracts the registrable domain of a host using the public suffix list,
+/// e.g. `www.bbc.co.uk` -> `bbc.co.uk`. IP addresses and unknown hosts are returned as-is.
+pub fn registrable_domain(host: &str) -> String {
+    let host = host.trim_matches(['[', ']']).to_lowercase();
+
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return host;
+    }
+
+    psl::domain_str(&host).map_or_else(|| host.clone(), std::string::ToString::to_string)
+}
+
+/// Checks whether a whitelist entry (optionally prefixed with `*.`) is a public suffix, such as `co.uk`.
+/// IP addresses and single-label hosts (e.g. `localhost`) aren't under any suffix, so they're let through,
+/// unless the label is a top-level domain, such as `com`.
+fn is_public_suffix(entry: &str) -> bool {
+    let host = entry.trim_start_matches("*.").to_lowercase();
+
+    if host
+        .trim_matches(['[', ']'])
+        .parse::<std::net::IpAddr>()
+        .is_ok()
+    {
+        return false;
+    }
+
+    if !host.contains('.') {
+        return psl::suffix(host.as_bytes()).is_some_and(|suffix| suffix.is_known());
+    }
+
+    psl::domain_str(&host).is_none()
+}
+
+/// Checks whether a host matches a whitelist / denylist entry.
+/// Entries match their subdomains too, e.g. `youtube.com` matches `www.youtube.com`;
+/// a leading wildcard (e.g. `*.example.org`) matches the subdomains only.
+pub fn host_matches(host: &str, entry: &str) -> bool {
+    let host = host.to_lowercase();
+    let entry = entry.to_lowercase();
+
+    entry.strip_prefix("*.").map_or_else(
+        || host == entry || host.ends_with(&format!(".{entry}")),
+        |domain| host.ends_with(&format!(".{domain}")),
+    )
+}
+
+/// Checks whether a host is denied.
+fn is_denied(host: &str) -> bool {
+    DENYLIST
+        .get()
+        .expect("DENYLIST not initialised")
+        .iter()
+        .any(|d| host_matches(host, d))
 }
 
-/// Parses a message and returns information about URLs found in it.
-pub fn get_url_info(msg: &str) -> URLInfo {
+/// Checks whether an URL points to one of the known URL shorteners.
+fn is_shortened(url: &Url) -> bool {
+    url.host_str().is_some_and(|host| {
+        SHORTENERS
+            .get()
+            .expect("SHORTENERS is not initialised")
+            .iter()
+            .any(|s| host_matches(host, s))
+    })
+}
+
+/// Follows the redirects of a shortened URL (up to `MAX_REDIRECTS` of them).
+/// Returns `None` if the URL couldn't be resolved.
+async fn resolve_redirects(url: &Url) -> Option<Url> {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(Duration::from_secs(10))
+        .build()
+        .ok()?;
+
+    let mut current = url.clone();
+
+    // stop as soon as the URL leaves the shorteners, it'll be checked against the whitelist
+    for _ in 0..MAX_REDIRECTS {
+        if !is_shortened(&current) {
+            return Some(current);
+        }
+
+        let response = client.head(current.clone()).send().await.ok()?;
+
+        if !response.status().is_redirection() {
+            return None;
+        }
+
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)?
+            .to_str()
+            .ok()?;
+
+        current = current.join(location).ok()?;
+    }
+
+    (!is_shortened(&current)).then_some(current)
+}
+
+/// Strips an URL down to its netloc and path, dropping the credentials, the query and the fragment,
+/// so that it can be shown without revealing too much.
+pub fn sanitize_url(url: &str) -> String {
+    Url::parse(url).map_or_else(
+        |_| "(invalid URL)".to_string(),
+        |url| {
+            format!(
+                "{}{}",
+                url.host_str().unwrap_or_default(),
+                url.path().trim_end_matches('/')
+            )
+        },
+    )
+}
+
+/// Replaces the shortened URLs in a message with the URLs they redirect to,
+/// so that they can be checked against the whitelist.
+/// URLs which can't be resolved are left as they are.
+pub async fn resolve_shortened_urls(msg: &str) -> String {
+    let mut finder = LinkFinder::new();
+    finder.kinds(&[LinkKind::Url]);
+
+    let shortened = finder
+        .links(msg)
+        .filter_map(|l| Url::parse(l.as_str()).ok().map(|u| (l.as_str(), u)))
+        .filter(|(_, u)| is_shortened(u))
+        .collect::<Vec<_>>();
+
+    let mut resolved = msg.to_string();
+
+    for (original, url) in shortened {
+        if let Some(target) = resolve_redirects(&url).await {
+            debug!("resolved {} to {}", original, target);
+            resolved = resolved.replace(original, target.as_str());
+        } else {
+            warn!("failed to resolve {}", original);
+        }
+    }
+
+    resolved
+}
+
+/// Parses a message and returns information about URLs found in it,
+/// checking them against the given whitelist.
+pub fn get_url_info(msg: &str, whitelist: &[String]) -> URLInfo {
     // create LinkFinder and initialise it with a proper config
     let mut finder = LinkFinder::new();
     finder.kinds(&[LinkKind::Url]);
@@ -67,206 +639,2421 @@ pub fn get_url_info(msg: &str) -> URLInfo {
     let netlocs = parsed_urls
         .into_iter()
         .map(|(i, u)| (i, u.host_str().map(std::string::ToString::to_string)))
-        .filter_map(|(i, u)| u.map(|u| (i, u)));
-
-    // split the netlocs into parts and extract the last two parts
-    // for example, vm.tiktok.com -> tiktok.com
-    let netloc_parts = netlocs.into_iter().map(|(&i, n)| {
-        let parts = n
-            .split('.')
-            .rev()
-            .take(2)
-            .collect::<Vec<_>>()
-            .iter()
-            .rev()
-            .map(std::string::ToString::to_string)
-            .collect::<Vec<_>>();
+        .filter_map(|(i, u)| u.map(|u| (i, u)))
+        .inspect(|(_, n)| debug!("found URL with netloc {}", registrable_domain(n)));
 
-        (i, parts)
-    });
+    // denied netlocs are rejected no matter what
+    let (denied, netlocs): (Vec<_>, Vec<_>) = netlocs.partition(|(_, n)| is_denied(n));
 
     // check the netlocs against the whitelist
-    let whitelist_items = netloc_parts.into_iter().map(|(i, n)| (i, n.join(".")));
-
-    let whitelisted_urls = whitelist_items
+    let whitelisted_urls = netlocs
         .into_iter()
-        .filter(|(_, w)| {
-            WHITELIST
-                .get()
-                .expect("WHITELIST not initialised")
-                .contains(w)
-        })
+        .filter(|(_, n)| whitelist.iter().any(|w| host_matches(n, w)))
         .collect::<Vec<_>>();
 
     let whitelisted_urls_len = whitelisted_urls.len();
 
     URLInfo {
         maybe_url: if whitelisted_urls_len == 1 {
-            let index = whitelisted_urls.first().unwrap().0;
+            let index = *whitelisted_urls.first().unwrap().0;
             let url = urls.get(index).unwrap().1;
             Some(url.to_string())
         } else {
             None
         },
+        urls: whitelisted_urls
+            .iter()
+            .map(|(i, _)| urls.get(**i).unwrap().1.to_string())
+            .collect(),
         total_urls: links_len,
         whitelisted_urls: whitelisted_urls_len,
+        denied_urls: denied.len(),
     }
 }
 
-/// Downloads a video from an URL in .mp4 format.
-pub async fn download(url: &str, dirname: &str) -> bool {
-    let mut command = Command::new("yt-dlp");
-    let yt_dlp = command.args([
-        "--no-playlist",
-        "--output",
-        &format!("{dirname}/%(id)s.%(ext)s"),
-        url,
-    ]);
+/// Query parameters which only track where a link has been shared.
+const TRACKING_PARAMS: [&str; 5] = ["si", "feature", "fbclid", "igshid", "ref"];
 
-    // run the command and wait for it to finish
-    yt_dlp
-        .status()
-        .await
-        .map_or(false, |status| status.success())
+/// Normalises an URL, so that different links to the same video are treated as one.
+/// The fragment, `www.` and tracking parameters are dropped.
+pub fn normalize_url(url: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+
+    parsed.set_fragment(None);
+
+    let query = parsed
+        .query_pairs()
+        .filter(|(k, _)| !k.starts_with("utm_") && !TRACKING_PARAMS.contains(&k.as_ref()))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect::<Vec<_>>();
+
+    if query.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    if let Some(host) = parsed
+        .host_str()
+        .and_then(|host| host.strip_prefix("www."))
+        .map(str::to_string)
+    {
+        parsed.set_host(Some(&host)).ok();
+    }
+
+    parsed.to_string()
 }
 
-/// Probe result.
-pub struct Probe {
-    pub duration: u32,
-    pub bitrate: u32,
-    pub width: u32,
-    pub height: u32,
+/// Checks whether an URL points to a playlist.
+pub fn is_playlist(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|u| {
+        u.query_pairs().any(|(k, _)| k == "list")
+            || u.path().contains("/playlist")
+            || u.path().contains("/sets/")
+    })
 }
 
-/// Implements a `Default` trait for `Probe`.
-impl Default for Probe {
-    fn default() -> Self {
-        Self {
-            duration: 0,
-            bitrate: 0,
-            width: 0,
-            height: 0,
-        }
+/// Kind of media to download.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Media {
+    Video,
+    Audio,
+}
+
+/// Options affecting what yt-dlp downloads.
+#[derive(Clone, Copy)]
+pub struct DownloadOptions {
+    /// Kind of media to download.
+    pub media: Media,
+    /// If specified, up to that many playlist items are downloaded.
+    pub playlist_end: Option<u32>,
+    /// If specified, the video is downloaded in at most that resolution (height in pixels).
+    pub max_height: Option<u32>,
+    /// If specified, only that part of the video is downloaded.
+    pub section: Option<Section>,
+    /// Whether the download is a `/retry`, which gets the larger `FALLBACK_MAX_FILESIZE`
+    /// and has its bitrate reduced to fit (even if `ALLOW_BITRATE_REDUCTION` is disabled).
+    pub fallback: bool,
+}
+
+/// Part of a video to download, in seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Section {
+    pub start: u32,
+    /// If unspecified, the section lasts until the end of the video.
+    pub end: Option<u32>,
+}
+
+impl Section {
+    /// Checks whether the section fits within a video of the given duration (in seconds).
+    pub fn fits(self, duration: Option<f64>) -> bool {
+        let ordered = self.end.is_none_or(|end| end > self.start);
+        let within = duration.is_none_or(|duration| {
+            f64::from(self.start) < duration
+                && self.end.is_none_or(|end| f64::from(end) <= duration.ceil())
+        });
+
+        ordered && within
+    }
+
+    /// Returns the length of the section of a video of the given duration (in seconds).
+    pub fn length(self, duration: f64) -> f64 {
+        let end = self
+            .end
+            .map_or(duration, |end| f64::from(end).min(duration));
+        (end - f64::from(self.start)).max(0.0)
+    }
+
+    /// Formats the section the way `--download-sections` expects it.
+    fn arg(self) -> String {
+        self.end.map_or_else(
+            || format!("*{}-inf", self.start),
+            |end| format!("*{}-{end}", self.start),
+        )
     }
 }
 
-/// Probes a video file for its duration, width and height.
-pub fn probe(path: &str) -> Option<Probe> {
-    match ffprobe::ffprobe(path) {
-        Ok(probe) => {
-            let streams = probe.streams;
-            let video_stream = streams
-                .iter()
-                .find(|&s| s.codec_type == Some("video".to_string()));
-
-            if let Some(video_stream) = video_stream {
-                let width = video_stream.width.unwrap_or(0);
-                let height = video_stream.height.unwrap_or(0);
-
-                let bitrate = u32::try_from(
-                    probe
-                        .format
-                        .bit_rate
-                        .clone()
-                        .unwrap_or_else(|| "0".to_string())
-                        .parse()
-                        .unwrap_or(0),
-                )
-                .unwrap_or(0)
-                .div(1000);
-
-                let duration = probe
-                    .format
-                    .try_get_duration()
-                    .and_then(std::result::Result::ok)
-                    //.map_or(0, |d| d.as_secs() as u32);
-                    .map_or(0, |d| u32::try_from(d.as_secs()).unwrap_or(0));
-
-                Some(Probe {
-                    duration,
-                    bitrate,
-                    width: u32::try_from(width).unwrap_or(0),
-                    height: u32::try_from(height).unwrap_or(0),
-                })
-            } else {
-                None
-            }
-        }
-        Err(_) => None,
+/// Parses a timestamp, like "90", "1:30" or "0:01:30", into seconds.
+pub fn parse_timestamp(s: &str) -> Option<u32> {
+    let parts = s.split(':').collect::<Vec<_>>();
+
+    if parts.len() > 3 || parts.iter().any(|p| p.is_empty()) {
+        return None;
     }
+
+    parts.iter().try_fold(0_u32, |total, part| {
+        let value = part.parse::<u32>().ok()?;
+        total.checked_mul(60)?.checked_add(value)
+    })
 }
 
-/// Converts a video to .mp4.
-pub async fn convert(input: &str, output: &str, bitrate: Option<u32>) -> bool {
-    // compose the ffmpeg command arguments
-    let mut args = vec![
-        "-y", // overwrite output files if they already exist
-        "-i", // input file
-        input,
-        "-c:v", // video codec
-        "libx264",
-        "-movflags", // faststart
-        "+faststart",
-        "-pix_fmt", // pixel format
-        "yuv420p",
-        "-b:a", // audio bitrate
-        "128k",
-        "-fs", // max filesize
-        "50M",
-        "-vf", // making sure the video dimensions are even
-        "crop=trunc(iw/2)*2:trunc(ih/2)*2",
-    ]
-    .into_iter()
-    .map(std::string::ToString::to_string)
-    .collect::<Vec<_>>();
+/// Parses a start time given in an URL, like "90", "90s" or "1h2m3s", into seconds.
+fn parse_start_param(s: &str) -> Option<u32> {
+    if let Ok(seconds) = s.parse() {
+        return Some(seconds);
+    }
 
-    // add bitrate if specified
-    if let Some(bitrate) = bitrate {
-        args.push("-b:v".to_string()); // video bitrate
-        args.push(format!("{bitrate}k"));
+    let mut total = 0_u32;
+    let mut number = String::new();
+
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+
+        total = total.checked_add(number.parse::<u32>().ok()?.checked_mul(unit)?)?;
+        number.clear();
     }
 
-    args.push(output.to_string());
+    number.is_empty().then_some(total)
+}
 
-    // create a new ffmpeg command
-    let mut command = Command::new("ffmpeg");
+/// Finds a start time in the URLs of a message (the `t` or `start` query parameter),
+/// so that links to a moment of a video are downloaded from that moment on.
+pub fn start_time(msg: &str) -> Option<Section> {
+    let mut finder = LinkFinder::new();
+    finder.kinds(&[LinkKind::Url]);
 
-    // run the command and wait for it to finish
-    command
-        .args(&args)
-        .status()
-        .await
-        .map_or(false, |status| status.success())
+    finder
+        .links(msg)
+        .filter_map(|l| Url::parse(l.as_str()).ok())
+        .find_map(|url| {
+            url.query_pairs()
+                .find(|(k, _)| k == "t" || k == "start")
+                .and_then(|(_, v)| parse_start_param(&v))
+        })
+        .filter(|start| *start > 0)
+        .map(|start| Section { start, end: None })
 }
 
-/// Extracts a thumbnail from a video, saving it as a .jpg file and returning its path.
-pub async fn get_thumbnail(video_path: &str) -> Option<InputFile> {
-    // get the parent folder of the video and construct the thumbnail path
-    let parent_folder = std::path::Path::new(video_path).parent();
-    let thumbnail_path = parent_folder
-        .map(|p| p.join("thumbnail.jpg"))
-        .map(|p| p.to_string_lossy().to_string())
-        .unwrap();
+/// Schemes of the proxies supported by yt-dlp.
+const PROXY_SCHEMES: [&str; 6] = ["http", "https", "socks4", "socks4a", "socks5", "socks5h"];
 
-    // create a new ffmpeg command
-    let exit_code = Command::new("ffmpeg")
-        .args([
-            "-y", // overwrite output files if they already exist
-            "-i", // input file
-            video_path,
-            "-vframes", // number of frames to output
-            "1",
-            "-q:v", // quality of the thumbnail (1-31)
-            "3",
-            &thumbnail_path,
-        ])
-        .status()
-        .await
-        .map(|s| s.success());
+/// Checks whether a proxy URL can be passed to yt-dlp.
+pub fn is_valid_proxy(proxy: &str) -> bool {
+    Url::parse(proxy).is_ok_and(|u| PROXY_SCHEMES.contains(&u.scheme()) && u.host_str().is_some())
+}
 
-    if matches!(exit_code, Ok(true)) {
-        Some(InputFile::file(thumbnail_path))
-    } else {
-        None
+/// Returns the proxy to download an URL through, if any.
+/// Proxies configured for specific hosts take precedence over `PROXY_POOL`,
+/// whose proxies are used in turns (`turn` picks one of them), which takes precedence over `PROXY_URL`.
+fn proxy_for(url: &str, turn: usize) -> Option<String> {
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string));
+
+    host.and_then(|host| {
+        PROXY_HOSTS
+            .get()
+            .expect("PROXY_HOSTS is not initialised")
+            .iter()
+            .find(|(h, _)| host_matches(&host, h))
+            .map(|(_, proxy)| proxy.clone())
+    })
+    .or_else(|| {
+        let pool = PROXY_POOL.get().expect("PROXY_POOL is not initialised");
+
+        (!pool.is_empty()).then(|| pool[turn % pool.len()].clone())
+    })
+    .or_else(|| {
+        PROXY_URL
+            .get()
+            .expect("PROXY_URL is not initialised")
+            .clone()
+    })
+}
+
+/// Checks whether an output template names a file in the directory of the task,
+/// i.e. it doesn't contain any path separators (nor `..`), and keeps the extension from yt-dlp,
+/// which the downloaded files are told apart by.
+fn is_valid_template(template: &str) -> bool {
+    !template.contains(['/', '\\'])
+        && !template.contains("..")
+        && template.ends_with(".%(ext)s")
+        && template != ".%(ext)s"
+}
+
+/// Returns the extra yt-dlp arguments configured for the host of an URL in `YTDLP_HOST_ARGS`.
+/// The hosts match their subdomains as well, like the whitelist entries.
+fn host_args(url: &str) -> Vec<String> {
+    let Some(host) = Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+    else {
+        return Vec::new();
+    };
+
+    let args = HOST_ARGS
+        .get()
+        .expect("HOST_ARGS is not initialised")
+        .iter()
+        .filter(|(h, _)| host_matches(&host, h))
+        .flat_map(|(_, args)| args.clone())
+        .collect::<Vec<_>>();
+
+    if !args.is_empty() {
+        debug!("using extra yt-dlp arguments for {}: {:?}", host, args);
+    }
+
+    args
+}
+
+/// A username and a password yt-dlp signs in to a site with.
+/// They're never logged, so `Debug` leaves them out.
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Credentials { .. }")
+    }
+}
+
+/// Parses the entries of `SITE_CREDENTIALS`, one per line, e.g. `media.example.org=user:password`.
+/// The password is everything after the first colon, so it can contain colons itself.
+/// Invalid entries are reported by their line number, so that no password ends up in the logs.
+fn parse_credentials(s: &str) -> Vec<(String, Credentials)> {
+    s.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let entry = line.trim().split_once('=').and_then(|(host, credentials)| {
+                let (username, password) = credentials.split_once(':')?;
+                let host = host.trim().to_lowercase();
+
+                (!host.is_empty() && !username.is_empty()).then(|| {
+                    let credentials = Credentials {
+                        username: username.to_string(),
+                        password: password.to_string(),
+                    };
+
+                    (host, credentials)
+                })
+            });
+
+            entry.unwrap_or_else(|| panic!("invalid site credentials entry on line {}", i + 1))
+        })
+        .collect()
+}
+
+/// Returns the credentials configured for the host of an URL in `SITE_CREDENTIALS`.
+/// The hosts match their subdomains as well, like the whitelist entries.
+fn credentials_for(url: &str) -> Option<&'static Credentials> {
+    let host = Url::parse(url).ok()?.host_str()?.to_string();
+
+    let credentials = CREDENTIALS
+        .get()
+        .expect("CREDENTIALS is not initialised")
+        .iter()
+        .find(|(h, _)| host_matches(&host, h))
+        .map(|(_, credentials)| credentials);
+
+    if credentials.is_some() {
+        debug!("signing in to {}", host);
+    }
+
+    credentials
+}
+
+/// Returns the yt-dlp arguments sorting the formats of a video by `FORMAT_SORT`,
+/// e.g. to prefer codecs which don't need to be re-encoded for Telegram.
+fn format_sort_args() -> Vec<String> {
+    FORMAT_SORT
+        .get()
+        .expect("FORMAT_SORT is not initialised")
+        .iter()
+        .flat_map(|sort| ["--format-sort".to_string(), sort.clone()])
+        .collect()
+}
+
+/// Returns yt-dlp arguments shared by all invocations for an URL.
+/// The lookups which don't download anything use the next proxy in the rotation (see `download`),
+/// without advancing it.
+fn common_args(url: &str) -> Vec<String> {
+    download_args(url, NEXT_PROXY.load(Ordering::Relaxed))
+}
+
+/// Returns yt-dlp arguments shared by all invocations for an URL, using the proxy picked by `turn`.
+fn download_args(url: &str, turn: usize) -> Vec<String> {
+    let mut args = host_args(url);
+
+    if let Some(proxy) = proxy_for(url, turn) {
+        args.extend(["--proxy".to_string(), proxy]);
+    }
+
+    // use cookies for age-restricted and login-gated videos
+    if let Some(path) = COOKIES_FILE.get().expect("COOKIES_FILE is not initialised") {
+        args.extend(["--cookies".to_string(), path.clone()]);
+    }
+
+    if let Some(browser) = COOKIES_FROM_BROWSER
+        .get()
+        .expect("COOKIES_FROM_BROWSER is not initialised")
+    {
+        args.extend(["--cookies-from-browser".to_string(), browser.clone()]);
+    }
+
+    // sign in to sites which require it, e.g. self-hosted media servers behind HTTP Basic auth
+    if let Some(credentials) = credentials_for(url) {
+        args.extend([
+            "--username".to_string(),
+            credentials.username.clone(),
+            "--password".to_string(),
+            credentials.password.clone(),
+        ]);
+    }
+
+    args
+}
+
+/// A format a video can be downloaded in, as reported by yt-dlp.
+#[derive(Clone, Debug)]
+pub struct Format {
+    pub id: String,
+    pub ext: String,
+    pub resolution: String,
+    pub vcodec: String,
+    pub acodec: String,
+    pub filesize: Option<u64>,
+}
+
+/// Video metadata reported by yt-dlp.
+#[derive(Clone, Debug)]
+pub struct VideoInfo {
+    pub title: Option<String>,
+    pub uploader: Option<String>,
+    pub webpage_url: Option<String>,
+    pub formats: Vec<Format>,
+}
+
+impl VideoInfo {
+    /// Describes the video in a human-readable way, one detail per line.
+    pub fn describe(&self) -> String {
+        [
+            self.title.clone(),
+            self.uploader.as_ref().map(|u| format!("by {u}")),
+            self.webpage_url.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+}
+
+/// Reads a string field of a yt-dlp JSON object.
+fn json_str(json: &serde_json::Value, key: &str) -> Option<String> {
+    json.get(key)
+        .and_then(serde_json::Value::as_str)
+        .map(std::string::ToString::to_string)
+}
+
+/// Fetches the metadata of a video without downloading it.
+pub async fn get_info(url: &str) -> Option<VideoInfo> {
+    let mut args = vec!["--no-playlist".to_string(), "--dump-json".to_string()];
+    args.extend(common_args(url));
+    args.push(url.to_string());
+
+    let output = Command::new("yt-dlp")
+        .args(&args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+
+    let formats = json
+        .get("formats")
+        .and_then(serde_json::Value::as_array)
+        .map(|formats| {
+            formats
+                .iter()
+                .map(|f| Format {
+                    id: json_str(f, "format_id").unwrap_or_default(),
+                    ext: json_str(f, "ext").unwrap_or_default(),
+                    resolution: json_str(f, "resolution").unwrap_or_default(),
+                    vcodec: json_str(f, "vcodec").unwrap_or_default(),
+                    acodec: json_str(f, "acodec").unwrap_or_default(),
+                    filesize: f
+                        .get("filesize")
+                        .and_then(serde_json::Value::as_u64)
+                        .or_else(|| f.get("filesize_approx").and_then(serde_json::Value::as_u64)),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(VideoInfo {
+        title: json_str(&json, "title"),
+        uploader: json_str(&json, "uploader"),
+        webpage_url: json_str(&json, "webpage_url"),
+        formats,
+    })
+}
+
+/// What yt-dlp expects to download, without downloading anything.
+pub struct Estimate {
+    pub filesize: Option<u64>,
+    pub resolution: Option<String>,
+    pub duration: Option<f64>,
+    pub ext: Option<String>,
+}
+
+/// Asks yt-dlp what it would download (in at most the given resolution).
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub async fn estimate(url: &str, max_height: Option<u32>) -> Option<Estimate> {
+    let mut args = vec![
+        "--no-playlist".to_string(),
+        "--simulate".to_string(),
+        "--print".to_string(),
+        "%(filesize_approx)s\t%(filesize)s\t%(resolution)s\t%(duration)s\t%(ext)s".to_string(),
+    ];
+
+    // same as in `download`, so that the estimate matches what /yeet would download
+    if let Some(height) = max_height {
+        args.extend([
+            "--format".to_string(),
+            format!("bv*[height<={height}]+ba/b[height<={height}]"),
+        ]);
+    }
+
+    args.extend(format_sort_args());
+
+    args.extend(common_args(url));
+    args.push(url.to_string());
+
+    let output = Command::new("yt-dlp")
+        .args(&args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    // unknown fields are printed as "NA"
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields = stdout
+        .lines()
+        .next()?
+        .split('\t')
+        .map(|f| Some(f.trim()).filter(|f| !f.is_empty() && *f != "NA"))
+        .collect::<Vec<_>>();
+
+    let field = |i: usize| fields.get(i).copied().flatten();
+
+    // the approximate size is usually available for merged formats, the exact one otherwise
+    Some(Estimate {
+        filesize: field(0)
+            .and_then(|f| f.parse::<f64>().ok())
+            .or_else(|| field(1).and_then(|f| f.parse::<f64>().ok()))
+            .filter(|&f| f >= 0.0)
+            .map(|f| f as u64),
+        resolution: field(2).map(str::to_string),
+        duration: field(3).and_then(|f| f.parse().ok()),
+        ext: field(4).map(str::to_string),
+    })
+}
+
+/// What yt-dlp reports about a video before downloading it.
+pub struct Preflight {
+    /// The video is being streamed right now.
+    pub is_live: bool,
+    /// The video is a recording of a finished livestream.
+    pub was_live: bool,
+    /// Duration of the video in seconds, if known.
+    pub duration: Option<f64>,
+}
+
+impl Preflight {
+    /// Whether the video is an ongoing livestream (which would never finish downloading).
+    pub const fn is_ongoing(&self) -> bool {
+        self.is_live && !self.was_live
+    }
+}
+
+/// Checks whether a video is a livestream, and how long it is, without downloading it.
+pub async fn preflight(url: &str) -> Option<Preflight> {
+    let mut args = vec![
+        "--no-playlist".to_string(),
+        "--simulate".to_string(),
+        "--print".to_string(),
+        "%(is_live)s %(was_live)s %(duration)s".to_string(),
+    ];
+    args.extend(common_args(url));
+    args.push(url.to_string());
+
+    let output = Command::new("yt-dlp")
+        .args(&args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    // the fields are printed as "True", "False" or "NA" (if unknown)
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut fields = stdout.lines().next()?.split_whitespace();
+
+    Some(Preflight {
+        is_live: fields.next() == Some("True"),
+        was_live: fields.next() == Some("True"),
+        duration: fields.next().and_then(|f| f.parse().ok()),
+    })
+}
+
+/// Reasons why a download can fail.
+#[derive(Debug)]
+pub enum DownloadError {
+    /// The video requires signing in (e.g. because it's age-restricted).
+    AuthRequired,
+    /// A (probably) temporary network error, worth retrying.
+    Transient,
+    /// The site rate-limited us, which is worth retrying (through another proxy, if there's a pool).
+    RateLimited,
+    /// The proxy couldn't be connected to.
+    Proxy,
+    /// The video is private.
+    Private,
+    /// The video has been removed, or never existed.
+    Unavailable,
+    /// The video isn't available in the requested format (e.g. resolution).
+    FormatUnavailable,
+    /// yt-dlp failed for any other reason.
+    Failed,
+}
+
+impl DownloadError {
+    /// Whether the download might succeed if attempted again.
+    pub const fn is_retryable(&self) -> bool {
+        matches!(self, Self::Transient | Self::RateLimited)
+    }
+}
+
+/// A failed download, along with what yt-dlp printed to stderr.
+pub struct DownloadFailure {
+    pub error: DownloadError,
+    pub stderr: String,
+}
+
+/// Parts of yt-dlp error messages which mean that signing in is required.
+const AUTH_ERRORS: [&str; 4] = [
+    "Sign in to confirm",
+    "login required",
+    "Use --cookies",
+    "inappropriate for some users",
+];
+
+/// Parts of yt-dlp error messages which mean that the proxy let us down.
+const PROXY_ERRORS: [&str; 4] = [
+    "Unable to connect to proxy",
+    "ProxyError",
+    "Tunnel connection failed",
+    "SOCKS",
+];
+
+/// Parts of yt-dlp error messages which mean that the video is private.
+const PRIVATE_ERRORS: [&str; 2] = ["Private video", "This video is private"];
+
+/// Parts of yt-dlp error messages which mean that the video is gone.
+const UNAVAILABLE_ERRORS: [&str; 4] = [
+    "Video unavailable",
+    "This video has been removed",
+    "This video is no longer available",
+    "HTTP Error 404",
+];
+
+/// Parts of yt-dlp error messages which mean that the requested format doesn't exist.
+const FORMAT_ERRORS: [&str; 1] = ["Requested format is not available"];
+
+/// Parts of yt-dlp error messages which mean that the site rate-limited us.
+const RATE_LIMIT_ERRORS: [&str; 3] = ["HTTP Error 429", "Too Many Requests", "rate-limit"];
+
+/// Parts of yt-dlp error messages which mean that the network let us down.
+const TRANSIENT_ERRORS: [&str; 7] = [
+    "timed out",
+    "Connection reset",
+    "Connection refused",
+    "Temporary failure in name resolution",
+    "Remote end closed connection",
+    "IncompleteRead",
+    "HTTP Error 5",
+];
+
+/// Extracts the download percentage from a yt-dlp progress line.
+/// Example line: `[download]  42.3% of ~  10.00MiB at  1.00MiB/s ETA 00:05`.
+fn parse_progress(line: &str) -> Option<f64> {
+    line.strip_prefix("[download]")?
+        .split_whitespace()
+        .next()?
+        .strip_suffix('%')?
+        .parse()
+        .ok()
+}
+
+/// Removes everything from a directory, leaving the directory itself in place.
+fn clear_dir(dirname: &str) {
+    let Ok(entries) = std::fs::read_dir(dirname) else {
+        return;
+    };
+
+    for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+        let result = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+
+        if result.is_err() {
+            warn!("failed to remove {}", path.display());
+        }
+    }
+}
+
+/// Downloads a video from an URL (or just its audio, in .mp3 format).
+/// `on_progress` is called with the download percentage every time yt-dlp reports it.
+/// Transient failures are retried up to `DOWNLOAD_RETRIES` times, with exponential backoff.
+pub async fn download(
+    url: &str,
+    dirname: &str,
+    options: DownloadOptions,
+    mut on_progress: impl FnMut(f64),
+) -> Result<(), DownloadFailure> {
+    let retries = *DOWNLOAD_RETRIES
+        .get()
+        .expect("DOWNLOAD_RETRIES is not initialised");
+
+    // direct links to media files don't need yt-dlp, which is left with anything less obvious
+    if let Some(ext) = direct_extension(url, options) {
+        if download_direct(url, dirname, ext, options.fallback, &mut on_progress).await {
+            return Ok(());
+        }
+
+        debug!("failed to download {} directly, using yt-dlp instead", url);
+        clear_dir(dirname);
+    }
+
+    let resume = *RESUME_DOWNLOADS
+        .get()
+        .expect("RESUME_DOWNLOADS is not initialised");
+
+    let mut attempt = 0;
+    let mut resuming = false;
+
+    // every download gets the next proxy of the pool, spreading the downloads across them
+    let pool_size = PROXY_POOL
+        .get()
+        .expect("PROXY_POOL is not initialised")
+        .len();
+    let mut turn = NEXT_PROXY.fetch_add(1, Ordering::Relaxed);
+
+    loop {
+        match download_once(url, dirname, options, resume, turn, &mut on_progress).await {
+            Err(e) if e.error.is_retryable() && attempt < retries => {
+                // rate limits are per IP, so retrying through the next proxy of the pool doesn't need to wait
+                let delay = if matches!(e.error, DownloadError::RateLimited) && pool_size > 1 {
+                    turn += 1;
+                    debug!("rate limited, retrying {} through the next proxy", url);
+                    Duration::ZERO
+                } else {
+                    RETRY_DELAY * 2_u32.pow(attempt)
+                };
+
+                attempt += 1;
+
+                warn!(
+                    "download of {} failed, retrying in {:?} ({}/{})",
+                    url, delay, attempt, retries
+                );
+
+                // the partial download is resumed once, as it might be corrupt if that fails as well;
+                // otherwise it's removed, so that it isn't mistaken for the finished file
+                if resume && !resuming {
+                    debug!("resuming the partial download of {}", url);
+                    resuming = true;
+                } else {
+                    clear_dir(dirname);
+                    resuming = false;
+                }
+
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns the extension of an URL if it's a direct link to a video (or a GIF),
+/// which can be downloaded without yt-dlp. Audio, playlists and sections are left to yt-dlp,
+/// and so are the sites behind a proxy, as it's only set up for yt-dlp.
+fn direct_extension(url: &str, options: DownloadOptions) -> Option<&'static str> {
+    if options.media != Media::Video
+        || options.playlist_end.is_some()
+        || options.section.is_some()
+        || proxy_for(url, 0).is_some()
+    {
+        return None;
+    }
+
+    let url = Url::parse(url).ok()?;
+    let ext = std::path::Path::new(url.path())
+        .extension()?
+        .to_str()?
+        .to_lowercase();
+
+    DIRECT_EXTENSIONS.into_iter().find(|e| *e == ext)
+}
+
+/// Downloads a media file from a direct link, returning whether it has been downloaded.
+/// Responses which don't turn out to be media files are left to yt-dlp.
+/// The download stops once the file exceeds `MAX_FILESIZE`, which is enough for it to be rejected.
+async fn download_direct(
+    url: &str,
+    dirname: &str,
+    ext: &str,
+    fallback: bool,
+    mut on_progress: impl FnMut(f64),
+) -> bool {
+    let Ok(client) = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .build()
+    else {
+        return false;
+    };
+
+    let mut request = client.get(url);
+
+    if let Some(credentials) = credentials_for(url) {
+        request = request.basic_auth(&credentials.username, Some(&credentials.password));
+    }
+
+    let Ok(mut response) = request
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+    else {
+        return false;
+    };
+
+    let is_media = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.starts_with("video/") || x.starts_with("image/gif"));
+
+    if !is_media {
+        return false;
+    }
+
+    // files are rejected once they're over MAX_FILESIZE megabytes (rounded down)
+    let max_filesize = crate::max_filesize(url, fallback);
+    let cap = (max_filesize + 1) * 1000 * 1000;
+
+    let total = response.content_length();
+    let path = format!("{dirname}/{}.{ext}", random_string(10));
+
+    // the file is written asynchronously, so that the other tasks keep running during the transfer
+    let Ok(mut file) = tokio::fs::File::create(&path).await else {
+        return false;
+    };
+
+    let mut written = 0;
+
+    while written < cap {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                if file.write_all(&chunk).await.is_err() {
+                    return false;
+                }
+
+                written += chunk.len() as u64;
+
+                #[allow(clippy::cast_precision_loss)]
+                if let Some(total) = total.filter(|&total| total > 0) {
+                    on_progress((written as f64 / total as f64 * 100.0).min(100.0));
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!("direct download of {} failed: {}", url, e);
+                return false;
+            }
+        }
+    }
+
+    if written >= cap {
+        info!(
+            "direct download of {} exceeds {} MB, stopping it",
+            url, max_filesize
+        );
+    }
+
+    // the writes might still be in flight otherwise
+    file.flush().await.is_ok() && written > 0
+}
+
+#[allow(clippy::too_many_lines)] // sorry
+/// Makes a single attempt at downloading a video, see `download`.
+async fn download_once(
+    url: &str,
+    dirname: &str,
+    options: DownloadOptions,
+    resume: bool,
+    turn: usize,
+    mut on_progress: impl FnMut(f64),
+) -> Result<(), DownloadFailure> {
+    let mut args = vec![
+        "--newline".to_string(), // print progress on separate lines
+        "--progress".to_string(),
+        // the unfinished files are removed before retrying, unless they're resumed
+        if resume {
+            "--continue"
+        } else {
+            "--no-continue"
+        }
+        .to_string(),
+    ];
+
+    args.extend(download_args(url, turn));
+
+    if options.media == Media::Audio {
+        args.extend([
+            "--extract-audio".to_string(),
+            "--audio-format".to_string(),
+            "mp3".to_string(),
+        ]);
+    }
+
+    // subtitles are only useful for videos
+    if let (Some(langs), Media::Video) = (
+        SUBTITLE_LANGS
+            .get()
+            .expect("SUBTITLE_LANGS is not initialised"),
+        options.media,
+    ) {
+        args.extend([
+            "--write-subs".to_string(),
+            "--sub-langs".to_string(),
+            langs.clone(),
+            "--convert-subs".to_string(),
+            "srt".to_string(),
+        ]);
+    }
+
+    // the site's thumbnail looks better than a frame extracted from the video
+    if options.media == Media::Video {
+        args.push("--write-thumbnail".to_string());
+    }
+
+    // prefer the best video within the height limit, falling back to the best combined format
+    if let (Some(height), Media::Video) = (options.max_height, options.media) {
+        args.extend([
+            "--format".to_string(),
+            format!("bv*[height<={height}]+ba/b[height<={height}]"),
+        ]);
+    }
+
+    if options.media == Media::Video {
+        args.extend(format_sort_args());
+    }
+
+    if let Some(section) = options.section {
+        args.extend(["--download-sections".to_string(), section.arg()]);
+    }
+
+    // the timestamps of a section refer to the whole video, so nothing is cut out of it;
+    // otherwise the duration changes, but the bitrate is based on the probed duration anyway
+    if let (Some(categories), None) = (
+        SPONSORBLOCK_REMOVE
+            .get()
+            .expect("SPONSORBLOCK_REMOVE is not initialised"),
+        options.section,
+    ) {
+        args.extend(["--sponsorblock-remove".to_string(), categories.clone()]);
+    }
+
+    let template = OUTPUT_TEMPLATE
+        .get()
+        .expect("OUTPUT_TEMPLATE is not initialised");
+
+    if let Some(n) = options.playlist_end {
+        // prefix the files with their index, so that they can be sorted
+        args.extend([
+            "--yes-playlist".to_string(),
+            "--playlist-end".to_string(),
+            n.to_string(),
+            "--output".to_string(),
+            format!("{dirname}/%(playlist_index)s_{template}"),
+        ]);
+    } else {
+        args.extend([
+            "--no-playlist".to_string(),
+            "--output".to_string(),
+            format!("{dirname}/{template}"),
+        ]);
+    }
+
+    args.push(url.to_string());
+
+    // the process is killed if the download gets cancelled
+    let Ok(mut child) = Command::new("yt-dlp")
+        .args(&args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    else {
+        error!("failed to spawn yt-dlp");
+        return Err(DownloadFailure {
+            error: DownloadError::Failed,
+            stderr: String::new(),
+        });
+    };
+
+    // read the output line by line, reporting the progress
+    let stdout = child.stdout.take();
+    let read_stdout = async {
+        if let Some(stdout) = stdout {
+            let mut lines = BufReader::new(stdout).lines();
+
+            while let Some(Ok(line)) = lines.next().await {
+                if let Some(percentage) = parse_progress(&line) {
+                    on_progress(percentage);
+                } else {
+                    debug!("yt-dlp: {}", line);
+                }
+            }
+        }
+    };
+
+    // errors are collected, so that they can be classified
+    let stderr = child.stderr.take();
+    let read_stderr = async {
+        let mut errors = String::new();
+
+        if let Some(mut stderr) = stderr {
+            stderr.read_to_string(&mut errors).await.ok();
+        }
+
+        errors
+    };
+
+    let ((), errors) = futures_lite::future::zip(read_stdout, read_stderr).await;
+
+    // wait for the command to finish
+    if child.status().await.is_ok_and(|status| status.success()) {
+        return Ok(());
+    }
+
+    debug!("yt-dlp failed: {}", errors);
+
+    // proxy errors are checked first, as they usually mention connection failures too,
+    // and private videos come before the sign-in errors, as yt-dlp suggests using cookies for them
+    let error = if PROXY_ERRORS.iter().any(|e| errors.contains(e)) {
+        DownloadError::Proxy
+    } else if PRIVATE_ERRORS.iter().any(|e| errors.contains(e)) {
+        DownloadError::Private
+    } else if UNAVAILABLE_ERRORS.iter().any(|e| errors.contains(e)) {
+        DownloadError::Unavailable
+    } else if FORMAT_ERRORS
+        .iter()
+        .any(|e| errors.to_lowercase().contains(&e.to_lowercase()))
+    {
+        DownloadError::FormatUnavailable
+    } else if AUTH_ERRORS.iter().any(|e| errors.contains(e)) {
+        DownloadError::AuthRequired
+    } else if RATE_LIMIT_ERRORS.iter().any(|e| errors.contains(e)) {
+        DownloadError::RateLimited
+    } else if TRANSIENT_ERRORS.iter().any(|e| errors.contains(e)) {
+        DownloadError::Transient
+    } else {
+        DownloadError::Failed
+    };
+
+    Err(DownloadFailure {
+        error,
+        stderr: errors,
+    })
+}
+
+/// Returns the size of a file in bytes, or zero if it can't be read.
+pub fn file_size(path: &str) -> u64 {
+    std::fs::metadata(path).map_or(0, |m| m.len())
+}
+
+/// Checks whether a converted file has reached `UPLOAD_LIMIT`, i.e. ffmpeg has stopped
+/// writing it (see the `-fs` option in `convert`), so it's cut short or too large to be uploaded.
+pub fn reaches_upload_limit(path: &str) -> bool {
+    let upload_limit = *UPLOAD_LIMIT.get().expect("UPLOAD_LIMIT is not initialised");
+
+    file_size(path) >= u64::from(upload_limit) * 1000 * 1000
+}
+
+/// Codecs of the videos Telegram can play.
+const PLAYABLE_CODECS: [&str; 2] = ["h264", "hevc"];
+
+/// Checks whether a converted video has a video stream Telegram can play.
+/// ffprobe runs on a blocking thread, like in `probe`.
+pub async fn is_playable(path: &str) -> bool {
+    let path = path.to_string();
+
+    tokio::task::spawn_blocking(move || {
+        ffprobe::ffprobe(path).is_ok_and(|probe| {
+            probe.streams.iter().any(|s| {
+                s.codec_type.as_deref() == Some("video")
+                    && s.codec_name
+                        .as_deref()
+                        .is_some_and(|codec| PLAYABLE_CODECS.contains(&codec))
+            })
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Probe result.
+pub struct Probe {
+    pub duration: u32,
+    pub bitrate: u32,
+    pub width: u32,
+    pub height: u32,
+    pub has_audio: bool,
+    pub audio_codec: Option<String>,
+    /// Bitrate of the audio stream in kbps, or zero if it's unknown.
+    pub audio_bitrate: u32,
+    /// Clockwise rotation (0, 90, 180 or 270 degrees) applied to the video when it's played.
+    pub rotation: u32,
+    pub video_codec: Option<String>,
+    pub pixel_format: Option<String>,
+}
+
+/// Implements a `Default` trait for `Probe`.
+impl Default for Probe {
+    fn default() -> Self {
+        Self {
+            duration: 0,
+            bitrate: 0,
+            width: 0,
+            height: 0,
+            has_audio: true, // unknown files shouldn't be mistaken for animations
+            audio_codec: None,
+            audio_bitrate: 0,
+            rotation: 0,
+            video_codec: None,
+            pixel_format: None,
+        }
+    }
+}
+
+/// Probes a media file for its duration, width, height and whether it has any audio.
+/// Files without a video stream (e.g. audio files) report zero width and height.
+/// The width and height are those of the video as it's displayed, i.e. after its rotation.
+/// ffprobe runs on a blocking thread, so that it doesn't hold up the other tasks; the files
+/// should be probed once, with the `Probe` passed on to whatever else needs it.
+pub async fn probe(path: &str) -> Option<Probe> {
+    let path = path.to_string();
+
+    tokio::task::spawn_blocking(move || probe_file(&path))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Probes a media file, blocking until ffprobe finishes (see `probe`).
+fn probe_file(path: &str) -> Option<Probe> {
+    let probe = ffprobe::ffprobe(path).ok()?;
+    let streams = probe.streams;
+
+    let video_stream = streams
+        .iter()
+        .find(|&s| s.codec_type == Some("video".to_string()));
+
+    let audio_stream = streams
+        .iter()
+        .find(|&s| s.codec_type == Some("audio".to_string()));
+
+    // bail if there's nothing to play
+    if video_stream.is_none() && audio_stream.is_none() {
+        return None;
+    }
+
+    let duration = probe
+        .format
+        .try_get_duration()
+        .and_then(std::result::Result::ok)
+        .map_or(0, |d| u32::try_from(d.as_secs()).unwrap_or(0));
+
+    // images are reported as a video stream, but there's nothing to play either
+    if is_still_image(
+        video_stream.and_then(|s| s.codec_name.as_deref()),
+        audio_stream.is_some(),
+        duration,
+    ) {
+        return None;
+    }
+
+    let width = video_stream.and_then(|s| s.width).unwrap_or(0);
+    let height = video_stream.and_then(|s| s.height).unwrap_or(0);
+
+    // phones record portrait videos as landscape ones, flagged to be rotated when played
+    let rotation = if video_stream.is_some() {
+        rotation(path)
+    } else {
+        0
+    };
+
+    let (width, height) = displayed_dimensions(width, height, rotation);
+
+    let bitrate = u32::try_from(
+        probe
+            .format
+            .bit_rate
+            .unwrap_or_else(|| "0".to_string())
+            .parse()
+            .unwrap_or(0),
+    )
+    .unwrap_or(0)
+    .div(1000);
+
+    Some(Probe {
+        duration,
+        bitrate,
+        width: u32::try_from(width).unwrap_or(0),
+        height: u32::try_from(height).unwrap_or(0),
+        has_audio: audio_stream.is_some(),
+        audio_codec: audio_stream.and_then(|s| s.codec_name.clone()),
+        audio_bitrate: audio_stream
+            .and_then(|s| s.bit_rate.as_deref())
+            .and_then(|b| b.parse::<u32>().ok())
+            .map_or(0, |b| b / 1000),
+        rotation,
+        video_codec: video_stream.and_then(|s| s.codec_name.clone()),
+        pixel_format: video_stream.and_then(|s| s.pix_fmt.clone()),
+    })
+}
+
+/// Checks whether a file is a single image, i.e. its only stream is an image without a duration
+/// (ffprobe reads JPEG, PNG and WebP files as videos made of a single frame).
+fn is_still_image(video_codec: Option<&str>, has_audio: bool, duration: u32) -> bool {
+    !has_audio
+        && duration == 0
+        && video_codec.is_some_and(|codec| STILL_IMAGE_CODECS.contains(&codec))
+}
+
+/// Reads the rotation of the video stream of a file, in degrees clockwise.
+/// It's stored either as a display matrix (counterclockwise) or, in older files, as a tag.
+fn rotation(path: &str) -> u32 {
+    let output = std::process::Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream_side_data=rotation:stream_tags=rotate",
+            "-of",
+            "json",
+            path,
+        ])
+        .output();
+
+    output
+        .ok()
+        .and_then(|output| serde_json::from_slice::<serde_json::Value>(&output.stdout).ok())
+        .map_or(0, |json| parse_rotation(&json))
+}
+
+/// Reads the rotation (in degrees clockwise) from the JSON output of ffprobe, see `rotation`.
+fn parse_rotation(json: &serde_json::Value) -> u32 {
+    let stream = &json["streams"][0];
+
+    let degrees = stream["side_data_list"]
+        .as_array()
+        .and_then(|list| list.iter().find_map(|data| data["rotation"].as_i64()))
+        .map(|rotation| -rotation)
+        .or_else(|| stream["tags"]["rotate"].as_str()?.parse().ok())
+        .unwrap_or(0);
+
+    // only quarter turns are supported by players, so round to the nearest one
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let rotation = ((degrees.rem_euclid(360) + 45) / 90 % 4 * 90) as u32;
+
+    rotation
+}
+
+/// Returns the dimensions of a video as it's displayed, i.e. swapped if it's turned sideways.
+const fn displayed_dimensions<T>(width: T, height: T, rotation: u32) -> (T, T) {
+    if rotation.is_multiple_of(180) {
+        (width, height)
+    } else {
+        (height, width)
+    }
+}
+
+/// Audio codecs which can be copied into an .mp4 file Telegram plays.
+const COPYABLE_AUDIO_CODECS: [&str; 1] = ["aac"];
+
+/// Checks whether the audio of a video is copied as it is, instead of being re-encoded.
+/// That's only the case if `COPY_AUDIO` is set and the audio can be played by Telegram.
+pub fn copies_audio(metadata: &Probe) -> bool {
+    *COPY_AUDIO.get().expect("COPY_AUDIO is not initialised")
+        && metadata
+            .audio_codec
+            .as_deref()
+            .is_some_and(|codec| COPYABLE_AUDIO_CODECS.contains(&codec))
+}
+
+/// Checks whether a video can be sent to Telegram as it is, only remuxed into an .mp4 file:
+/// H.264 in yuv420p with even dimensions (and no rotation, which the filters would otherwise apply),
+/// with AAC audio (if any), no taller than `MAX_OUTPUT_HEIGHT`, and small enough to be uploaded
+/// (`size` is the size of the file in bytes).
+fn is_compatible(metadata: &Probe, size: u64) -> bool {
+    let upload_limit = *UPLOAD_LIMIT.get().expect("UPLOAD_LIMIT is not initialised");
+
+    metadata.video_codec.as_deref() == Some("h264")
+        && metadata.pixel_format.as_deref() == Some("yuv420p")
+        && metadata.width.is_multiple_of(2)
+        && metadata.height.is_multiple_of(2)
+        && metadata.rotation == 0
+        && max_output_height().is_none_or(|height| metadata.height <= height)
+        && (!metadata.has_audio
+            || metadata
+                .audio_codec
+                .as_deref()
+                .is_some_and(|codec| COPYABLE_AUDIO_CODECS.contains(&codec)))
+        && size < u64::from(upload_limit) * 1000 * 1000
+}
+
+fn max_output_height() -> Option<u32> {
+    *MAX_OUTPUT_HEIGHT
+        .get()
+        .expect("MAX_OUTPUT_HEIGHT is not initialised")
+}
+
+/// Returns the filters applied to the re-encoded videos: videos taller than `MAX_OUTPUT_HEIGHT`
+/// are scaled down (keeping their aspect ratio, and never scaled up), then cropped to even dimensions.
+fn video_filter() -> String {
+    let crop = "crop=trunc(iw/2)*2:trunc(ih/2)*2";
+
+    max_output_height().map_or_else(
+        || crop.to_string(),
+        |height| format!("scale=-2:min(ih\\,{height}),{crop}"),
+    )
+}
+
+/// Returns the bitrate (in kbps) the audio of a converted video is going to take up.
+pub fn audio_allowance(metadata: &Probe) -> u32 {
+    let audio_bitrate = *AUDIO_BITRATE
+        .get()
+        .expect("AUDIO_BITRATE is not initialised");
+
+    if !metadata.has_audio {
+        0
+    } else if copies_audio(metadata) && metadata.audio_bitrate > 0 {
+        metadata.audio_bitrate
+    } else {
+        audio_bitrate
+    }
+}
+
+/// Returns the ffmpeg arguments selecting the encoders for a video (probed as `metadata`), as configured.
+fn encoder_args(metadata: &Probe) -> Vec<String> {
+    let mut args = vec![
+        "-c:v".to_string(), // video codec
+        VIDEO_CODEC
+            .get()
+            .expect("VIDEO_CODEC is not initialised")
+            .clone(),
+    ];
+
+    // the audio is kept at its source quality, if possible
+    if copies_audio(metadata) {
+        args.extend(["-c:a".to_string(), "copy".to_string()]);
+    } else {
+        args.extend([
+            "-b:a".to_string(), // audio bitrate
+            format!(
+                "{}k",
+                AUDIO_BITRATE
+                    .get()
+                    .expect("AUDIO_BITRATE is not initialised")
+            ),
+        ]);
+    }
+
+    if let Some(preset) = PRESET.get().expect("PRESET is not initialised") {
+        args.extend(["-preset".to_string(), preset.clone()]);
+    }
+
+    args
+}
+
+/// Version of a binary, like 2024.04.09 (yt-dlp) or 6.1.1 (ffmpeg).
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub Vec<u32>);
+
+impl FromStr for Version {
+    type Err = std::num::ParseIntError;
+
+    /// Parses the leading numeric part of a version, ignoring suffixes like "-static".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // some builds prefix the version with an "n", like n6.1
+        let version = s.trim().trim_start_matches('n');
+        let end = version
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(version.len());
+
+        version[..end]
+            .trim_end_matches('.')
+            .split('.')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts = self.0.iter().map(u32::to_string).collect::<Vec<_>>();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+/// Returns the raw version string of yt-dlp or ffmpeg, as reported by the binary.
+pub fn version_string(binary: &str) -> Option<String> {
+    let arg = if binary == "yt-dlp" {
+        "--version"
+    } else {
+        "-version"
+    };
+
+    let output = std::process::Command::new(binary).arg(arg).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let line = stdout.lines().next()?.trim();
+
+    // ffmpeg prints `ffmpeg version 6.1.1 Copyright (c) ...`, yt-dlp just prints the version
+    line.strip_prefix(&format!("{binary} version "))
+        .map_or(line, |rest| {
+            rest.split_whitespace().next().unwrap_or_default()
+        })
+        .to_string()
+        .into()
+}
+
+/// Resource usage of the bot, as reported by `/sysinfo`.
+pub struct SystemInfo {
+    /// Resident memory of the process, in bytes.
+    pub memory: Option<u64>,
+    /// CPU usage of the process, in percent of a single core.
+    pub cpu: Option<f32>,
+    /// Free space on the disk holding the work directory, in bytes.
+    pub free_disk: Option<u64>,
+    pub ytdlp_version: Option<String>,
+    pub ffmpeg_version: Option<String>,
+}
+
+/// Measures the resource usage of the bot.
+/// The CPU usage is measured over a short interval, so this takes a moment.
+pub async fn system_info() -> SystemInfo {
+    let pid = sysinfo::get_current_pid().ok();
+    let mut system = System::new();
+
+    let refresh = |system: &mut System| {
+        if let Some(pid) = pid {
+            system.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+        }
+    };
+
+    // the CPU usage is computed from the difference between two refreshes
+    refresh(&mut system);
+    tokio::time::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
+    refresh(&mut system);
+
+    let process = pid.and_then(|pid| system.process(pid));
+
+    // the work directory lives on the disk with the longest matching mount point
+    let work_dir = WORK_DIR
+        .get()
+        .expect("WORK_DIR is not initialised")
+        .clone()
+        .unwrap_or_else(std::env::temp_dir);
+    let work_dir = work_dir.canonicalize().unwrap_or(work_dir);
+
+    let free_disk = Disks::new_with_refreshed_list()
+        .iter()
+        .filter(|disk| work_dir.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(Disk::available_space);
+
+    SystemInfo {
+        memory: process.map(Process::memory),
+        cpu: process.map(Process::cpu_usage),
+        free_disk,
+        ytdlp_version: version_string("yt-dlp"),
+        ffmpeg_version: version_string("ffmpeg"),
+    }
+}
+
+/// Updates yt-dlp to the latest release, returning its output if the update failed.
+pub async fn update_ytdlp() -> Result<(), String> {
+    let output = Command::new("yt-dlp")
+        .arg("-U")
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    // the reason is printed last, e.g. when yt-dlp was installed with a package manager
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    Err(stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .unwrap_or("unknown error")
+        .to_string())
+}
+
+/// Checks whether ffmpeg supports an encoder.
+pub fn has_encoder(encoder: &str) -> bool {
+    std::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-encoders"])
+        .output()
+        .is_ok_and(|output| {
+            // every line looks like ` V....D libx264              libx264 H.264 / AVC ...`
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.split_whitespace().nth(1) == Some(encoder))
+        })
+}
+
+/// Converts a video to .mp4, embedding the subtitle files as soft subtitle tracks.
+/// Videos which Telegram can play as they are keep their streams (see `is_compatible`).
+pub async fn convert(
+    input: &str,
+    output: &str,
+    metadata: &Probe,
+    bitrate: Option<u32>,
+    subtitles: &[String],
+) -> bool {
+    let mut args = vec![
+        "-y".to_string(), // overwrite output files if they already exist
+        "-i".to_string(), // input file
+        input.to_string(),
+    ];
+
+    // every subtitle file is a separate input, mapped to its own track
+    if !subtitles.is_empty() {
+        for subtitle in subtitles {
+            args.extend(["-i".to_string(), subtitle.clone()]);
+        }
+
+        args.extend([
+            "-map".to_string(),
+            "0:v".to_string(),
+            "-map".to_string(),
+            "0:a?".to_string(),
+        ]);
+
+        for i in 1..=subtitles.len() {
+            args.extend(["-map".to_string(), format!("{i}:0")]);
+        }
+
+        args.extend(["-c:s".to_string(), "mov_text".to_string()]);
+    }
+
+    let upload_limit = *UPLOAD_LIMIT.get().expect("UPLOAD_LIMIT is not initialised");
+
+    // videos Telegram can already play are only remuxed, which is much faster than re-encoding them
+    // (unless their bitrate has to be reduced)
+    let remux = bitrate.is_none() && is_compatible(metadata, file_size(input));
+
+    if remux {
+        debug!("{} is compatible with Telegram, remuxing it", input);
+
+        args.extend(
+            ["-c:v", "copy", "-c:a", "copy", "-movflags", "+faststart"]
+                .into_iter()
+                .map(std::string::ToString::to_string),
+        );
+    } else {
+        args.extend(encoder_args(metadata));
+
+        // compose the rest of the ffmpeg command arguments
+        // (faststart and even dimensions are needed for the video to play in Telegram)
+        // rotated videos are turned upright before the filters run and lose their rotation flag,
+        // so the output is displayed the same way as the input (see `Probe::rotation`)
+        args.extend([
+            "-movflags".to_string(), // faststart
+            "+faststart".to_string(),
+            "-pix_fmt".to_string(), // pixel format
+            "yuv420p".to_string(),
+            "-vf".to_string(), // making sure the video dimensions are even
+            video_filter(),
+        ]);
+    }
+
+    // max filesize
+    args.extend(["-fs".to_string(), format!("{upload_limit}M")]);
+
+    // add bitrate if specified
+    if let Some(bitrate) = bitrate {
+        args.push("-b:v".to_string()); // video bitrate
+        args.push(format!("{bitrate}k"));
+    }
+
+    args.push(output.to_string());
+
+    // create a new ffmpeg command
+    let mut command = Command::new("ffmpeg");
+
+    // run the command and wait for it to finish
+    command
+        .args(&args)
+        .kill_on_drop(true)
+        .status()
+        .await
+        .is_ok_and(|status| status.success())
+}
+
+/// Re-encodes a video and splits it into parts of roughly `segment_duration` seconds.
+/// Keyframes are forced at the split points, so that every part plays on its own.
+/// Returns the paths of the parts, in order (or nothing, if ffmpeg failed).
+pub async fn split(
+    input: &str,
+    metadata: &Probe,
+    dirname: &str,
+    segment_duration: u32,
+) -> Vec<String> {
+    let mut args = vec![
+        "-i".to_string(), // input file
+        input.to_string(),
+        "-map".to_string(), // keep the video and the audio (if there is any)
+        "0:v".to_string(),
+        "-map".to_string(),
+        "0:a?".to_string(),
+    ];
+
+    args.extend(encoder_args(metadata));
+
+    args.extend([
+        "-pix_fmt".to_string(), // pixel format
+        "yuv420p".to_string(),
+        "-vf".to_string(), // making sure the video dimensions are even
+        video_filter(),
+        "-force_key_frames".to_string(), // a keyframe at the start of every part
+        format!("expr:gte(t,n_forced*{segment_duration})"),
+        "-f".to_string(), // split the output
+        "segment".to_string(),
+        "-segment_time".to_string(),
+        segment_duration.to_string(),
+        "-reset_timestamps".to_string(),
+        "1".to_string(),
+        "-segment_format_options".to_string(), // faststart
+        "movflags=+faststart".to_string(),
+        format!("{dirname}/part_%03d.mp4"),
+    ]);
+
+    let success = Command::new("ffmpeg")
+        .args(&args)
+        .kill_on_drop(true)
+        .status()
+        .await
+        .is_ok_and(|status| status.success());
+
+    if !success {
+        return Vec::new();
+    }
+
+    let mut parts = std::fs::read_dir(dirname)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    parts.sort();
+    parts
+}
+
+/// Returns the thumbnail yt-dlp has written alongside a downloaded video, if there's one.
+pub fn find_thumbnail(video_path: &str) -> Option<String> {
+    // thumbnails are named after the video, e.g. id.mp4 -> id.webp
+    let path = std::path::Path::new(video_path);
+
+    THUMBNAIL_EXTENSIONS
+        .iter()
+        .map(|ext| path.with_extension(ext))
+        .find(|thumbnail| thumbnail.exists())
+        .map(|thumbnail| thumbnail.to_string_lossy().to_string())
+}
+
+/// Downloads the thumbnail of a video to a directory, without downloading the video itself.
+/// Returns the path of the thumbnail converted to a .jpg file, or `None` if the site doesn't provide one.
+pub async fn download_cover(url: &str, dir_path: &str) -> Option<String> {
+    let mut args = [
+        "--no-playlist",
+        "--skip-download",
+        "--write-thumbnail",
+        "--output",
+        &format!("{dir_path}/cover.%(ext)s"),
+    ]
+    .map(str::to_string)
+    .to_vec();
+
+    args.extend(common_args(url));
+    args.push(url.to_string());
+
+    let output = Command::new("yt-dlp")
+        .args(&args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    // the thumbnail may be a .webp or .png file, and Telegram only accepts the common formats as photos
+    let thumbnail = find_thumbnail(&format!("{dir_path}/cover.mp4"))?;
+    let cover_path = format!("{dir_path}/{}.jpg", random_string(10));
+
+    let converted = Command::new("ffmpeg")
+        .args([
+            "-y", // overwrite output files if they already exist
+            "-i", // input file
+            &thumbnail,
+            "-vframes", // number of frames to output
+            "1",
+            "-q:v", // quality of the image (1-31)
+            "2",
+            &cover_path,
+        ])
+        .kill_on_drop(true)
+        .status()
+        .await
+        .is_ok_and(|s| s.success());
+
+    (converted && file_size(&cover_path) > 0).then_some(cover_path)
+}
+
+/// Prepares a thumbnail for a video, saving it as a .jpg file and returning its path.
+/// The site's thumbnail is used if there's one, otherwise a frame is extracted from the video
+/// (`duration` is the duration of the video in seconds, or zero if it's unknown).
+/// Returns `None` if `GENERATE_THUMBNAILS` is disabled, so that Telegram makes one itself.
+pub async fn get_thumbnail(
+    video_path: &str,
+    site_thumbnail: Option<&str>,
+    duration: u32,
+) -> Option<InputFile> {
+    // at most MAX_CONCURRENT_THUMBNAILS are made at a time, e.g. for the items of a playlist
+    let _thumbnail = THUMBNAILS
+        .get()
+        .expect("THUMBNAILS is not initialised")
+        .as_ref()?
+        .acquire()
+        .await;
+
+    // get the parent folder of the video and construct the thumbnail path
+    let parent_folder = std::path::Path::new(video_path).parent();
+    let thumbnail_path = parent_folder
+        .map(|p| p.join("thumbnail.jpg"))
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap();
+
+    // site thumbnails are often .webp or .png files, which Telegram doesn't accept
+    if let Some(site_thumbnail) = site_thumbnail {
+        if write_thumbnail(site_thumbnail, &thumbnail_path, 0).await {
+            return Some(InputFile::file(thumbnail_path));
+        }
+
+        warn!("failed to convert the thumbnail, extracting a frame instead");
+    }
+
+    // the first frame is often black, so a frame from a bit later on is used (unless the video is short);
+    // if the duration is off and the seek goes past the end, no frame is written, so the first one is used
+    let seek = if duration >= MIN_THUMBNAIL_SEEK_DURATION {
+        duration / 10
+    } else {
+        0
+    };
+
+    if write_thumbnail(video_path, &thumbnail_path, seek).await
+        || (seek > 0 && write_thumbnail(video_path, &thumbnail_path, 0).await)
+    {
+        Some(InputFile::file(thumbnail_path))
+    } else {
+        None
+    }
+}
+
+/// Writes a frame of a video (or an image) to a .jpg file, scaled down to fit Telegram's limits.
+/// The frame is taken `seek` seconds into the video. Returns whether the file has been written.
+async fn write_thumbnail(input: &str, output: &str, seek: u32) -> bool {
+    // an existing file would be mistaken for the new one, if ffmpeg doesn't write any frame
+    tokio::fs::remove_file(output).await.ok();
+
+    let written = Command::new("ffmpeg")
+        .args([
+            "-y",  // overwrite output files if they already exist
+            "-ss", // seek before opening the input, which is fast
+            &seek.to_string(),
+            "-i", // input file
+            input,
+            "-vframes", // number of frames to output
+            "1",
+            "-vf", // thumbnails may be at most 320 pixels wide and tall
+            "scale=min(320\\,iw):min(320\\,ih):force_original_aspect_ratio=decrease",
+            "-q:v", // quality of the thumbnail (1-31)
+            "3",
+            output,
+        ])
+        .kill_on_drop(true)
+        .status()
+        .await
+        .is_ok_and(|s| s.success());
+
+    written && file_size(output) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_upload_limit() {
+        crate::harness::init();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_string_lossy().to_string();
+
+        // UPLOAD_LIMIT is 50 MB (the file is sparse, so nothing is actually written)
+        file.as_file().set_len(49_999_999).unwrap();
+        assert!(!reaches_upload_limit(&path));
+
+        file.as_file().set_len(50_000_000).unwrap();
+        assert!(reaches_upload_limit(&path));
+
+        assert!(!reaches_upload_limit("missing.mp4"));
+    }
+
+    /// Returns the probe of a video Telegram can play as it is.
+    fn compatible_probe() -> Probe {
+        Probe {
+            duration: 10,
+            bitrate: 1000,
+            width: 1280,
+            height: 720,
+            has_audio: true,
+            audio_codec: Some("aac".to_string()),
+            audio_bitrate: 128,
+            rotation: 0,
+            video_codec: Some("h264".to_string()),
+            pixel_format: Some("yuv420p".to_string()),
+        }
+    }
+
+    #[test]
+    fn accepts_compatible_videos() {
+        crate::harness::init();
+
+        assert!(is_compatible(&compatible_probe(), 10_000_000));
+
+        let silent = Probe {
+            has_audio: false,
+            audio_codec: None,
+            ..compatible_probe()
+        };
+
+        assert!(is_compatible(&silent, 10_000_000));
+    }
+
+    #[test]
+    fn rejects_incompatible_videos() {
+        crate::harness::init();
+
+        let incompatible = [
+            Probe {
+                video_codec: Some("hevc".to_string()),
+                ..compatible_probe()
+            },
+            Probe {
+                video_codec: None,
+                ..compatible_probe()
+            },
+            Probe {
+                pixel_format: Some("yuv444p".to_string()),
+                ..compatible_probe()
+            },
+            Probe {
+                width: 1281,
+                ..compatible_probe()
+            },
+            Probe {
+                height: 721,
+                ..compatible_probe()
+            },
+            Probe {
+                rotation: 90,
+                ..compatible_probe()
+            },
+            Probe {
+                audio_codec: Some("opus".to_string()),
+                ..compatible_probe()
+            },
+            Probe {
+                audio_codec: None,
+                ..compatible_probe()
+            },
+        ];
+
+        for metadata in &incompatible {
+            assert!(!is_compatible(metadata, 10_000_000));
+        }
+
+        // UPLOAD_LIMIT is 50 MB
+        assert!(!is_compatible(&compatible_probe(), 50_000_000));
+        assert!(is_compatible(&compatible_probe(), 49_999_999));
+    }
+
+    fn whitelist(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|e| (*e).to_string()).collect()
+    }
+
+    #[test]
+    fn finds_a_whitelisted_url() {
+        crate::harness::init();
+
+        let info = get_url_info(
+            "look at https://www.youtube.com/watch?v=abc",
+            &whitelist(&["youtube.com"]),
+        );
+
+        assert_eq!(
+            info.maybe_url.as_deref(),
+            Some("https://www.youtube.com/watch?v=abc")
+        );
+        assert_eq!(info.total_urls, 1);
+        assert_eq!(info.whitelisted_urls, 1);
+        assert_eq!(info.denied_urls, 0);
+    }
+
+    #[test]
+    fn counts_the_urls_of_a_message() {
+        crate::harness::init();
+
+        let info = get_url_info(
+            "https://youtube.com/a https://vimeo.com/b https://example.org/c",
+            &whitelist(&["youtube.com", "vimeo.com"]),
+        );
+
+        assert_eq!(info.maybe_url, None);
+        assert_eq!(info.urls, ["https://youtube.com/a", "https://vimeo.com/b"]);
+        assert_eq!(info.total_urls, 3);
+        assert_eq!(info.whitelisted_urls, 2);
+
+        let info = get_url_info("no links here", &whitelist(&["youtube.com"]));
+
+        assert_eq!(info.maybe_url, None);
+        assert_eq!(info.total_urls, 0);
+    }
+
+    #[test]
+    fn denies_urls_regardless_of_the_whitelist() {
+        crate::harness::init();
+
+        let info = get_url_info(
+            "https://denied.example.com/video",
+            &whitelist(&["example.com"]),
+        );
+
+        assert_eq!(info.maybe_url, None);
+        assert_eq!(info.whitelisted_urls, 0);
+        assert_eq!(info.denied_urls, 1);
+    }
+
+    #[test]
+    fn matches_hosts() {
+        assert!(host_matches("youtube.com", "youtube.com"));
+        assert!(host_matches("WWW.YouTube.com", "youtube.com"));
+        assert!(!host_matches("notyoutube.com", "youtube.com"));
+        assert!(!host_matches("youtube.com.evil.org", "youtube.com"));
+    }
+
+    #[test]
+    fn matches_subdomains_of_bare_entries() {
+        assert!(host_matches("www.youtube.com", "youtube.com"));
+        assert!(host_matches("m.youtube.com", "youtube.com"));
+        assert!(host_matches("music.youtube.com", "youtube.com"));
+    }
+
+    #[test]
+    fn matches_wildcard_entries() {
+        assert!(host_matches("cdn.example.org", "*.example.org"));
+        assert!(host_matches("a.b.example.org", "*.example.org"));
+        assert!(!host_matches("example.org", "*.example.org"));
+        assert!(!host_matches("badexample.org", "*.example.org"));
+    }
+
+    #[test]
+    fn matches_explicit_subdomains() {
+        assert!(host_matches("music.youtube.com", "music.youtube.com"));
+        assert!(host_matches("www.music.youtube.com", "music.youtube.com"));
+        assert!(!host_matches("www.youtube.com", "music.youtube.com"));
+        assert!(!host_matches("youtube.com", "music.youtube.com"));
+    }
+
+    #[test]
+    fn matches_entries_under_multi_part_suffixes() {
+        assert!(host_matches("bbc.co.uk", "bbc.co.uk"));
+        assert!(host_matches("www.bbc.co.uk", "bbc.co.uk"));
+        assert!(!host_matches("itv.co.uk", "bbc.co.uk"));
+        assert!(!host_matches("bbc.co.uk.evil.org", "bbc.co.uk"));
+    }
+
+    #[test]
+    fn normalizes_urls() {
+        assert_eq!(
+            normalize_url("https://www.youtube.com/watch?v=abc&si=xyz&utm_source=x#t=1"),
+            "https://youtube.com/watch?v=abc"
+        );
+        assert_eq!(
+            normalize_url("https://vimeo.com/123?feature=share"),
+            "https://vimeo.com/123"
+        );
+        assert_eq!(normalize_url("not a url"), "not a url");
+    }
+
+    #[test]
+    fn validates_output_templates() {
+        assert!(is_valid_template("%(id)s.%(ext)s"));
+        assert!(is_valid_template("%(title).50s [%(id)s].%(ext)s"));
+        assert!(!is_valid_template(".%(ext)s"));
+        assert!(!is_valid_template("%(id)s.mp4"));
+        assert!(!is_valid_template("../%(id)s.%(ext)s"));
+        assert!(!is_valid_template("videos/%(id)s.%(ext)s"));
+        assert!(!is_valid_template("videos\\%(id)s.%(ext)s"));
+    }
+
+    #[test]
+    fn extracts_registrable_domains() {
+        assert_eq!(registrable_domain("www.youtube.com"), "youtube.com");
+        assert_eq!(registrable_domain("WWW.BBC.CO.UK"), "bbc.co.uk");
+        assert_eq!(registrable_domain("127.0.0.1"), "127.0.0.1");
+        assert_eq!(registrable_domain("[::1]"), "::1");
+    }
+
+    #[test]
+    fn extracts_registrable_domains_under_multi_part_suffixes() {
+        assert_eq!(registrable_domain("news.bbc.co.uk"), "bbc.co.uk");
+        assert_eq!(registrable_domain("www.abc.net.au"), "abc.net.au");
+        assert_eq!(registrable_domain("www.smh.com.au"), "smh.com.au");
+        assert_eq!(registrable_domain("user.github.io"), "user.github.io");
+        assert_eq!(registrable_domain("m.example.com"), "example.com");
+    }
+
+    #[test]
+    fn matches_hosts_under_multi_part_suffixes() {
+        crate::harness::init();
+
+        let info = get_url_info(
+            "https://www.bbc.co.uk/news/av/123",
+            &whitelist(&["bbc.co.uk"]),
+        );
+        assert_eq!(info.whitelisted_urls, 1);
+
+        let info = get_url_info(
+            "https://www.itv.co.uk/watch/123",
+            &whitelist(&["bbc.co.uk"]),
+        );
+        assert_eq!(info.whitelisted_urls, 0);
+
+        let info = get_url_info("https://www.smh.com.au/v/1", &whitelist(&["smh.com.au"]));
+        assert_eq!(info.whitelisted_urls, 1);
+
+        let info = get_url_info(
+            "https://other.github.io/a.mp4",
+            &whitelist(&["user.github.io"]),
+        );
+        assert_eq!(info.whitelisted_urls, 0);
+    }
+
+    #[test]
+    fn finds_urls_with_ip_address_hosts() {
+        crate::harness::init();
+
+        let info = get_url_info(
+            "http://192.168.1.10:8080/video.mp4",
+            &whitelist(&["192.168.1.10"]),
+        );
+        assert_eq!(
+            info.maybe_url.as_deref(),
+            Some("http://192.168.1.10:8080/video.mp4")
+        );
+
+        let info = get_url_info("http://10.0.0.1/video.mp4", &whitelist(&["192.168.1.10"]));
+        assert_eq!(info.total_urls, 1);
+        assert_eq!(info.whitelisted_urls, 0);
+    }
+
+    #[test]
+    fn detects_public_suffixes() {
+        assert!(is_public_suffix("co.uk"));
+        assert!(is_public_suffix("com.au"));
+        assert!(is_public_suffix("github.io"));
+        assert!(is_public_suffix("*.github.io"));
+        assert!(is_public_suffix("CO.UK"));
+        assert!(!is_public_suffix("bbc.co.uk"));
+        assert!(!is_public_suffix("YouTube.com"));
+        assert!(is_public_suffix("com"));
+        assert!(!is_public_suffix("localhost"));
+        assert!(!is_public_suffix("intranet"));
+        assert!(!is_public_suffix("192.168.1.10"));
+        assert!(!is_public_suffix("[::1]"));
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("90"), Some(90));
+        assert_eq!(parse_timestamp("1:30"), Some(90));
+        assert_eq!(parse_timestamp("x"), None);
+    }
+
+    #[test]
+    fn parses_minutes_and_seconds() {
+        assert_eq!(parse_timestamp("00:30"), Some(30));
+        assert_eq!(parse_timestamp("01:15"), Some(75));
+        assert_eq!(parse_timestamp("90:00"), Some(5400));
+        assert_eq!(parse_timestamp("1:"), None);
+        assert_eq!(parse_timestamp(":30"), None);
+        assert_eq!(parse_timestamp("1:-30"), None);
+    }
+
+    #[test]
+    fn parses_hours_minutes_and_seconds() {
+        assert_eq!(parse_timestamp("0:01:30"), Some(90));
+        assert_eq!(parse_timestamp("01:02:03"), Some(3723));
+        assert_eq!(parse_timestamp("1:2:3:4"), None);
+        assert_eq!(parse_timestamp("1::3"), None);
+    }
+
+    #[test]
+    fn checks_that_sections_fit() {
+        let section = |start, end| Section { start, end };
+
+        // the duration isn't always known beforehand
+        assert!(section(30, Some(75)).fits(None));
+        assert!(section(30, None).fits(None));
+        assert!(section(30, Some(75)).fits(Some(120.0)));
+        assert!(section(30, Some(121)).fits(Some(120.4)));
+
+        // invalid ranges are ignored, and the whole video is downloaded
+        assert!(!section(75, Some(30)).fits(None));
+        assert!(!section(30, Some(30)).fits(Some(120.0)));
+
+        // so are ranges beyond the end of the video
+        assert!(!section(130, None).fits(Some(120.0)));
+        assert!(!section(30, Some(150)).fits(Some(120.0)));
+    }
+
+    #[test]
+    fn measures_sections() {
+        let section = |start, end| Section { start, end };
+
+        assert!((section(30, Some(75)).length(120.0) - 45.0).abs() < f64::EPSILON);
+        assert!((section(30, None).length(120.0) - 90.0).abs() < f64::EPSILON);
+        assert!((section(30, Some(150)).length(120.0) - 90.0).abs() < f64::EPSILON);
+        assert!(section(130, None).length(120.0).abs() < f64::EPSILON);
+        assert_eq!(section(30, Some(75)).arg(), "*30-75");
+        assert_eq!(section(30, None).arg(), "*30-inf");
+    }
+
+    #[test]
+    fn parses_start_times() {
+        assert_eq!(
+            start_time("https://youtu.be/abc?t=1m30s"),
+            Some(Section {
+                start: 90,
+                end: None
+            })
+        );
+        assert_eq!(
+            start_time("https://youtube.com/watch?v=abc&start=15"),
+            Some(Section {
+                start: 15,
+                end: None
+            })
+        );
+        assert_eq!(start_time("https://youtu.be/abc?t=0"), None);
+        assert_eq!(start_time("https://youtu.be/abc"), None);
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!("2024.04.09".parse(), Ok(Version(vec![2024, 4, 9])));
+        assert_eq!("6.1.1-static".parse(), Ok(Version(vec![6, 1, 1])));
+        assert_eq!("n6.1".parse(), Ok(Version(vec![6, 1])));
+        assert!("git-2024".parse::<Version>().is_err());
+        assert!(Version(vec![6, 1]) < Version(vec![6, 1, 1]));
+        assert_eq!(Version(vec![2024, 4, 9]).to_string(), "2024.4.9");
+    }
+
+    #[test]
+    fn reads_rotations_from_display_matrices() {
+        // display matrices are counterclockwise, e.g. portrait phone videos are -90 degrees
+        let side_data = |rotation: i64| {
+            serde_json::json!({
+                "streams": [{ "side_data_list": [{ "side_data_type": "Display Matrix", "rotation": rotation }] }]
+            })
+        };
+
+        assert_eq!(parse_rotation(&side_data(-90)), 90);
+        assert_eq!(parse_rotation(&side_data(180)), 180);
+        assert_eq!(parse_rotation(&side_data(-180)), 180);
+        assert_eq!(parse_rotation(&side_data(90)), 270);
+        assert_eq!(parse_rotation(&side_data(0)), 0);
+
+        // only quarter turns are supported
+        assert_eq!(parse_rotation(&side_data(-89)), 90);
+        assert_eq!(parse_rotation(&side_data(10)), 0);
+    }
+
+    #[test]
+    fn reads_rotations_from_tags() {
+        let tags =
+            |rotate: &str| serde_json::json!({ "streams": [{ "tags": { "rotate": rotate } }] });
+
+        assert_eq!(parse_rotation(&tags("90")), 90);
+        assert_eq!(parse_rotation(&tags("180")), 180);
+        assert_eq!(parse_rotation(&tags("270")), 270);
+        assert_eq!(parse_rotation(&tags("invalid")), 0);
+
+        // the display matrix takes precedence over the tag
+        let both = serde_json::json!({
+            "streams": [{ "side_data_list": [{ "rotation": -90 }], "tags": { "rotate": "180" } }]
+        });
+
+        assert_eq!(parse_rotation(&both), 90);
+        assert_eq!(parse_rotation(&serde_json::json!({ "streams": [{}] })), 0);
+        assert_eq!(parse_rotation(&serde_json::json!({})), 0);
+    }
+
+    #[test]
+    fn swaps_the_dimensions_of_sideways_videos() {
+        assert_eq!(displayed_dimensions(1920, 1080, 0), (1920, 1080));
+        assert_eq!(displayed_dimensions(1920, 1080, 90), (1080, 1920));
+        assert_eq!(displayed_dimensions(1920, 1080, 180), (1920, 1080));
+        assert_eq!(displayed_dimensions(1920, 1080, 270), (1080, 1920));
+    }
+
+    #[test]
+    fn tells_images_from_videos() {
+        assert!(is_still_image(Some("mjpeg"), false, 0));
+        assert!(is_still_image(Some("png"), false, 0));
+        assert!(is_still_image(Some("webp"), false, 0));
+        assert!(!is_still_image(Some("mjpeg"), true, 0));
+        assert!(!is_still_image(Some("mjpeg"), false, 60));
+        assert!(!is_still_image(Some("h264"), false, 0));
+        assert!(!is_still_image(Some("gif"), false, 0));
+        assert!(!is_still_image(None, true, 0));
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn rejects_files_without_media() {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+        assert!(probe_file(&format!("{fixtures}/not_media.txt")).is_none());
+        assert!(probe_file(&format!("{fixtures}/manifest.m3u8")).is_none());
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn rejects_images() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for ext in ["jpg", "png", "webp"] {
+            let path = dir.path().join(format!("image.{ext}"));
+            let path = path.to_string_lossy();
+
+            let status = std::process::Command::new("ffmpeg")
+                .args([
+                    "-y",
+                    "-f",
+                    "lavfi",
+                    "-i",
+                    "testsrc=size=320x240",
+                    "-vframes",
+                    "1",
+                ])
+                .arg(path.as_ref())
+                .output()
+                .expect("failed to run ffmpeg")
+                .status;
+
+            assert!(status.success());
+            assert!(probe_file(&path).is_none(), "{ext} files are images");
+        }
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn probes_a_sample_video() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = crate::harness::sample_video(dir.path());
+        let metadata = probe_file(&path).unwrap();
+
+        assert_eq!((metadata.width, metadata.height), (320, 240));
+        assert_eq!(metadata.duration, 2);
+        assert_eq!(metadata.rotation, 0);
+        assert!(metadata.has_audio);
+        assert_eq!(metadata.video_codec.as_deref(), Some("mpeg4"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ffmpeg"]
+    async fn converts_a_sample_video() {
+        crate::harness::init();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = crate::harness::sample_video(dir.path());
+        let output = dir.path().join("output.mp4").to_string_lossy().to_string();
+
+        let metadata = probe(&path).await.unwrap();
+
+        assert!(convert(&path, &output, &metadata, None, &[]).await);
+
+        let metadata = probe(&output).await.unwrap();
+
+        assert_eq!((metadata.width, metadata.height), (320, 240));
+        assert_eq!(metadata.video_codec.as_deref(), Some("h264"));
+        assert_eq!(metadata.pixel_format.as_deref(), Some("yuv420p"));
+        assert_eq!(metadata.audio_codec.as_deref(), Some("aac"));
+    }
+
+    /// Checks whether a process is still around (a zombie counts, as it hasn't been reaped yet).
+    fn is_alive(pid: u32) -> bool {
+        std::path::Path::new(&format!("/proc/{pid}")).exists()
+    }
+
+    /// Waits for up to 5 seconds for a process to be killed and reaped.
+    async fn wait_for_exit(pid: u32) -> bool {
+        for _ in 0..50 {
+            if !is_alive(pid) {
+                return true;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        false
+    }
+
+    #[tokio::test]
+    async fn kills_children_when_dropped() {
+        let mut child = Command::new("sleep")
+            .arg("30")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+
+        // a cancelled download drops the future waiting for the child
+        let status =
+            tokio::time::timeout(
+                Duration::from_millis(200),
+                async move { child.status().await },
+            )
+            .await;
+
+        assert!(status.is_err(), "sleep exited early");
+        assert!(wait_for_exit(pid).await, "sleep is still running");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ffmpeg"]
+    async fn kills_ffmpeg_when_the_conversion_is_dropped() {
+        crate::harness::init();
+
+        // ffmpeg blocks on reading the FIFO, as nothing is ever written to it
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.mkv").to_string_lossy().to_string();
+        let output = dir.path().join("output.mp4").to_string_lossy().to_string();
+
+        let status = std::process::Command::new("mkfifo")
+            .arg(&input)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let metadata = Probe::default();
+        let mut conversion = Box::pin(convert(&input, &output, &metadata, None, &[]));
+
+        // find ffmpeg by its input, while the conversion is running
+        let find_ffmpeg = || {
+            std::fs::read_dir("/proc")
+                .ok()?
+                .flatten()
+                .find_map(|entry| {
+                    let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+                    let cmdline = std::fs::read(entry.path().join("cmdline")).ok()?;
+                    let cmdline = String::from_utf8_lossy(&cmdline);
+
+                    (cmdline.starts_with("ffmpeg") && cmdline.contains(&input)).then_some(pid)
+                })
+        };
+
+        let mut pid = None;
+
+        for _ in 0..50 {
+            tokio::select! {
+                _ = &mut conversion => panic!("ffmpeg exited early"),
+                () = tokio::time::sleep(Duration::from_millis(100)) => {}
+            }
+
+            pid = find_ffmpeg();
+
+            if pid.is_some() {
+                break;
+            }
+        }
+
+        let pid = pid.expect("ffmpeg wasn't started");
+
+        // like a cancelled download
+        drop(conversion);
+
+        assert!(wait_for_exit(pid).await, "ffmpeg is still running");
     }
 }
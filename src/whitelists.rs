@@ -0,0 +1,229 @@
+//! Per-chat whitelists, which narrow the global whitelist down in specific chats,
+//! and the global whitelist itself, which the maintainer can change at runtime.
+//! Optionally, they are saved to files, so that they survive restarts.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Mutex, OnceLock, RwLock},
+};
+
+use teloxide::types::ChatId;
+
+use crate::utils;
+
+static PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+static WHITELISTS: OnceLock<Mutex<HashMap<i64, Vec<String>>>> = OnceLock::new();
+static GLOBAL_PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+static GLOBAL: OnceLock<RwLock<Vec<String>>> = OnceLock::new();
+
+/// Initialises the per-chat whitelists, loading them from `CHAT_WHITELISTS_PATH` if it's set.
+/// The global whitelist is loaded from `WHITELIST_PATH`, falling back to `WHITELIST`.
+pub fn init_statics() {
+    PATH.set(utils::optional_var("CHAT_WHITELISTS_PATH").map(PathBuf::from))
+        .expect("PATH was already initialised");
+
+    // the file doesn't exist on the first run
+    let whitelists = PATH
+        .get()
+        .expect("PATH is not initialised")
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+        .map_or_else(HashMap::new, |json| {
+            serde_json::from_slice(&json).unwrap_or_else(|e| {
+                error!("failed to parse the chat whitelists: {}", e);
+                HashMap::new()
+            })
+        });
+
+    WHITELISTS
+        .set(Mutex::new(whitelists))
+        .expect("WHITELISTS was already initialised");
+
+    GLOBAL_PATH
+        .set(utils::optional_var("WHITELIST_PATH").map(PathBuf::from))
+        .expect("GLOBAL_PATH was already initialised");
+
+    // the file only exists once the maintainer has changed the whitelist
+    let global = GLOBAL_PATH
+        .get()
+        .expect("GLOBAL_PATH is not initialised")
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|json| {
+            serde_json::from_slice(&json)
+                .map_err(|e| error!("failed to parse the global whitelist: {}", e))
+                .ok()
+        })
+        .unwrap_or_else(|| {
+            utils::WHITELIST
+                .get()
+                .expect("WHITELIST is not initialised")
+                .clone()
+        });
+
+    GLOBAL
+        .set(RwLock::new(global))
+        .expect("GLOBAL was already initialised");
+}
+
+/// Writes the per-chat whitelists to `CHAT_WHITELISTS_PATH`.
+fn save(whitelists: &HashMap<i64, Vec<String>>) {
+    let Some(path) = PATH.get().expect("PATH is not initialised") else {
+        return;
+    };
+
+    match serde_json::to_vec(whitelists) {
+        Ok(json) => {
+            if let Err(e) = std::fs::write(path, json) {
+                error!("failed to save the chat whitelists: {}", e);
+            }
+        }
+        Err(e) => error!("failed to serialise the chat whitelists: {}", e),
+    }
+}
+
+/// Writes the global whitelist to `WHITELIST_PATH`.
+fn save_global(whitelist: &[String]) {
+    let Some(path) = GLOBAL_PATH.get().expect("GLOBAL_PATH is not initialised") else {
+        return;
+    };
+
+    match serde_json::to_vec(whitelist) {
+        Ok(json) => {
+            if let Err(e) = std::fs::write(path, json) {
+                error!("failed to save the global whitelist: {}", e);
+            }
+        }
+        Err(e) => error!("failed to serialise the global whitelist: {}", e),
+    }
+}
+
+/// Returns the global whitelist.
+pub fn global() -> Vec<String> {
+    GLOBAL
+        .get()
+        .expect("GLOBAL is not initialised")
+        .read()
+        .unwrap()
+        .clone()
+}
+
+/// Adds entries to the global whitelist, or removes them from it.
+/// Returns the updated whitelist.
+pub fn change_global(add: &[String], remove: &[String]) -> Vec<String> {
+    let mut global = GLOBAL
+        .get()
+        .expect("GLOBAL is not initialised")
+        .write()
+        .unwrap();
+
+    global.retain(|w| !remove.contains(w));
+
+    for entry in add {
+        if !global.contains(entry) {
+            global.push(entry.clone());
+        }
+    }
+
+    save_global(&global);
+    let whitelist = global.clone();
+    drop(global);
+
+    whitelist
+}
+
+/// Returns the whitelist of a chat, if it has one of its own.
+pub fn get(chat_id: ChatId) -> Option<Vec<String>> {
+    WHITELISTS
+        .get()
+        .expect("WHITELISTS is not initialised")
+        .lock()
+        .unwrap()
+        .get(&chat_id.0)
+        .cloned()
+}
+
+/// Returns the whitelist in effect in a chat, falling back to the global one.
+/// Entries which the global whitelist no longer allows are left out.
+pub fn effective(chat_id: ChatId) -> Vec<String> {
+    get(chat_id).map_or_else(global, |whitelist| {
+        whitelist.into_iter().filter(|e| is_allowed(e)).collect()
+    })
+}
+
+/// Checks whether an entry is covered by the global whitelist,
+/// as chats can't allow anything the maintainer doesn't.
+pub fn is_allowed(entry: &str) -> bool {
+    global()
+        .iter()
+        .any(|w| utils::host_matches(entry.trim_start_matches("*."), w))
+}
+
+/// Checks whether an entry looks like a valid netloc (optionally prefixed with `*.`).
+/// Public suffixes, such as `co.uk`, are rejected, as they would allow every site under them.
+pub fn is_valid(entry: &str) -> bool {
+    let host = entry.strip_prefix("*.").unwrap_or(entry);
+
+    let labels_valid = host.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    });
+
+    host.len() <= 253 && labels_valid && psl::domain_str(host).is_some()
+}
+
+/// Sets the whitelist of a chat, or removes it if `whitelist` is `None`.
+pub fn set(chat_id: ChatId, whitelist: Option<Vec<String>>) {
+    let mut whitelists = WHITELISTS
+        .get()
+        .expect("WHITELISTS is not initialised")
+        .lock()
+        .unwrap();
+
+    if let Some(whitelist) = whitelist {
+        whitelists.insert(chat_id.0, whitelist);
+    } else {
+        whitelists.remove(&chat_id.0);
+    }
+
+    save(&whitelists);
+    drop(whitelists);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_netlocs() {
+        assert!(is_valid("youtube.com"));
+        assert!(is_valid("music.youtube.com"));
+        assert!(is_valid("*.example.org"));
+        assert!(is_valid("bbc.co.uk"));
+        assert!(is_valid("my-site.net"));
+    }
+
+    #[test]
+    fn rejects_invalid_netlocs() {
+        assert!(!is_valid(""));
+        assert!(!is_valid("YouTube.com"));
+        assert!(!is_valid("youtube..com"));
+        assert!(!is_valid("-youtube.com"));
+        assert!(!is_valid("youtube.com/watch"));
+        assert!(!is_valid("https://youtube.com"));
+        assert!(!is_valid(&format!("{}.com", "a".repeat(64))));
+    }
+
+    #[test]
+    fn rejects_public_suffixes() {
+        assert!(!is_valid("com"));
+        assert!(!is_valid("co.uk"));
+        assert!(!is_valid("*.github.io"));
+    }
+}